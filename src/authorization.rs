@@ -0,0 +1,498 @@
+use serde::Deserialize;
+use serde_json::{Map, Value};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::error_page::ErrorCode;
+use crate::messages::DownStreamResponse;
+use crate::session::AuthorizationTokens;
+use crate::util;
+
+const DEFAULT_GROUPS_CLAIM: &str = "groups";
+const WWW_AUTHENTICATE: &str = "WWW-Authenticate";
+
+// How requests matching a path rule are authenticated
+#[derive(Deserialize, Clone, Debug, Default, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum AuthenticationMode {
+    // Browser flow with a session cookie, unauthenticated requests are redirected to the authorization server
+    #[default]
+    Session,
+    // JWT access token in the Authorization header, unauthenticated requests are rejected
+    Bearer,
+}
+
+// Configuration for requests matching a path prefix, the first matching rule is used
+#[derive(Deserialize, Clone, Debug)]
+pub struct PathRule {
+    path_prefix: String,
+    #[serde(default)]
+    mode: AuthenticationMode,
+    // Accepted audiences of bearer tokens, defaults to the client id
+    #[serde(default)]
+    audiences: Vec<String>,
+    #[serde(default)]
+    authorization: Option<AuthorizationRule>,
+    // Authentication context the session must have, requested from the authorization server on step-up
+    #[serde(default)]
+    acr_values: Vec<String>,
+    // Maximum seconds since the end-user authenticated, requested from the authorization server on step-up
+    #[serde(default)]
+    max_age: Option<u64>,
+    // Extra query parameters added to authorization redirects started from this path
+    #[serde(default)]
+    extra_params: Vec<(String, String)>,
+}
+
+impl PathRule {
+    pub fn new(path_prefix: &str, authorization: Option<AuthorizationRule>) -> PathRule {
+        PathRule {
+            path_prefix: path_prefix.to_string(),
+            mode: AuthenticationMode::Session,
+            audiences: vec![],
+            authorization,
+            acr_values: vec![],
+            max_age: None,
+            extra_params: vec![],
+        }
+    }
+
+    pub fn with_bearer(mut self, audiences: Vec<String>) -> PathRule {
+        self.mode = AuthenticationMode::Bearer;
+        self.audiences = audiences;
+        self
+    }
+
+    pub fn is_bearer(&self) -> bool {
+        self.mode == AuthenticationMode::Bearer
+    }
+
+    pub fn audiences(&self) -> &Vec<String> {
+        &self.audiences
+    }
+
+    pub fn with_step_up(mut self, acr_values: Vec<String>, max_age: Option<u64>) -> PathRule {
+        self.acr_values = acr_values;
+        self.max_age = max_age;
+        self
+    }
+
+    pub fn extra_params(&self) -> &Vec<(String, String)> {
+        &self.extra_params
+    }
+
+    // Returns true if the session authentication is weaker or older than required by the rule
+    pub fn requires_step_up(&self, tokens: &AuthorizationTokens) -> bool {
+        let acr_satisfied = self.acr_values.is_empty()
+            || tokens
+                .acr()
+                .is_some_and(|acr| self.acr_values.iter().any(|value| value == acr));
+
+        let age_satisfied = match (self.max_age, tokens.auth_time()) {
+            (None, _) => true,
+            (Some(_), None) => false,
+            (Some(max_age), Some(auth_time)) => {
+                let now = SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .map(|now| now.as_secs())
+                    .unwrap_or(0);
+                now.saturating_sub(auth_time) <= max_age
+            }
+        };
+        !(acr_satisfied && age_satisfied)
+    }
+
+    // Authorization request parameters for a step-up, forcing the end-user to authenticate again
+    pub fn step_up_params(&self) -> Vec<(String, String)> {
+        let mut params = self.extra_params.clone();
+        if !self.acr_values.is_empty() {
+            params.push(("acr_values".to_string(), self.acr_values.join(" ")));
+        }
+        if let Some(max_age) = self.max_age {
+            params.push(("max_age".to_string(), max_age.to_string()));
+        }
+        params.push(("prompt".to_string(), "login".to_string()));
+        params
+    }
+
+    pub fn matches(&self, path: &str) -> bool {
+        path.starts_with(&self.path_prefix)
+    }
+
+    pub fn authorization(&self) -> Option<&AuthorizationRule> {
+        self.authorization.as_ref()
+    }
+}
+
+// Declarative requirements on the claims of the session tokens, all requirements must be met
+#[derive(Deserialize, Clone, Debug, Default)]
+pub struct AuthorizationRule {
+    // Every scope must be granted to the access token
+    #[serde(default)]
+    scopes: Vec<String>,
+    // At least one of the groups must be present in the groups claim
+    #[serde(default)]
+    groups: Vec<String>,
+    #[serde(default = "default_groups_claim")]
+    groups_claim: String,
+    // The acr claim must be one of the values
+    #[serde(default)]
+    acr_values: Vec<String>,
+    // The email claim must belong to one of the domains
+    #[serde(default)]
+    email_domains: Vec<String>,
+    #[serde(default)]
+    claims: Vec<ClaimRequirement>,
+}
+
+#[derive(Deserialize, Clone, Debug)]
+pub struct ClaimRequirement {
+    claim: String,
+    #[serde(flatten)]
+    condition: ClaimCondition,
+}
+
+#[derive(Deserialize, Clone, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum ClaimCondition {
+    // Claim value is equal to the value
+    Equals(Value),
+    // Claim is a list containing the value, or a string containing the value as a substring
+    Contains(Value),
+}
+
+impl ClaimRequirement {
+    pub fn new(claim: &str, condition: ClaimCondition) -> ClaimRequirement {
+        ClaimRequirement {
+            claim: claim.to_string(),
+            condition,
+        }
+    }
+
+    fn is_met(&self, claims: &TokenClaims) -> bool {
+        match (claims.get(&self.claim), &self.condition) {
+            (None, _) => false,
+            (Some(value), ClaimCondition::Equals(expected)) => value == expected,
+            (Some(Value::Array(values)), ClaimCondition::Contains(expected)) => {
+                values.contains(expected)
+            }
+            (Some(Value::String(value)), ClaimCondition::Contains(Value::String(expected))) => {
+                value.contains(expected.as_str())
+            }
+            _ => false,
+        }
+    }
+}
+
+impl AuthorizationRule {
+    pub fn new(
+        scopes: Vec<String>,
+        groups: Vec<String>,
+        acr_values: Vec<String>,
+        email_domains: Vec<String>,
+        claims: Vec<ClaimRequirement>,
+    ) -> AuthorizationRule {
+        AuthorizationRule {
+            scopes,
+            groups,
+            groups_claim: default_groups_claim(),
+            acr_values,
+            email_domains,
+            claims,
+        }
+    }
+
+    pub fn scopes(&self) -> &Vec<String> {
+        &self.scopes
+    }
+
+    // Evaluates the rule against the token claims, returns the reason when access is denied
+    pub fn evaluate(&self, claims: &TokenClaims) -> Result<(), String> {
+        let granted = claims.scopes();
+        for scope in &self.scopes {
+            if !granted.contains(scope) {
+                return Err(format!("Missing required scope={}", scope));
+            }
+        }
+
+        if !self.groups.is_empty() {
+            let member_of = claims.strings(&self.groups_claim);
+            if !self.groups.iter().any(|group| member_of.contains(group)) {
+                return Err(format!("Not member of any of the groups={:?}", self.groups));
+            }
+        }
+
+        if !self.acr_values.is_empty() {
+            match claims.get("acr").and_then(|acr| acr.as_str()) {
+                Some(acr) if self.acr_values.iter().any(|value| value == acr) => {}
+                acr => {
+                    return Err(format!(
+                        "Authentication context acr={:?} is not one of {:?}",
+                        acr, self.acr_values
+                    ))
+                }
+            }
+        }
+
+        if !self.email_domains.is_empty() {
+            // Unverified addresses could be set to any domain by the end-user
+            let domain = claims
+                .get("email")
+                .filter(|_| claims.get("email_verified") == Some(&Value::Bool(true)))
+                .and_then(|email| email.as_str())
+                .and_then(|email| email.rsplit_once('@'))
+                .map(|(_, domain)| domain.to_lowercase());
+            match domain {
+                Some(domain)
+                    if self
+                        .email_domains
+                        .iter()
+                        .any(|allowed| allowed.to_lowercase() == domain) => {}
+                _ => {
+                    return Err(format!(
+                        "Email is not in one of the domains={:?}",
+                        self.email_domains
+                    ))
+                }
+            }
+        }
+
+        for requirement in &self.claims {
+            if !requirement.is_met(claims) {
+                return Err(format!("Claim requirement not met for claim={}", requirement.claim));
+            }
+        }
+        Ok(())
+    }
+}
+
+// Claims of the ID token, the userinfo endpoint and the access token (if it is a JWT), in order of precedence
+pub struct TokenClaims {
+    id_token: Map<String, Value>,
+    userinfo: Map<String, Value>,
+    access_token: Map<String, Value>,
+}
+
+impl TokenClaims {
+    pub fn new(id_token: Map<String, Value>, access_token: Map<String, Value>) -> TokenClaims {
+        TokenClaims {
+            id_token,
+            userinfo: Map::new(),
+            access_token,
+        }
+    }
+
+    pub fn from_tokens(tokens: &AuthorizationTokens) -> TokenClaims {
+        TokenClaims {
+            id_token: tokens
+                .id_token()
+                .and_then(util::jwt_claims)
+                .unwrap_or_default(),
+            userinfo: tokens.userinfo().clone(),
+            access_token: util::jwt_claims(tokens.access_token()).unwrap_or_default(),
+        }
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Value> {
+        self.id_token
+            .get(name)
+            .or_else(|| self.userinfo.get(name))
+            .or_else(|| self.access_token.get(name))
+    }
+
+    // Scopes granted to the access token, from the space separated scope claim or the scp list
+    pub fn scopes(&self) -> Vec<String> {
+        match self.access_token.get("scope") {
+            Some(Value::String(scopes)) => scopes.split(' ').map(|s| s.to_string()).collect(),
+            _ => strings(self.access_token.get("scp")),
+        }
+    }
+
+    // Claim value as a list of strings, single string claims are treated as a list of one
+    pub fn strings(&self, name: &str) -> Vec<String> {
+        strings(self.get(name))
+    }
+}
+
+fn strings(value: Option<&Value>) -> Vec<String> {
+    match value {
+        Some(Value::String(value)) => vec![value.clone()],
+        Some(Value::Array(values)) => values
+            .iter()
+            .filter_map(|value| value.as_str().map(|s| s.to_string()))
+            .collect(),
+        _ => vec![],
+    }
+}
+
+// Response sent to the end-user when an authorization rule denies the request
+pub fn access_denied(reason: String) -> DownStreamResponse {
+    DownStreamResponse::error(vec![], ErrorCode::AccessDenied, format!("Access denied, {}", reason))
+}
+
+// Response for rejected bearer token requests with a WWW-Authenticate challenge, RFC 6750 section 3
+pub fn bearer_challenge(
+    status: u64,
+    error: Option<&str>,
+    description: &str,
+    scopes: &[String],
+) -> DownStreamResponse {
+    let mut params = vec![];
+    if let Some(error) = error {
+        params.push(format!("error=\"{}\"", error));
+        params.push(format!(
+            "error_description=\"{}\"",
+            description.replace(['"', '\\'], "'")
+        ));
+    }
+    if !scopes.is_empty() {
+        params.push(format!("scope=\"{}\"", scopes.join(" ")));
+    }
+    let challenge = if params.is_empty() {
+        "Bearer".to_string()
+    } else {
+        format!("Bearer {}", params.join(", "))
+    };
+    DownStreamResponse::new(
+        vec![(WWW_AUTHENTICATE.to_string(), challenge)],
+        status,
+        description.to_string(),
+    )
+}
+
+fn default_groups_claim() -> String {
+    DEFAULT_GROUPS_CLAIM.to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::authorization::{AuthorizationRule, ClaimCondition, ClaimRequirement, PathRule, TokenClaims};
+    use crate::session::AuthorizationTokens;
+    use serde_json::json;
+    use std::time::SystemTime;
+
+    fn unsigned_token(claims: serde_json::Value) -> String {
+        let encode = |value: &serde_json::Value| {
+            base64::encode_config(value.to_string(), base64::URL_SAFE_NO_PAD)
+        };
+        format!("{}.{}.signature", encode(&json!({"alg": "RS256"})), encode(&claims))
+    }
+
+    fn test_tokens() -> AuthorizationTokens {
+        AuthorizationTokens::new(
+            SystemTime::now(),
+            unsigned_token(json!({"sub": "tester", "scope": "openid admin"})),
+            None,
+            Some(unsigned_token(json!({
+                "sub": "tester",
+                "acr": "Level4",
+                "email": "tester@Example.com",
+                "email_verified": true,
+                "groups": ["developers", "admins"],
+                "department": "platform"
+            }))),
+            None,
+        )
+    }
+
+    #[test]
+    fn path_rule() {
+        let text = "{
+            \"path_prefix\": \"/admin\",
+            \"authorization\": {
+                \"groups\": [\"admins\"],
+                \"claims\": [ { \"claim\": \"department\", \"equals\": \"platform\" } ]
+            }
+        }";
+        let rule: PathRule = serde_json::from_str(text).unwrap();
+        assert!(rule.matches("/admin/users"));
+        assert!(!rule.matches("/public"));
+        let claims = TokenClaims::from_tokens(&test_tokens());
+        assert!(rule.authorization().unwrap().evaluate(&claims).is_ok());
+    }
+
+    #[test]
+    fn bearer_challenge() {
+        let response = super::bearer_challenge(401, None, "Bearer token required", &[]);
+        assert_eq!(response.code(), 401);
+        assert_eq!(response.headers(), vec![("WWW-Authenticate", "Bearer")]);
+
+        let response = super::bearer_challenge(
+            403,
+            Some("insufficient_scope"),
+            "Missing \"admin\"",
+            &["admin".to_string()],
+        );
+        assert_eq!(
+            response.headers(),
+            vec![(
+                "WWW-Authenticate",
+                "Bearer error=\"insufficient_scope\", error_description=\"Missing 'admin'\", scope=\"admin\""
+            )]
+        );
+    }
+
+    #[test]
+    fn requires_step_up() {
+        let tokens = test_tokens();
+        assert_eq!(tokens.acr(), Some("Level4"));
+
+        let rule = PathRule::new("/", None);
+        assert!(!rule.requires_step_up(&tokens));
+
+        let rule = PathRule::new("/", None).with_step_up(vec!["Level4".to_string()], None);
+        assert!(!rule.requires_step_up(&tokens));
+
+        // Stronger authentication context than the session has
+        let rule = PathRule::new("/", None).with_step_up(vec!["Level5".to_string()], None);
+        assert!(rule.requires_step_up(&tokens));
+        assert!(rule.step_up_params().contains(&("acr_values".to_string(), "Level5".to_string())));
+        assert!(rule.step_up_params().contains(&("prompt".to_string(), "login".to_string())));
+
+        // Session without auth_time cannot satisfy max_age
+        let rule = PathRule::new("/", None).with_step_up(vec![], Some(300));
+        assert!(rule.requires_step_up(&tokens));
+    }
+
+    #[test]
+    fn evaluate() {
+        let claims = TokenClaims::from_tokens(&test_tokens());
+
+        let rule = AuthorizationRule::new(
+            vec!["admin".to_string()],
+            vec!["admins".to_string(), "owners".to_string()],
+            vec!["Level4".to_string()],
+            vec!["example.com".to_string()],
+            vec![ClaimRequirement::new("groups", ClaimCondition::Contains(json!("developers")))],
+        );
+        assert!(rule.evaluate(&claims).is_ok());
+
+        let missing_scope = AuthorizationRule::new(vec!["write".to_string()], vec![], vec![], vec![], vec![]);
+        assert!(missing_scope.evaluate(&claims).is_err());
+
+        let wrong_group = AuthorizationRule::new(vec![], vec!["owners".to_string()], vec![], vec![], vec![]);
+        assert!(wrong_group.evaluate(&claims).is_err());
+
+        let low_acr = AuthorizationRule::new(vec![], vec![], vec!["Level3".to_string()], vec![], vec![]);
+        assert!(low_acr.evaluate(&claims).is_err());
+
+        let wrong_domain = AuthorizationRule::new(vec![], vec![], vec![], vec!["other.com".to_string()], vec![]);
+        assert!(wrong_domain.evaluate(&claims).is_err());
+
+        // Only verified email addresses with a domain part are accepted
+        let domain = AuthorizationRule::new(vec![], vec![], vec![], vec!["example.com".to_string()], vec![]);
+        let email_claims = |claims: serde_json::Value| {
+            TokenClaims::new(claims.as_object().unwrap().clone(), serde_json::Map::new())
+        };
+        assert!(domain.evaluate(&email_claims(json!({"email": "tester@example.com", "email_verified": true}))).is_ok());
+        assert!(domain.evaluate(&email_claims(json!({"email": "tester@example.com"}))).is_err());
+        assert!(domain.evaluate(&email_claims(json!({"email": "tester@example.com", "email_verified": false}))).is_err());
+        assert!(domain.evaluate(&email_claims(json!({"email": "tester@example.com", "email_verified": "true"}))).is_err());
+        assert!(domain.evaluate(&email_claims(json!({"email": "example.com", "email_verified": true}))).is_err());
+
+        let wrong_claim = AuthorizationRule::new(
+            vec![], vec![], vec![], vec![],
+            vec![ClaimRequirement::new("department", ClaimCondition::Equals(json!("sales")))],
+        );
+        assert!(wrong_claim.evaluate(&claims).is_err());
+    }
+}
@@ -0,0 +1,82 @@
+use serde::Deserialize;
+use std::time::Duration;
+
+use crate::oauth_client_types::Request;
+
+// Token endpoint for same-origin front-ends (backend-for-frontend), the refresh token never leaves the proxy
+#[derive(Deserialize, Clone, Debug)]
+pub struct BffConfig {
+    // Origins of the front-ends allowed to get the access token
+    allowed_origins: Vec<String>,
+    // Custom header requests must carry, cross-origin requests can't set it without a CORS preflight
+    #[serde(default = "default_csrf_header")]
+    csrf_header: String,
+    // Access tokens expiring within this many seconds are refreshed first
+    #[serde(default = "default_refresh_before")]
+    refresh_before: u64,
+}
+
+impl BffConfig {
+    pub fn new(allowed_origins: Vec<String>, csrf_header: &str, refresh_before: u64) -> BffConfig {
+        BffConfig {
+            allowed_origins,
+            csrf_header: csrf_header.to_string(),
+            refresh_before,
+        }
+    }
+
+    pub fn refresh_before(&self) -> Duration {
+        Duration::from_secs(self.refresh_before)
+    }
+
+    // Rejects requests without the custom header, or with an Origin header that is not allowed. Browsers omit
+    // the Origin header on same-origin GET requests, which are accepted unless fetch metadata says otherwise.
+    pub fn validate_request(&self, request: &Request) -> Result<(), String> {
+        if request.header(&self.csrf_header).is_none_or(|value| value.is_empty()) {
+            return Err(format!("missing {} header", self.csrf_header));
+        }
+        match (request.header("origin"), request.header("sec-fetch-site")) {
+            (Some(origin), _) if self.allowed_origins.iter().any(|allowed| allowed.eq_ignore_ascii_case(origin)) => Ok(()),
+            (Some(origin), _) => Err(format!("origin {} is not allowed", origin)),
+            (None, Some(site)) if !site.eq_ignore_ascii_case("same-origin") => Err(format!("{} request without Origin header", site)),
+            (None, _) => Ok(()),
+        }
+    }
+}
+
+fn default_csrf_header() -> String {
+    "X-CSRF".to_string()
+}
+
+fn default_refresh_before() -> u64 {
+    60
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::bff::BffConfig;
+    use crate::oauth_client_types::Request;
+
+    fn test_request(headers: Vec<(&str, &str)>) -> Request {
+        let mut headers: Vec<(String, String)> = headers.iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect();
+        headers.push(("x-forwarded-proto".to_string(), "https".to_string()));
+        headers.push((":authority".to_string(), "app.example.com".to_string()));
+        headers.push((":path".to_string(), "/oauth2/token".to_string()));
+        Request::new(headers).unwrap()
+    }
+
+    #[test]
+    fn validate_request() {
+        let config = BffConfig::new(vec!["https://app.example.com".to_string()], "X-CSRF", 60);
+        assert!(config.validate_request(&test_request(vec![("origin", "https://app.example.com"), ("x-csrf", "1")])).is_ok());
+        assert!(config.validate_request(&test_request(vec![("origin", "https://app.example.com")])).is_err());
+        assert!(config.validate_request(&test_request(vec![("origin", "https://evil.example.com"), ("x-csrf", "1")])).is_err());
+        // Same-origin requests without an Origin header
+        assert!(config.validate_request(&test_request(vec![("x-csrf", "1")])).is_ok());
+        assert!(config.validate_request(&test_request(vec![("x-csrf", "1"), ("sec-fetch-site", "same-origin")])).is_ok());
+        assert!(config.validate_request(&test_request(vec![("x-csrf", "1"), ("sec-fetch-site", "cross-site")])).is_err());
+        assert!(config.validate_request(&test_request(vec![("sec-fetch-site", "same-origin")])).is_err());
+    }
+}
@@ -2,11 +2,24 @@ use std::collections::HashMap;
 use proxy_wasm::traits::Context;
 use serde::{Serialize, Deserialize};
 use crate::session::{SessionCache, SessionUpdate, Session};
+use crate::introspection::{IntrospectionCache, CachedIntrospection};
+use crate::token_exchange::{self, TokenExchangeCache, ExchangedToken};
+use crate::util;
 
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct SharedCache {
     sessions: HashMap<String, Session>,
+    #[serde(default)]
+    introspections: HashMap<String, CachedIntrospection>,
+    // Result received for the current request, used even if it expired at once, e.g. with a ttl of 0
+    #[serde(skip)]
+    received_introspection: Option<(String, CachedIntrospection)>,
+    #[serde(default)]
+    exchanged_tokens: HashMap<String, ExchangedToken>,
+    // Token exchanged for the current request, used even if it expired at once
+    #[serde(skip)]
+    received_exchanged_token: Option<(String, ExchangedToken)>,
 }
 
 const SHARED_SESSIONS_KEY: &str = "SHARED_SESSIONS";
@@ -15,7 +28,11 @@ impl SharedCache {
 
     pub fn new() -> SharedCache {
         SharedCache {
-            sessions: HashMap::new()
+            sessions: HashMap::new(),
+            introspections: HashMap::new(),
+            received_introspection: None,
+            exchanged_tokens: HashMap::new(),
+            received_exchanged_token: None,
         }
     }
 
@@ -54,12 +71,51 @@ impl SessionCache for SharedCache {
     }
 }
 
+impl IntrospectionCache for SharedCache {
+    // Results are kept by the hash of the token
+    fn get_introspection(&self, token: &str) -> Option<CachedIntrospection> {
+        let key = util::token_hash(token);
+        match &self.received_introspection {
+            Some((received, introspection)) if *received == key => Some(introspection.clone()),
+            _ => self.introspections.get(&key).filter(|introspection| !introspection.is_expired()).cloned(),
+        }
+    }
+
+    fn set_introspection(&mut self, token: &str, introspection: CachedIntrospection) {
+        let key = util::token_hash(token);
+        // Drop expired results so the cache does not grow with every token seen
+        self.introspections.retain(|_, introspection| !introspection.is_expired());
+        self.introspections.insert(key.clone(), introspection.clone());
+        self.received_introspection = Some((key, introspection));
+    }
+}
+
+impl TokenExchangeCache for SharedCache {
+    fn get_exchanged_token(&self, subject_token: &str, audience: &str) -> Option<ExchangedToken> {
+        let key = token_exchange::cache_key(subject_token, audience);
+        match &self.received_exchanged_token {
+            Some((received, token)) if *received == key => Some(token.clone()),
+            _ => self.exchanged_tokens.get(&key).filter(|token| !token.is_expired()).cloned(),
+        }
+    }
+
+    fn set_exchanged_token(&mut self, subject_token: &str, audience: &str, token: ExchangedToken) {
+        let key = token_exchange::cache_key(subject_token, audience);
+        self.exchanged_tokens.retain(|_, token| !token.is_expired());
+        self.exchanged_tokens.insert(key.clone(), token.clone());
+        self.received_exchanged_token = Some((key, token));
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use crate::cache::SharedCache;
     use proxy_wasm::traits::Context;
     use proxy_wasm::types::{Status, Bytes};
     use crate::session::{SessionCache, SessionUpdate, SessionType};
+    use crate::introspection::{CachedIntrospection, IntrospectionCache, IntrospectionResponse};
+    use crate::token_exchange::{ExchangedToken, TokenExchangeCache};
+    use std::time::Duration;
 
     struct TestContext {
         data: Vec<u8>,
@@ -113,4 +169,33 @@ mod tests {
 
     }
 
+    #[test]
+    fn introspections() {
+        let mut cache = SharedCache::new();
+        let response = IntrospectionResponse::from_bytes(b"{\"active\": true}").unwrap();
+        cache.set_introspection("opaquetoken", CachedIntrospection::new(response, 0));
+        // The result received for the request is used even if it expired at once
+        assert!(cache.get_introspection("opaquetoken").unwrap().is_active());
+
+        // Tokens are not kept in the shared data, and expired results are not returned
+        let serialized = serde_json::to_string(&cache).unwrap();
+        assert!(!serialized.contains("opaquetoken"));
+        let cache: SharedCache = serde_json::from_str(&serialized).unwrap();
+        assert!(cache.get_introspection("opaquetoken").is_none());
+    }
+
+    #[test]
+    fn exchanged_tokens() {
+        let mut cache = SharedCache::new();
+        let token = ExchangedToken::new("exchangedtoken".to_string(), Some(Duration::from_secs(0)));
+        cache.set_exchanged_token("subjecttoken", "api2", token);
+        // The token exchanged for the request is used even if it expired at once
+        assert_eq!(cache.get_exchanged_token("subjecttoken", "api2").unwrap().access_token(), "exchangedtoken");
+        assert!(cache.get_exchanged_token("subjecttoken", "api3").is_none());
+        assert!(!serde_json::to_string(&cache).unwrap().contains("subjecttoken"));
+
+        let cache: SharedCache = serde_json::from_str(&serde_json::to_string(&cache).unwrap()).unwrap();
+        assert!(cache.get_exchanged_token("subjecttoken", "api2").is_none());
+    }
+
 }
@@ -1,15 +1,26 @@
+use crate::authorization::PathRule;
+use crate::bff::BffConfig;
+use crate::post_replay::PostReplayConfig;
+use crate::client_auth::{
+    ClientAuthentication, ClientKey, TokenEndpointAuthMethod, CLIENT_ASSERTION_TYPE_JWT_BEARER,
+};
 use crate::discovery::ConfigError::BadState;
-use crate::discovery::{JsonWebKeySet, ProviderMetadata};
+use crate::discovery::{ConfigError, JsonWebKeySet, ProviderMetadata};
+use crate::error_page::ErrorPages;
+use crate::token_exchange::{ExchangeTarget, GRANT_TYPE_TOKEN_EXCHANGE, TOKEN_TYPE_ACCESS_TOKEN};
 use crate::util;
 use jwt_simple::claims::NoCustomClaims;
 use jwt_simple::prelude::{Claims, JWTClaims, RSAPublicKeyLike, VerificationOptions};
 use jwt_simple::Error;
 use oauth2::basic::{BasicClient, BasicErrorResponse, BasicTokenResponse, BasicTokenType};
 use oauth2::{
-    AuthType, AuthUrl, Client, ClientId, ClientSecret, CsrfToken, HttpRequest, PkceCodeChallenge,
+    AuthUrl, Client, ClientId, ClientSecret, CsrfToken, HttpRequest, PkceCodeChallenge,
     RedirectUrl, Scope, TokenUrl,
 };
+use oauth2::http::header::{ACCEPT, AUTHORIZATION};
+use oauth2::http::{HeaderMap, HeaderValue, Method};
 use serde::Deserialize;
+use serde_json::{Map, Value};
 use std::collections::HashSet;
 use std::fmt::Debug;
 use time::Duration;
@@ -35,8 +46,38 @@ pub struct FilterConfig {
     cookie_expire: Duration,
     extra_authorization_params: Vec<(String, String)>,
     extra: ExtraConfig,
+    rules: Vec<PathRule>,
+    introspection_uri: Option<Url>,
+    introspect_session_tokens: bool,
+    introspection_cache_ttl: u64,
+    token_exchange_uri: Option<Url>,
+    exchange_targets: Vec<ExchangeTarget>,
+    client_authentication: ClientAuthentication,
+    par_uri: Option<Url>,
+    request_object_key: Option<ClientKey>,
+    form_post: bool,
+    error_redirect_uri: Option<Url>,
+    error_page: Option<String>,
+    iss_parameter_required: bool,
+    provider: Option<String>,
+    userinfo_uri: Option<Url>,
+    userinfo_claims: Vec<String>,
+    session_info: bool,
+    session_info_claims: Vec<String>,
+    session_info_tokens: bool,
+    bff: Option<BffConfig>,
+    silent_renewal_attempts: u32,
+    redirect_loop_threshold: u32,
+    redirect_loop_window: u64,
+    post_replay: Option<PostReplayConfig>,
+    error_pages: ErrorPages,
 }
 
+// Lifetime of signed request objects in seconds
+const REQUEST_OBJECT_LIFETIME: u64 = 300;
+// Authorization parameters that are JSON numbers in request objects, OpenID Connect Core 1.0 section 6.1
+const NUMERIC_REQUEST_OBJECT_CLAIMS: [&str; 1] = ["max_age"];
+
 impl FilterConfig {
     pub fn new(
         cookie_name: &str,
@@ -65,12 +106,294 @@ impl FilterConfig {
             cookie_expire,
             extra_authorization_params,
             extra,
+            rules: vec![],
+            introspection_uri: None,
+            introspect_session_tokens: false,
+            introspection_cache_ttl: 0,
+            token_exchange_uri: None,
+            exchange_targets: vec![],
+            client_authentication: ClientAuthentication::ClientSecretPost,
+            par_uri: None,
+            request_object_key: None,
+            form_post: false,
+            error_redirect_uri: None,
+            error_page: None,
+            iss_parameter_required: false,
+            provider: None,
+            userinfo_uri: None,
+            userinfo_claims: vec![],
+            session_info: false,
+            session_info_claims: vec![],
+            session_info_tokens: false,
+            bff: None,
+            silent_renewal_attempts: 0,
+            redirect_loop_threshold: 0,
+            redirect_loop_window: 0,
+            post_replay: None,
+            error_pages: ErrorPages::default(),
+        }
+    }
+
+    // Keys of an OAuth authorization server validating JWT access tokens, OpenID Connect providers are created with theirs
+    pub fn with_jwks(mut self, jwks: JsonWebKeySet) -> FilterConfig {
+        if let ExtraConfig::BasicOAuth { .. } = self.extra {
+            self.extra = ExtraConfig::BasicOAuth { jwks };
+        }
+        self
+    }
+
+    // Sets the path rules, rules are matched against request paths in order
+    pub fn with_rules(mut self, rules: Vec<PathRule>) -> FilterConfig {
+        self.rules = rules;
+        self
+    }
+
+    // Enables token introspection (RFC 7662) against the endpoint, results are cached for the ttl in seconds
+    pub fn with_introspection(
+        mut self,
+        introspection_uri: Option<Url>,
+        introspect_session_tokens: bool,
+        introspection_cache_ttl: u64,
+    ) -> FilterConfig {
+        self.introspection_uri = introspection_uri;
+        self.introspect_session_tokens = introspect_session_tokens;
+        self.introspection_cache_ttl = introspection_cache_ttl;
+        self
+    }
+
+    pub fn introspection_enabled(&self) -> bool {
+        self.introspection_uri.is_some()
+    }
+
+    pub fn introspect_session_tokens(&self) -> bool {
+        self.introspection_enabled() && self.introspect_session_tokens
+    }
+
+    pub fn introspection_cache_ttl(&self) -> u64 {
+        self.introspection_cache_ttl
+    }
+
+    // Enables token exchange (RFC 8693) for requests to the target hosts, the token endpoint is used by default
+    pub fn with_token_exchange(
+        mut self,
+        token_exchange_uri: Option<Url>,
+        exchange_targets: Vec<ExchangeTarget>,
+    ) -> FilterConfig {
+        self.token_exchange_uri = token_exchange_uri;
+        self.exchange_targets = exchange_targets;
+        self
+    }
+
+    // Sets how the filter authenticates to the token, introspection and token exchange endpoints
+    pub fn with_client_authentication(
+        mut self,
+        client_authentication: ClientAuthentication,
+    ) -> FilterConfig {
+        self.client_authentication = client_authentication;
+        self
+    }
+
+    // Enables pushed authorization requests (RFC 9126) to the endpoint
+    pub fn with_pushed_authorization(mut self, par_uri: Option<Url>) -> FilterConfig {
+        self.par_uri = par_uri;
+        self
+    }
+
+    // Sends the authorization parameters as a request object signed with the key (RFC 9101)
+    pub fn with_request_objects(mut self, request_object_key: Option<ClientKey>) -> FilterConfig {
+        self.request_object_key = request_object_key;
+        self
+    }
+
+    // Requests authorization responses with response_mode=form_post
+    pub fn with_form_post(mut self, form_post: bool) -> FilterConfig {
+        self.form_post = form_post;
+        self
+    }
+
+    pub fn form_post(&self) -> bool {
+        self.form_post
+    }
+
+    // End-users are redirected to the URI, or shown the HTML page, on authorization error responses
+    pub fn with_error_handling(
+        mut self,
+        error_redirect_uri: Option<Url>,
+        error_page: Option<String>,
+    ) -> FilterConfig {
+        self.error_redirect_uri = error_redirect_uri;
+        self.error_page = error_page;
+        self
+    }
+
+    pub fn error_redirect_uri(&self) -> Option<&Url> {
+        self.error_redirect_uri.as_ref()
+    }
+
+    pub fn error_page(&self) -> Option<&str> {
+        self.error_page.as_deref()
+    }
+
+    // Names the provider of this configuration, for filters with several providers sharing the session cookie
+    pub fn with_provider(mut self, provider: &str, cookie_name: &str) -> FilterConfig {
+        self.provider = Some(provider.to_string());
+        self.cookie_name = cookie_name.to_string();
+        self
+    }
+
+    pub fn provider(&self) -> Option<&str> {
+        self.provider.as_deref()
+    }
+
+    // Fetches the claims from the userinfo endpoint after token responses, only the listed claims are kept if any
+    pub fn with_userinfo(mut self, userinfo_uri: Option<Url>, userinfo_claims: Vec<String>) -> FilterConfig {
+        self.userinfo_uri = userinfo_uri;
+        self.userinfo_claims = userinfo_claims;
+        self
+    }
+
+    pub fn userinfo_claims(&self) -> &Vec<String> {
+        &self.userinfo_claims
+    }
+
+    // Enables the session info endpoint with the claims it returns, the raw tokens are only returned if enabled
+    pub fn with_session_info(
+        mut self,
+        session_info: bool,
+        session_info_claims: Vec<String>,
+        session_info_tokens: bool,
+    ) -> FilterConfig {
+        self.session_info = session_info;
+        self.session_info_claims = session_info_claims;
+        self.session_info_tokens = session_info_tokens;
+        self
+    }
+
+    pub fn session_info(&self) -> bool {
+        self.session_info
+    }
+
+    pub fn session_info_claims(&self) -> &Vec<String> {
+        &self.session_info_claims
+    }
+
+    pub fn session_info_tokens(&self) -> bool {
+        self.session_info_tokens
+    }
+
+    // Enables the token endpoint for front-ends
+    pub fn with_bff(mut self, bff: Option<BffConfig>) -> FilterConfig {
+        self.bff = bff;
+        self
+    }
+
+    pub fn bff(&self) -> Option<&BffConfig> {
+        self.bff.as_ref()
+    }
+
+    // Expired sessions are renewed with prompt=none up to this many times in a row, 0 disables silent renewal
+    pub fn with_silent_renewal(mut self, silent_renewal_attempts: u32) -> FilterConfig {
+        self.silent_renewal_attempts = silent_renewal_attempts;
+        self
+    }
+
+    pub fn silent_renewal_attempts(&self) -> u32 {
+        self.silent_renewal_attempts
+    }
+
+    // Browsers starting the threshold of authorization requests within the window in seconds get a diagnostic page,
+    // 0 disables the check
+    pub fn with_redirect_loop(mut self, redirect_loop_threshold: u32, redirect_loop_window: u64) -> FilterConfig {
+        self.redirect_loop_threshold = redirect_loop_threshold;
+        self.redirect_loop_window = redirect_loop_window;
+        self
+    }
+
+    pub fn redirect_loop_threshold(&self) -> u32 {
+        self.redirect_loop_threshold
+    }
+
+    pub fn redirect_loop_window(&self) -> u64 {
+        self.redirect_loop_window
+    }
+
+    // Saves POST request bodies with the authorization request and replays them after the callback
+    pub fn with_post_replay(mut self, post_replay: Option<PostReplayConfig>) -> FilterConfig {
+        self.post_replay = post_replay;
+        self
+    }
+
+    pub fn post_replay(&self) -> Option<&PostReplayConfig> {
+        self.post_replay.as_ref()
+    }
+
+    // Templates of the pages shown to browsers for errors and sign out
+    pub fn with_error_pages(mut self, error_pages: ErrorPages) -> FilterConfig {
+        self.error_pages = error_pages;
+        self
+    }
+
+    pub fn error_pages(&self) -> &ErrorPages {
+        &self.error_pages
+    }
+
+    // Userinfo request with the access token, OpenID Connect Core 1.0 section 5.3.1. None if userinfo is not enabled.
+    pub fn userinfo_request(&self, access_token: &str) -> Option<HttpRequest> {
+        let userinfo_uri = self.userinfo_uri.as_ref()?;
+        let mut headers = HeaderMap::new();
+        headers.insert(AUTHORIZATION, HeaderValue::from_str(&format!("Bearer {}", access_token)).ok()?);
+        headers.insert(ACCEPT, HeaderValue::from_static("application/json"));
+        Some(HttpRequest {
+            url: userinfo_uri.clone(),
+            method: Method::GET,
+            headers,
+            body: vec![],
+        })
+    }
+
+    // Issuer identifier of the discovered provider metadata, it may differ from the configured issuer by a trailing
+    // slash. Tokens and authorization responses carry the discovered one.
+    pub fn with_issuer(mut self, issuer: &str) -> FilterConfig {
+        self.issuer = issuer.to_string();
+        self
+    }
+
+    // Requires the iss parameter in authorization responses, RFC 9207
+    pub fn with_iss_parameter_required(mut self, iss_parameter_required: bool) -> FilterConfig {
+        self.iss_parameter_required = iss_parameter_required;
+        self
+    }
+
+    // Validates the iss parameter of an authorization response against the issuer, RFC 9207 section 2.4
+    pub fn validate_iss_parameter(&self, iss: Option<&str>) -> Result<(), String> {
+        match iss {
+            None if self.iss_parameter_required => Err("missing iss parameter".to_string()),
+            None => Ok(()),
+            // Simple string comparison without normalization, RFC 9207 section 2.4
+            Some(iss) if iss == self.issuer => Ok(()),
+            Some(iss) => Err(format!("iss={} does not match the issuer", iss)),
         }
     }
 
+    pub fn extra_authorization_params(&self) -> &Vec<(String, String)> {
+        &self.extra_authorization_params
+    }
+
+    // Returns the token exchange target for requests to the host
+    pub fn exchange_target(&self, host: &str) -> Option<&ExchangeTarget> {
+        self.exchange_targets
+            .iter()
+            .find(|target| target.matches(host))
+    }
+
+    // Returns the first path rule matching the path
+    pub fn rule(&self, path: &str) -> Option<&PathRule> {
+        self.rules.iter().find(|rule| rule.matches(path))
+    }
+
     pub fn validate_token(&self, token: &str) -> Result<(), Error> {
         let allowed_issuers: HashSet<String> =
-            vec![&self.issuer].iter().map(|s| s.to_string()).collect();
+            [&self.issuer].iter().map(|s| s.to_string()).collect();
 
         // Allowed audiences for ID token is client id and the issuer (for userinfo fetching)
         let mut allowed_audiences = allowed_issuers.clone();
@@ -88,10 +411,41 @@ impl FilterConfig {
             time_tolerance: None,
             max_validity: None,
         };
-        let _ = self.extra.validate_id_token(token, None)?;
+        let _ = self.extra.validate_id_token(token, Some(option))?;
         Ok(())
     }
 
+    // Validates a JWT access token presented as a bearer token, the audience must be one of the audiences
+    pub fn validate_access_token(
+        &self,
+        token: &str,
+        audiences: &[String],
+    ) -> Result<JWTClaims<NoCustomClaims>, Error> {
+        let allowed_issuers: HashSet<String> =
+            [&self.issuer].iter().map(|s| s.to_string()).collect();
+
+        // Access tokens must be issued for one of the audiences, or for this client by default
+        let allowed_audiences: HashSet<String> = if audiences.is_empty() {
+            vec![self.client_id.clone()].into_iter().collect()
+        } else {
+            audiences.iter().cloned().collect()
+        };
+
+        let options = VerificationOptions {
+            reject_before: None,
+            accept_future: false,
+            required_subject: None,
+            required_key_id: None,
+            required_public_key: None,
+            required_nonce: None,
+            allowed_issuers: Some(allowed_issuers),
+            allowed_audiences: Some(allowed_audiences),
+            time_tolerance: None,
+            max_validity: None,
+        };
+        self.extra.validate_access_token(token, Some(options))
+    }
+
     pub fn cookie_name(&self) -> &str {
         &self.cookie_name
     }
@@ -149,7 +503,9 @@ impl FilterConfig {
             scopes,
             cookie_expire,
             extra_authorization_params,
-            ExtraConfig::BasicOAuth,
+            ExtraConfig::BasicOAuth {
+                jwks: JsonWebKeySet::new(vec![]),
+            },
         )
     }
 }
@@ -190,25 +546,62 @@ impl FilterConfig {
             scopes,
             cookie_expire,
             extra_authorization_params,
-            extra: ExtraConfig::OIDC {
-                jwks,
-                provider_metadata,
-            },
+            extra: ExtraConfig::OIDC { jwks },
+            rules: vec![],
+            introspection_uri: None,
+            introspect_session_tokens: false,
+            introspection_cache_ttl: 0,
+            token_exchange_uri: None,
+            exchange_targets: vec![],
+            client_authentication: ClientAuthentication::ClientSecretPost,
+            par_uri: None,
+            request_object_key: None,
+            form_post: false,
+            error_redirect_uri: None,
+            error_page: None,
+            iss_parameter_required: false,
+            provider: None,
+            userinfo_uri: None,
+            userinfo_claims: vec![],
+            session_info: false,
+            session_info_claims: vec![],
+            session_info_tokens: false,
+            bff: None,
+            silent_renewal_attempts: 0,
+            redirect_loop_threshold: 0,
+            redirect_loop_window: 0,
+            post_replay: None,
+            error_pages: ErrorPages::default(),
         }
     }
 
     pub fn authorization_url(
         &self,
         pkce_challenge: PkceCodeChallenge,
-    ) -> (Url, CsrfToken) {
+        extra_params: &[(String, String)],
+    ) -> Result<(Url, CsrfToken), Error> {
         let builder = self.client();
         let mut builder = builder
             .authorize_url(|| CsrfToken::new(util::new_random_verifier(32).secret().to_string()))
             // Set the PKCE code challenge.
             .set_pkce_challenge(pkce_challenge);
 
-        // Add extra parameters for Authorization redirect from configuration
-        for param in &self.extra_authorization_params {
+        // Add extra parameters for Authorization redirect from configuration,
+        // unless overridden by the parameters for this particular redirect
+        for param in self
+            .extra_authorization_params
+            .iter()
+            .filter(|(name, _)| !extra_params.iter().any(|(extra, _)| extra == name))
+        {
+            builder = builder.add_extra_param(param.0.as_str(), param.1.as_str());
+        }
+
+        if self.form_post {
+            builder = builder.add_extra_param("response_mode", "form_post");
+        }
+
+        // Add extra parameters for this particular redirect, e.g. from path rules
+        for param in extra_params {
             builder = builder.add_extra_param(param.0.as_str(), param.1.as_str());
         }
 
@@ -217,10 +610,122 @@ impl FilterConfig {
             builder = builder.add_scope(Scope::new(scope.clone()))
         }
 
-        builder.url()
+        let (url, csrf_token) = builder.url();
+        match &self.request_object_key {
+            None => Ok((url, csrf_token)),
+            Some(key) => Ok((self.request_object_url(&url, key)?, csrf_token)),
+        }
+    }
+
+    // Authorization URL with the parameters in a signed request object, RFC 9101 section 5.
+    // OpenID Connect requires client_id, response_type and scope to be sent as parameters as well.
+    fn request_object_url(&self, authorization_url: &Url, key: &ClientKey) -> Result<Url, Error> {
+        let params: Map<String, Value> = authorization_url
+            .query_pairs()
+            .map(|(name, value)| match value.parse::<u64>() {
+                Ok(number) if NUMERIC_REQUEST_OBJECT_CLAIMS.contains(&name.as_ref()) => {
+                    (name.to_string(), Value::from(number))
+                }
+                _ => (name.to_string(), Value::String(value.to_string())),
+            })
+            .collect();
+        let claims = Claims::with_custom_claims(
+            params.clone(),
+            jwt_simple::prelude::Duration::from_secs(REQUEST_OBJECT_LIFETIME),
+        )
+        .with_issuer(&self.client_id)
+        .with_audience(&self.issuer)
+        .with_jwt_id(util::new_random_verifier(32).secret());
+        let request_object = key.sign(claims)?;
+
+        let mut url = self.auth_uri.clone();
+        {
+            let mut query = url.query_pairs_mut();
+            for name in ["client_id", "response_type", "scope"].iter() {
+                if let Some(Value::String(value)) = params.get(*name) {
+                    query.append_pair(name, value);
+                }
+            }
+            query.append_pair("request", &request_object);
+        }
+        Ok(url)
+    }
+
+    // Token introspection request, RFC 7662 section 2.1
+    pub fn introspection_request(&self, token: &str) -> Result<Option<HttpRequest>, Error> {
+        let introspection_uri = match self.introspection_uri.as_ref() {
+            None => return Ok(None),
+            Some(introspection_uri) => introspection_uri,
+        };
+        let params = vec![("token", token), ("token_type_hint", "access_token")];
+
+        self.authenticated_request(introspection_uri, None, params)
+            .map(Some)
     }
 
-    pub fn token_request(&self, code: String, code_verifier: Option<String>) -> HttpRequest {
+    // Pushes the parameters of the authorization URL to the PAR endpoint, RFC 9126 section 2.1.
+    // Returns None if pushed authorization requests are not enabled.
+    pub fn pushed_authorization_request(
+        &self,
+        authorization_url: &Url,
+    ) -> Result<Option<HttpRequest>, Error> {
+        let par_uri = match self.par_uri.as_ref() {
+            None => return Ok(None),
+            Some(par_uri) => par_uri,
+        };
+        // The client ID is added by the client authentication
+        let query: Vec<(String, String)> = authorization_url
+            .query_pairs()
+            .filter(|(name, _)| name != "client_id")
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect();
+        let params = query
+            .iter()
+            .map(|(name, value)| (name.as_str(), value.as_str()))
+            .collect();
+
+        self.authenticated_request(par_uri, None, params).map(Some)
+    }
+
+    // Authorization redirect referencing the pushed request, RFC 9126 section 4
+    pub fn pushed_authorization_url(&self, request_uri: &str) -> Url {
+        let mut url = self.auth_uri.clone();
+        url.query_pairs_mut()
+            .append_pair("client_id", &self.client_id)
+            .append_pair("request_uri", request_uri);
+        url
+    }
+
+    // Token exchange request for a token with the target audience, RFC 8693 section 2.1
+    pub fn token_exchange_request(
+        &self,
+        subject_token: &str,
+        target: &ExchangeTarget,
+    ) -> Result<HttpRequest, Error> {
+        let scopes = target.scopes().join(" ");
+        let mut params = vec![
+            ("grant_type", GRANT_TYPE_TOKEN_EXCHANGE),
+            ("subject_token", subject_token),
+            ("subject_token_type", TOKEN_TYPE_ACCESS_TOKEN),
+            ("requested_token_type", TOKEN_TYPE_ACCESS_TOKEN),
+            ("audience", target.audience()),
+        ];
+        if !scopes.is_empty() {
+            params.push(("scope", scopes.as_str()));
+        }
+
+        self.authenticated_request(
+            self.token_exchange_uri.as_ref().unwrap_or(&self.token_uri),
+            None,
+            params,
+        )
+    }
+
+    pub fn token_request(
+        &self,
+        code: String,
+        code_verifier: Option<String>,
+    ) -> Result<HttpRequest, Error> {
         let mut params = vec![
             ("grant_type", "authorization_code"),
             ("code", code.as_str()),
@@ -233,25 +738,67 @@ impl FilterConfig {
             params.push(("code_verifier", verifier_string.as_str()))
         }
 
-        util::token_request(
-            &AuthType::RequestBody,
+        self.authenticated_request(
+            &self.token_uri,
+            Some(RedirectUrl::from_url(self.redirect_uri.clone())),
+            params,
+        )
+    }
+
+    // Refresh token request, RFC 6749 section 6
+    pub fn refresh_request(&self, refresh_token: &str) -> Result<HttpRequest, Error> {
+        let params = vec![
+            ("grant_type", "refresh_token"),
+            ("refresh_token", refresh_token),
+        ];
+        self.authenticated_request(&self.token_uri, None, params)
+    }
+
+    // Request to an endpoint of the authorization server, authenticated with the configured method.
+    // Client assertions are created per request with the token endpoint as audience, RFC 7523 section 3.
+    fn authenticated_request(
+        &self,
+        url: &Url,
+        redirect_url: Option<RedirectUrl>,
+        params: Vec<(&str, &str)>,
+    ) -> Result<HttpRequest, Error> {
+        let client_assertion = self.client_authentication.client_assertion(
+            &self.client_id,
+            &self.client_secret,
+            self.token_uri.as_str(),
+        )?;
+
+        let mut params = params;
+        if let Some(ref client_assertion) = client_assertion {
+            params.push(("client_assertion_type", CLIENT_ASSERTION_TYPE_JWT_BEARER));
+            params.push(("client_assertion", client_assertion));
+        }
+        let client_secret = match self.client_authentication.sends_client_secret() {
+            true => Some(ClientSecret::new(self.client_secret().to_string())),
+            false => None,
+        };
+
+        Ok(util::token_request(
+            &self.client_authentication.auth_type(),
             &ClientId::new(self.client_id().to_string()),
-            Some(&ClientSecret::new(self.client_secret().to_string())),
+            client_secret.as_ref(),
             &[],
-            Some(&RedirectUrl::from_url(self.redirect_uri.clone())),
+            redirect_url.as_ref(),
             None,
-            &TokenUrl::from_url(self.token_uri.clone()),
+            &TokenUrl::from_url(url.clone()),
             params,
-        )
+        ))
     }
 }
 
 #[derive(Clone, Debug)]
 pub enum ExtraConfig {
-    BasicOAuth,
+    // Keys of the authorization server validate JWT access tokens, the set is empty if it publishes none
+    BasicOAuth {
+        jwks: JsonWebKeySet,
+    },
     OIDC {
         jwks: JsonWebKeySet,
-        provider_metadata: ProviderMetadata,
     },
 }
 
@@ -281,18 +828,47 @@ impl ExtraConfig {
         options: Option<VerificationOptions>,
     ) -> Result<JWTClaims<NoCustomClaims>, Error> {
         match self {
-            ExtraConfig::BasicOAuth => Err(Error::new(BadState(
+            ExtraConfig::BasicOAuth { .. } => Err(Error::new(BadState(
                 "Asked to validate ID token, but configured for OAuth".to_string(),
             ))),
-            ExtraConfig::OIDC { jwks, .. } => self.validate(token, jwks, options),
+            ExtraConfig::OIDC { jwks } => self.validate(token, jwks, options),
+        }
+    }
+
+    pub fn validate_access_token(
+        &self,
+        token: &str,
+        options: Option<VerificationOptions>,
+    ) -> Result<JWTClaims<NoCustomClaims>, Error> {
+        match self {
+            ExtraConfig::BasicOAuth { jwks } | ExtraConfig::OIDC { jwks } if jwks.keys().is_empty() => {
+                Err(Error::new(BadState(
+                    "Asked to validate access token, but the JWKS contains no keys".to_string(),
+                )))
+            }
+            ExtraConfig::BasicOAuth { jwks } | ExtraConfig::OIDC { jwks } => {
+                self.validate(token, jwks, options)
+            }
         }
     }
 }
 
+// Protocol spoken with the provider
+#[derive(Deserialize, Clone, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum ProviderMode {
+    Oidc,
+    Oauth,
+}
+
+// Name of the provider configured at the top level of the configuration
+pub const DEFAULT_PROVIDER: &str = "default";
+
 // Struct representing the raw configuration passed from the proxy
 // Serves as a protection layer between external and internal representation of configuration
-#[derive(Deserialize, Clone, Debug)]
+#[derive(Deserialize, Clone)]
 pub struct RawFilterConfig {
+    mode: Option<ProviderMode>,
     #[serde(default = "default_redirect_uri")]
     redirect_uri: String,
     #[serde(default = "default_target_header_name")]
@@ -304,40 +880,218 @@ pub struct RawFilterConfig {
     auth_uri: Option<String>,
     token_uri: Option<String>,
     client_id: String,
-    client_secret: String,
+    #[serde(default)]
+    client_secret: String, // empty for public clients
     #[serde(default = "default_scopes")]
     scopes: Vec<String>,
     #[serde(default = "default_cookie_expire")]
     cookie_expire: u64, // in seconds
     #[serde(default = "default_extra_params")]
     extra_params: Vec<(String, String)>,
+    #[serde(default)]
+    rules: Vec<PathRule>,
+    introspection_uri: Option<String>,
+    #[serde(default)]
+    introspect_session_tokens: bool,
+    #[serde(default = "default_introspection_cache_ttl")]
+    introspection_cache_ttl: u64, // in seconds
+    token_exchange_uri: Option<String>,
+    #[serde(default)]
+    token_exchange: Vec<ExchangeTarget>,
+    token_endpoint_auth_method: Option<TokenEndpointAuthMethod>,
+    #[serde(default)]
+    pushed_authorization_requests: bool,
+    #[serde(default)]
+    request_objects: bool,
+    #[serde(default = "default_response_mode")]
+    response_mode: String,
+    error_redirect_uri: Option<String>,
+    error_page: Option<String>,
+    par_uri: Option<String>,
+    client_private_key: Option<String>,
+    client_key_id: Option<String>,
+    // Static provider configuration, replaces discovery of the metadata and JWKS
+    provider_metadata: Option<ProviderMetadata>,
+    jwks: Option<JsonWebKeySet>,
+    // Fetch claims from the userinfo endpoint after token responses
+    #[serde(default)]
+    userinfo: bool,
+    userinfo_uri: Option<String>,
+    #[serde(default)]
+    userinfo_claims: Vec<String>,
+    // Session info endpoint
+    #[serde(default)]
+    session_info: bool,
+    #[serde(default = "default_session_info_claims")]
+    session_info_claims: Vec<String>,
+    #[serde(default)]
+    session_info_tokens: bool,
+    bff: Option<BffConfig>,
+    // Renew expired sessions with prompt=none authorization requests
+    #[serde(default)]
+    silent_renewal_attempts: u32,
+    // Stop redirecting browsers that keep starting authorization requests
+    #[serde(default = "default_redirect_loop_threshold")]
+    redirect_loop_threshold: u32,
+    #[serde(default = "default_redirect_loop_window")]
+    redirect_loop_window: u64, // in seconds
+    post_replay: Option<PostReplayConfig>,
+    // Templates of the login error, access denied, signed out and not ready pages
+    #[serde(default)]
+    error_pages: ErrorPages,
+    // Accept provider endpoints without https, for development only
+    #[serde(default)]
+    allow_insecure_endpoints: bool,
+    // Additional providers, the top level configuration is the default provider
+    #[serde(default)]
+    providers: Vec<RawProviderConfig>,
+    #[serde(default)]
+    provider_picker: bool,
+}
+
+// Leaves out the client secret and private key, configurations are logged. Destructuring makes new fields a
+// compile error until they are listed here
+impl Debug for RawFilterConfig {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let RawFilterConfig {
+            mode, redirect_uri, target_header_name, cookie_name, auth_cluster, issuer, auth_uri, token_uri,
+            client_id, client_secret, scopes, cookie_expire, extra_params, rules, introspection_uri,
+            introspect_session_tokens, introspection_cache_ttl, token_exchange_uri, token_exchange,
+            token_endpoint_auth_method, pushed_authorization_requests, request_objects, response_mode,
+            error_redirect_uri, error_page, par_uri, client_private_key, client_key_id, provider_metadata, jwks,
+            userinfo, userinfo_uri, userinfo_claims, session_info, session_info_claims, session_info_tokens,
+            bff, silent_renewal_attempts, redirect_loop_threshold, redirect_loop_window, post_replay,
+            error_pages, allow_insecure_endpoints, providers, provider_picker,
+        } = self;
+        f.debug_struct("RawFilterConfig")
+            .field("mode", mode)
+            .field("redirect_uri", redirect_uri)
+            .field("target_header_name", target_header_name)
+            .field("cookie_name", cookie_name)
+            .field("auth_cluster", auth_cluster)
+            .field("issuer", issuer)
+            .field("auth_uri", auth_uri)
+            .field("token_uri", token_uri)
+            .field("client_id", client_id)
+            .field("client_secret", &redacted(!client_secret.is_empty()))
+            .field("scopes", scopes)
+            .field("cookie_expire", cookie_expire)
+            .field("extra_params", extra_params)
+            .field("rules", rules)
+            .field("introspection_uri", introspection_uri)
+            .field("introspect_session_tokens", introspect_session_tokens)
+            .field("introspection_cache_ttl", introspection_cache_ttl)
+            .field("token_exchange_uri", token_exchange_uri)
+            .field("token_exchange", token_exchange)
+            .field("token_endpoint_auth_method", token_endpoint_auth_method)
+            .field("pushed_authorization_requests", pushed_authorization_requests)
+            .field("request_objects", request_objects)
+            .field("response_mode", response_mode)
+            .field("error_redirect_uri", error_redirect_uri)
+            .field("error_page", error_page)
+            .field("par_uri", par_uri)
+            .field("client_private_key", &redacted(client_private_key.is_some()))
+            .field("client_key_id", client_key_id)
+            .field("provider_metadata", provider_metadata)
+            .field("jwks", jwks)
+            .field("userinfo", userinfo)
+            .field("userinfo_uri", userinfo_uri)
+            .field("userinfo_claims", userinfo_claims)
+            .field("session_info", session_info)
+            .field("session_info_claims", session_info_claims)
+            .field("session_info_tokens", session_info_tokens)
+            .field("bff", bff)
+            .field("silent_renewal_attempts", silent_renewal_attempts)
+            .field("redirect_loop_threshold", redirect_loop_threshold)
+            .field("redirect_loop_window", redirect_loop_window)
+            .field("post_replay", post_replay)
+            .field("error_pages", error_pages)
+            .field("allow_insecure_endpoints", allow_insecure_endpoints)
+            .field("providers", providers)
+            .field("provider_picker", provider_picker)
+            .finish()
+    }
+}
+
+fn redacted(configured: bool) -> Option<&'static str> {
+    configured.then_some("<redacted>")
+}
+
+// Configuration of an additional provider, with the requests that select it
+#[derive(Deserialize, Clone, Debug)]
+pub struct RawProviderConfig {
+    pub name: String,
+    #[serde(default)]
+    pub hosts: Vec<String>,
+    pub path_prefix: Option<String>,
+    #[serde(default)]
+    pub login_hint_domains: Vec<String>,
+    #[serde(flatten)]
+    pub config: RawFilterConfig,
 }
 
 impl RawFilterConfig {
     // Convert Raw config to filter config with rich types
     pub fn oauth_config(&self) -> Result<FilterConfig, Error> {
-        Ok(FilterConfig::oauth(
+        let (auth_uri, token_uri) = match (&self.auth_uri, &self.token_uri) {
+            (Some(auth_uri), Some(token_uri)) => (auth_uri.parse()?, token_uri.parse()?),
+            _ => {
+                return Err(ConfigError::Validation(
+                    "OAuth mode without discovery requires auth_uri and token_uri".to_string(),
+                )
+                .into())
+            }
+        };
+        let jwks = self.jwks.clone().unwrap_or_else(|| JsonWebKeySet::new(vec![]));
+        self.with_provider_metadata(self.basic_oauth_config(&auth_uri, &token_uri, &jwks), None)
+    }
+
+    // OAuth config with the endpoints from the authorization server metadata (RFC 8414), configured endpoints take precedence
+    pub fn discovered_oauth_config(
+        &self,
+        provider_metadata: &ProviderMetadata,
+        jwks: &JsonWebKeySet,
+    ) -> Result<FilterConfig, Error> {
+        let auth_uri = match &self.auth_uri {
+            Some(auth_uri) => auth_uri.parse()?,
+            None => provider_metadata.authorization_endpoint().clone(),
+        };
+        let token_uri = match (&self.token_uri, provider_metadata.token_endpoint()) {
+            (Some(token_uri), _) => token_uri.parse()?,
+            (None, Some(token_uri)) => token_uri.clone(),
+            (None, None) => {
+                return Err(ConfigError::Validation(
+                    "Authorization server metadata without token_endpoint requires a token_uri".to_string(),
+                )
+                .into())
+            }
+        };
+        self.with_provider_metadata(self.basic_oauth_config(&auth_uri, &token_uri, jwks), Some(provider_metadata))
+    }
+
+    fn basic_oauth_config(&self, auth_uri: &Url, token_uri: &Url, jwks: &JsonWebKeySet) -> FilterConfig {
+        FilterConfig::oauth(
             &self.cookie_name,
             &self.auth_cluster,
             &self.issuer,
             &self.redirect_uri.parse().unwrap(),
-            &self.auth_uri.as_ref().unwrap().parse().unwrap(), // TODO FIX
-            &self.token_uri.as_ref().unwrap().parse().unwrap(), // TODO FIX
+            auth_uri,
+            token_uri,
             &self.client_id,
             &self.client_secret,
             self.scopes.clone(),
             time::Duration::seconds(self.cookie_expire as i64),
             self.extra_params.clone(),
-        ))
+        )
+        .with_jwks(jwks.clone())
     }
 
-    // Convert Raw config to filter config with rich types and completed discovery
     pub fn oidc_config(
         &self,
         provider_metadata: &ProviderMetadata,
         jwks: &JsonWebKeySet,
     ) -> Result<FilterConfig, Error> {
-        Ok(FilterConfig::oidc(
+        let config = FilterConfig::oidc(
             &self.cookie_name,
             &self.auth_cluster,
             &self.issuer,
@@ -351,11 +1105,249 @@ impl RawFilterConfig {
             self.extra_params.clone(),
             jwks.clone(),
             provider_metadata.clone(),
-        ))
+        );
+        self.with_provider_metadata(config, Some(provider_metadata))
+    }
+
+    // Applies the rest of the configuration, with defaults from the provider metadata if discovered
+    fn with_provider_metadata(
+        &self,
+        config: FilterConfig,
+        provider_metadata: Option<&ProviderMetadata>,
+    ) -> Result<FilterConfig, Error> {
+        let supported_auth_methods = provider_metadata
+            .map_or(vec!["client_secret_post".to_string()], |provider_metadata| {
+                provider_metadata.token_endpoint_auth_methods_supported()
+            });
+        Ok(config
+            .with_issuer(provider_metadata.map_or(self.issuer.as_str(), |metadata| metadata.issuer()))
+            .with_rules(self.rules.clone())
+            .with_introspection(
+                parse_optional_uri("introspection_uri", &self.introspection_uri)?
+                    .or_else(|| provider_metadata.and_then(|metadata| metadata.introspection_endpoint().clone())),
+                self.introspect_session_tokens,
+                self.introspection_cache_ttl,
+            )
+            .with_token_exchange(
+                parse_optional_uri("token_exchange_uri", &self.token_exchange_uri)?,
+                self.token_exchange.clone(),
+            )
+            .with_client_authentication(self.client_authentication(&supported_auth_methods)?)
+            .with_pushed_authorization(self.par_uri(
+                provider_metadata.and_then(|metadata| metadata.pushed_authorization_request_endpoint().as_ref()),
+                provider_metadata.is_some_and(|metadata| metadata.require_pushed_authorization_requests()),
+            )?)
+            .with_request_objects(self.request_object_key(provider_metadata)?)
+            .with_iss_parameter_required(
+                provider_metadata.is_some_and(|metadata| metadata.authorization_response_iss_parameter_supported()),
+            )
+            .with_form_post(self.form_post()?)
+            .with_userinfo(self.userinfo_uri(provider_metadata)?, self.userinfo_claims.clone())
+            .with_session_info(self.session_info, self.session_info_claims.clone(), self.session_info_tokens)
+            .with_bff(self.bff.clone())
+            .with_silent_renewal(self.silent_renewal_attempts)
+            .with_redirect_loop(self.redirect_loop_threshold, self.redirect_loop_window)
+            .with_post_replay(self.post_replay.clone())
+            .with_error_pages(self.error_pages.clone())
+            .with_error_handling(
+                parse_optional_uri("error_redirect_uri", &self.error_redirect_uri)?,
+                self.error_page.clone(),
+            ))
+    }
+
+    // Validates the configuration when it is loaded, before any requests are handled
+    pub fn validate(&self) -> Result<(), Error> {
+        self.validate_endpoints()?;
+        parse_optional_uri("error_redirect_uri", &self.error_redirect_uri)?;
+        self.client_authentication(&[])?;
+        self.form_post()?;
+        self.request_object_key(None)?;
+        self.static_discovery()?;
+        if let Some(post_replay) = &self.post_replay {
+            post_replay.validate().map_err(ConfigError::Validation)?;
+        }
+        let mut names = vec![DEFAULT_PROVIDER];
+        for provider in &self.providers {
+            if names.contains(&provider.name.as_str()) {
+                return Err(ConfigError::Validation(format!("Duplicate provider name={}", provider.name)).into());
+            }
+            if !provider.config.providers.is_empty() {
+                return Err(ConfigError::Validation(format!("Provider {} can't have providers", provider.name)).into());
+            }
+            names.push(&provider.name);
+            provider.config.validate()?;
+        }
+        Ok(())
+    }
+
+    // Configured endpoints must use https like discovered ones, unless insecure endpoints are allowed
+    fn validate_endpoints(&self) -> Result<(), Error> {
+        let endpoints = vec![
+            ("auth_uri", &self.auth_uri),
+            ("token_uri", &self.token_uri),
+            ("introspection_uri", &self.introspection_uri),
+            ("token_exchange_uri", &self.token_exchange_uri),
+            ("par_uri", &self.par_uri),
+            ("userinfo_uri", &self.userinfo_uri),
+        ];
+        for (name, uri) in endpoints {
+            let url = match uri {
+                None => continue,
+                Some(uri) => parse_uri(name, uri)?,
+            };
+            if url.scheme() != "https" && !self.allow_insecure_endpoints {
+                return Err(ConfigError::Validation(format!("{} {} does not use https", name, url)).into());
+            }
+        }
+        Ok(())
+    }
+
+    // Validates the configuration against the discovered provider metadata
+    pub fn validate_provider_metadata(&self, provider_metadata: &ProviderMetadata) -> Result<(), Error> {
+        provider_metadata.validate(&self.issuer, &self.scopes, !self.allow_insecure_endpoints)?;
+        self.request_object_key(Some(provider_metadata))?;
+        Ok(())
+    }
+
+    // Provider metadata and JWKS from the configuration, None if they must be discovered
+    pub fn static_discovery(&self) -> Result<Option<(ProviderMetadata, JsonWebKeySet)>, Error> {
+        match (&self.provider_metadata, self.jwks.clone()) {
+            (Some(provider_metadata), Some(jwks)) => {
+                self.validate_provider_metadata(provider_metadata)?;
+                Ok(Some((provider_metadata.clone(), jwks)))
+            }
+            // OAuth authorization servers may have no keys
+            (Some(provider_metadata), None) if !self.is_oidc() => {
+                self.validate_provider_metadata(provider_metadata)?;
+                Ok(Some((provider_metadata.clone(), JsonWebKeySet::new(vec![]))))
+            }
+            (None, None) => Ok(None),
+            // OAuth authorization servers with configured endpoints are not discovered, their keys can still be configured
+            (None, Some(_)) if !self.requires_discovery() => Ok(None),
+            _ => Err(ConfigError::Validation(
+                "provider_metadata requires jwks, and the other way around".to_string(),
+            ).into()),
+        }
+    }
+
+    fn form_post(&self) -> Result<bool, Error> {
+        match self.response_mode.as_str() {
+            "query" => Ok(false),
+            "form_post" => Ok(true),
+            mode => Err(ConfigError::Validation(format!("Unsupported response_mode={}", mode)).into()),
+        }
+    }
+
+    // Key signing request objects if enabled, the provider must support request objects signed with it
+    fn request_object_key(
+        &self,
+        provider_metadata: Option<&ProviderMetadata>,
+    ) -> Result<Option<ClientKey>, Error> {
+        if !self.request_objects {
+            return Ok(None);
+        }
+        let key = match &self.client_private_key {
+            None => {
+                return Err(ConfigError::Validation(
+                    "Request objects require a client_private_key".to_string(),
+                )
+                .into())
+            }
+            Some(key) => ClientKey::parse(key, self.client_key_id.as_deref())?,
+        };
+        if let Some(provider_metadata) = provider_metadata {
+            provider_metadata.validate_request_objects(key.alg())?;
+        }
+        Ok(Some(key))
+    }
+
+    // Userinfo endpoint if userinfo is enabled
+    fn userinfo_uri(&self, provider_metadata: Option<&ProviderMetadata>) -> Result<Option<Url>, Error> {
+        if !self.userinfo {
+            return Ok(None);
+        }
+        match (&self.userinfo_uri, provider_metadata.and_then(|metadata| metadata.userinfo_endpoint().as_ref())) {
+            (Some(userinfo_uri), _) => Ok(Some(userinfo_uri.parse()?)),
+            (None, Some(userinfo_uri)) => Ok(Some(userinfo_uri.clone())),
+            (None, None) => Err(ConfigError::Validation("Userinfo requires a userinfo_uri".to_string()).into()),
+        }
+    }
+
+    // PAR endpoint if pushed authorization requests are enabled or required by the provider
+    fn par_uri(&self, discovered: Option<&Url>, required: bool) -> Result<Option<Url>, Error> {
+        if !self.pushed_authorization_requests && !required {
+            return Ok(None);
+        }
+        match (self.par_uri.as_ref(), discovered) {
+            (Some(par_uri), _) => Ok(Some(par_uri.parse()?)),
+            (None, Some(par_uri)) => Ok(Some(par_uri.clone())),
+            (None, None) => Err(ConfigError::Validation(
+                "Pushed authorization requests require a par_uri".to_string(),
+            )
+            .into()),
+        }
+    }
+
+    // The configured token endpoint auth method, or the default for the methods the provider supports
+    fn client_authentication(&self, supported: &[String]) -> Result<ClientAuthentication, Error> {
+        let method = self.token_endpoint_auth_method.clone().unwrap_or_else(|| {
+            TokenEndpointAuthMethod::from_supported(supported, &self.client_secret)
+        });
+        Ok(ClientAuthentication::new(
+            &method,
+            &self.client_secret,
+            self.client_private_key.as_deref(),
+            self.client_key_id.as_deref(),
+        )?)
+    }
+
+    pub fn providers(&self) -> &Vec<RawProviderConfig> {
+        &self.providers
+    }
+
+    // Configurations of all providers by name, starting with the default provider
+    pub fn provider_configs(&self) -> Vec<(&str, &RawFilterConfig)> {
+        let mut configs = vec![(DEFAULT_PROVIDER, self)];
+        configs.extend(self.providers.iter().map(|provider| (provider.name.as_str(), &provider.config)));
+        configs
+    }
+
+    pub fn provider_picker(&self) -> bool {
+        self.provider_picker
+    }
+
+    pub fn error_pages(&self) -> &ErrorPages {
+        &self.error_pages
+    }
+
+    pub fn cookie_name(&self) -> &str {
+        &self.cookie_name
+    }
+
+    // The configured mode, without it OpenID Connect is used if the scopes contain openid
+    pub fn mode(&self) -> ProviderMode {
+        self.mode.clone().unwrap_or_else(|| match self.scopes.contains(&"openid".to_string()) {
+            true => ProviderMode::Oidc,
+            false => ProviderMode::Oauth,
+        })
     }
 
     pub fn is_oidc(&self) -> bool {
-        self.scopes.contains(&"openid".to_string())
+        self.mode() == ProviderMode::Oidc
+    }
+
+    // OpenID Connect providers are always discovered, OAuth providers if an endpoint is not configured
+    pub fn requires_discovery(&self) -> bool {
+        self.is_oidc() || self.auth_uri.is_none() || self.token_uri.is_none()
+    }
+
+    // Request for the provider metadata, RFC 8414 authorization server metadata in OAuth mode
+    pub fn discovery_request(&self) -> Result<crate::messages::HttpRequest, url::ParseError> {
+        let issuer = self.issuer.parse()?;
+        match self.mode() {
+            ProviderMode::Oidc => crate::discovery::discovery_request(&issuer),
+            ProviderMode::Oauth => crate::discovery::authorization_server_metadata_request(&issuer),
+        }
     }
 
     pub fn cluster(&self) -> &str {
@@ -371,6 +1363,28 @@ fn default_redirect_uri() -> String {
     "{proto}://{authority}{path}".to_owned()
 }
 
+// Parses a configured URI, naming the option in the error
+fn parse_uri(name: &str, uri: &str) -> Result<Url, ConfigError> {
+    uri.parse()
+        .map_err(|err| ConfigError::Parse(format!("Invalid {}={}, error={}", name, uri, err)))
+}
+
+fn parse_optional_uri(name: &str, uri: &Option<String>) -> Result<Option<Url>, ConfigError> {
+    uri.as_deref().map(|uri| parse_uri(name, uri)).transpose()
+}
+
+fn default_redirect_loop_threshold() -> u32 {
+    0
+}
+
+fn default_redirect_loop_window() -> u64 {
+    60
+}
+
+fn default_session_info_claims() -> Vec<String> {
+    vec!["name".to_string(), "email".to_string(), "preferred_username".to_string()]
+}
+
 fn default_oidc_cookie_name() -> String {
     "oidcSession".to_owned()
 }
@@ -391,9 +1405,17 @@ fn default_cookie_expire() -> u64 {
     3600
 }
 
+fn default_response_mode() -> String {
+    "query".to_owned()
+}
+
+fn default_introspection_cache_ttl() -> u64 {
+    60
+}
+
 #[cfg(test)]
 mod tests {
-    use crate::config::{FilterConfig, RawFilterConfig};
+    use crate::config::{FilterConfig, ProviderMode, RawFilterConfig, DEFAULT_PROVIDER};
     use crate::discovery::{JsonWebKeySet, ProviderMetadata};
     use jwt_simple::prelude::{Audiences, VerificationOptions};
     use std::collections::HashSet;
@@ -520,6 +1542,18 @@ mod tests {
         let oauth_config: RawFilterConfig = serde_json::from_str(text).unwrap();
         assert!(!oauth_config.is_oidc());
 
+        // Invalid endpoints are configuration errors
+        let invalid_uri = |name: &str| {
+            let text = text.replace("\"cookie_expire\": 120", &format!("\"{}\": \"not a url\"", name));
+            serde_json::from_str::<RawFilterConfig>(&text).unwrap()
+        };
+        assert!(invalid_uri("introspection_uri").validate().is_err());
+        assert!(invalid_uri("introspection_uri").oauth_config().is_err());
+        assert!(invalid_uri("token_exchange_uri").validate().is_err());
+        assert!(invalid_uri("token_exchange_uri").oauth_config().is_err());
+        assert!(invalid_uri("error_redirect_uri").validate().is_err());
+        assert!(invalid_uri("error_redirect_uri").oauth_config().is_err());
+
         let text = "
         {
         \"auth_cluster\": \"cluster_mock_auth\",
@@ -533,4 +1567,218 @@ mod tests {
         let oauth_config: RawFilterConfig = serde_json::from_str(text).unwrap();
         assert!(oauth_config.is_oidc());
     }
+
+    #[test]
+    fn client_authentication() {
+        let text = "
+        {
+        \"redirect_uri\": \"http://localhost:8090/callback\",
+        \"auth_cluster\": \"cluster_mock_auth\",
+        \"issuer\": \"http://mock-oauth2-server:8080/customiss\",
+        \"token_uri\": \"http://mock-oauth2-server:8888/customiss/token\",
+        \"auth_uri\": \"http://localhost:8888/customiss/authorize\",
+        \"client_id\": \"mycoolclientid\",
+        \"client_secret\": \"mycoolclientsecret\",
+        \"scopes\": [\"email\"],
+        \"token_endpoint_auth_method\": \"client_secret_jwt\"
+        }";
+
+        let raw_config: RawFilterConfig = serde_json::from_str(text).unwrap();
+        let config = raw_config.oauth_config().unwrap();
+        let request = config.token_request("somecode".to_string(), None).unwrap();
+        let body = String::from_utf8(request.body).unwrap();
+        assert!(body.contains("client_assertion_type=urn%3Aietf%3Aparams%3Aoauth%3Aclient-assertion-type%3Ajwt-bearer"));
+        assert!(body.contains("client_assertion="));
+        assert!(body.contains("client_id=mycoolclientid"));
+        assert!(!body.contains("client_secret="));
+
+        let raw_config: RawFilterConfig =
+            serde_json::from_str(&text.replace("client_secret_jwt", "client_secret_basic")).unwrap();
+        let request = raw_config.oauth_config().unwrap().token_request("somecode".to_string(), None).unwrap();
+        assert!(request.headers.get("Authorization").unwrap().to_str().unwrap().starts_with("Basic "));
+        assert!(!String::from_utf8(request.body).unwrap().contains("client_secret="));
+
+        // Public clients only send the client ID
+        let raw_config: RawFilterConfig = serde_json::from_str(
+            &text.replace("\"token_endpoint_auth_method\": \"client_secret_jwt\"", "\"token_endpoint_auth_method\": \"none\""),
+        ).unwrap();
+        let request = raw_config.oauth_config().unwrap().token_request("somecode".to_string(), None).unwrap();
+        let body = String::from_utf8(request.body).unwrap();
+        assert!(request.headers.get("Authorization").is_none());
+        assert!(body.contains("client_id=mycoolclientid"));
+        assert!(!body.contains("client_secret="));
+
+        // private_key_jwt requires a private key
+        let raw_config: RawFilterConfig =
+            serde_json::from_str(&text.replace("client_secret_jwt", "private_key_jwt")).unwrap();
+        assert!(raw_config.oauth_config().is_err());
+    }
+
+    #[test]
+    fn debug_without_secrets() {
+        let text = "
+        {
+        \"redirect_uri\": \"http://localhost:8090/callback\",
+        \"auth_cluster\": \"cluster_mock_auth\",
+        \"issuer\": \"http://mock-oauth2-server:8080/customiss\",
+        \"token_uri\": \"http://mock-oauth2-server:8888/customiss/token\",
+        \"auth_uri\": \"http://localhost:8888/customiss/authorize\",
+        \"client_id\": \"mycoolclientid\",
+        \"client_secret\": \"mycoolclientsecret\",
+        \"token_endpoint_auth_method\": \"private_key_jwt\",
+        \"client_private_key\": \"{\\\"kty\\\": \\\"EC\\\", \\\"crv\\\": \\\"P-256\\\", \\\"d\\\": \\\"N4mh_4QhxXV6WDpdmUp3fAbOsKaV9IHLIQlAa_dpaHM\\\", \\\"x\\\": \\\"Ix9aR3DjcNQhS1t-Jbe4Hy0khr_laJyW3nnOnl8WT0c\\\", \\\"y\\\": \\\"jW2hwsbPZexSaxVawc23TgRf4aPa6M_R7g15wNnw7lw\\\"}\",
+        \"client_key_id\": \"key-1\"
+        }";
+
+        let raw_config: RawFilterConfig = serde_json::from_str(text).unwrap();
+        let debug = format!("{:?}", raw_config);
+        assert!(debug.contains("client_private_key: Some(\"<redacted>\")"));
+        assert!(debug.contains("client_key_id: Some(\"key-1\")"));
+        assert!(!debug.contains("N4mh_4QhxXV6WDpdmUp3fAbOsKaV9IHLIQlAa_dpaHM"));
+        assert!(!debug.contains("mycoolclientsecret"));
+
+        // The parsed key is left out as well
+        let debug = format!("{:?}", raw_config.oauth_config().unwrap());
+        assert!(debug.contains("PrivateKeyJwt(ES256 { key_id: Some(\"key-1\"), .. })"));
+        assert!(!debug.contains("N4mh_4QhxXV6WDpdmUp3fAbOsKaV9IHLIQlAa_dpaHM"));
+    }
+
+    #[test]
+    fn providers() {
+        let provider = "
+            \"name\": \"acme\",
+            \"hosts\": [\"acme.example.com\"],
+            \"auth_cluster\": \"cluster_acme\",
+            \"issuer\": \"https://acme.example.com\",
+            \"token_uri\": \"https://acme.example.com/token\",
+            \"auth_uri\": \"https://acme.example.com/authorize\",
+            \"client_id\": \"acmeclient\",
+            \"scopes\": [\"email\"]";
+        let text = format!("
+        {{
+        \"redirect_uri\": \"http://localhost:8090/callback\",
+        \"auth_cluster\": \"cluster_mock_auth\",
+        \"issuer\": \"http://mock-oauth2-server:8080/customiss\",
+        \"token_uri\": \"http://mock-oauth2-server:8888/customiss/token\",
+        \"auth_uri\": \"http://localhost:8888/customiss/authorize\",
+        \"client_id\": \"mycoolclientid\",
+        \"scopes\": [\"email\"],
+        \"allow_insecure_endpoints\": true,
+        \"providers\": [{{{}}}]
+        }}", provider);
+
+        let raw_config: RawFilterConfig = serde_json::from_str(&text).unwrap();
+        assert!(raw_config.validate().is_ok());
+        // Configured endpoints must use https unless insecure endpoints are allowed
+        let raw_config: RawFilterConfig =
+            serde_json::from_str(&text.replace("\"allow_insecure_endpoints\": true,", "")).unwrap();
+        assert!(raw_config.validate().is_err());
+        let raw_config: RawFilterConfig = serde_json::from_str(&text).unwrap();
+        let configs = raw_config.provider_configs();
+        assert_eq!(configs.len(), 2);
+        assert_eq!(configs[0].0, DEFAULT_PROVIDER);
+        assert_eq!(configs[1].0, "acme");
+        assert_eq!(configs[1].1.cluster(), "cluster_acme");
+        assert_eq!(raw_config.providers()[0].hosts, vec!["acme.example.com".to_string()]);
+
+        // Provider names must be unique
+        let duplicate = text.replace(&format!("{{{}}}", provider), &format!("{{{}}}, {{{}}}", provider, provider));
+        let raw_config: RawFilterConfig = serde_json::from_str(&duplicate).unwrap();
+        assert!(raw_config.validate().is_err());
+    }
+
+    #[test]
+    fn static_discovery() {
+        let text = "
+        {
+        \"redirect_uri\": \"http://localhost:8090/callback\",
+        \"auth_cluster\": \"cluster_mock_auth\",
+        \"issuer\": \"https://issuer\",
+        \"client_id\": \"mycoolclientid\",
+        \"provider_metadata\": {
+            \"issuer\": \"https://issuer\",
+            \"authorization_endpoint\": \"https://issuer/authorize\",
+            \"token_endpoint\": \"https://issuer/token\",
+            \"jwks_uri\": \"https://issuer/jwks\",
+            \"response_types_supported\": [\"code\"],
+            \"subject_types_supported\": [\"public\"],
+            \"id_token_signing_alg_values_supported\": [\"RS256\"]
+        },
+        \"jwks\": {\"keys\": []}
+        }";
+
+        let raw_config: RawFilterConfig = serde_json::from_str(text).unwrap();
+        assert!(raw_config.validate().is_ok());
+        let (provider_metadata, jwks) = raw_config.static_discovery().unwrap().unwrap();
+        assert!(raw_config.oidc_config(&provider_metadata, &jwks).is_ok());
+
+        // The iss parameter is compared to the issuer of the metadata, which may differ by a trailing slash
+        let trailing_slash = text
+            .replace("\"issuer\": \"https://issuer\",\n            ", "\"issuer\": \"https://issuer/\",\n            ")
+            .replace("\"jwks_uri\"", "\"authorization_response_iss_parameter_supported\": true,\n            \"jwks_uri\"");
+        let raw_config: RawFilterConfig = serde_json::from_str(&trailing_slash).unwrap();
+        assert!(raw_config.validate().is_ok());
+        let (provider_metadata, jwks) = raw_config.static_discovery().unwrap().unwrap();
+        let config = raw_config.oidc_config(&provider_metadata, &jwks).unwrap();
+        assert!(config.validate_iss_parameter(Some("https://issuer/")).is_ok());
+        assert!(config.validate_iss_parameter(Some("https://issuer")).is_err());
+        assert!(config.validate_iss_parameter(None).is_err());
+
+        // Without static configuration the provider is discovered
+        let raw_config: RawFilterConfig = serde_json::from_str(
+            &text.replace("\"jwks\"", "\"unused_jwks\"").replace("\"provider_metadata\"", "\"unused\""),
+        ).unwrap();
+        assert!(raw_config.static_discovery().unwrap().is_none());
+
+        // The metadata requires a JWKS
+        let raw_config: RawFilterConfig =
+            serde_json::from_str(&text.replace("\"jwks\"", "\"unused\"")).unwrap();
+        assert!(raw_config.validate().is_err());
+    }
+
+    #[test]
+    fn mode() {
+        let text = "
+        {
+        \"redirect_uri\": \"http://localhost:8090/callback\",
+        \"auth_cluster\": \"cluster_mock_auth\",
+        \"issuer\": \"https://issuer\",
+        \"client_id\": \"mycoolclientid\",
+        \"client_secret\": \"mycoolclientsecret\",
+        \"scopes\": [\"openid\", \"email\"],
+        \"mode\": \"oauth\"
+        }";
+
+        let raw_config: RawFilterConfig = serde_json::from_str(text).unwrap();
+        assert_eq!(raw_config.mode(), ProviderMode::Oauth);
+        assert!(raw_config.requires_discovery());
+        assert!(raw_config.discovery_request().unwrap().url().contains("/.well-known/oauth-authorization-server"));
+        // The endpoints must be configured without discovery
+        assert!(raw_config.oauth_config().is_err());
+
+        let provider_metadata = ProviderMetadata::from_bytes("{
+            \"issuer\": \"https://issuer\",
+            \"authorization_endpoint\": \"https://issuer/authorize\",
+            \"token_endpoint\": \"https://issuer/token\",
+            \"response_types_supported\": [\"code\"]
+        }".to_string().into_bytes()).unwrap();
+        let config = raw_config.discovered_oauth_config(&provider_metadata, &JsonWebKeySet::new(vec![])).unwrap();
+        let request = config.token_request("somecode".to_string(), None).unwrap();
+        assert_eq!(request.url.as_str(), "https://issuer/token");
+
+        // With configured endpoints the keys can be configured without provider metadata
+        let static_jwks = text.replace(
+            "\"mode\": \"oauth\"",
+            "\"mode\": \"oauth\", \"auth_uri\": \"https://issuer/authorize\", \"token_uri\": \"https://issuer/token\", \"jwks\": {\"keys\": []}",
+        );
+        let raw_config: RawFilterConfig = serde_json::from_str(&static_jwks).unwrap();
+        assert!(raw_config.validate().is_ok());
+        assert!(!raw_config.requires_discovery());
+        assert!(raw_config.oauth_config().is_ok());
+
+        // Without mode the scopes decide
+        let raw_config: RawFilterConfig =
+            serde_json::from_str(&text.replace(",\n        \"mode\": \"oauth\"", "")).unwrap();
+        assert_eq!(raw_config.mode(), ProviderMode::Oidc);
+    }
 }
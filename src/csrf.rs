@@ -0,0 +1,115 @@
+use cookie::{CookieBuilder, SameSite};
+use url::Url;
+
+use crate::error_page::ErrorCode;
+use crate::messages::DownStreamResponse;
+use crate::oauth_client_types::Request;
+use crate::util;
+
+// Double-submit CSRF token, readable by scripts so front-ends can send it in the header
+pub const CSRF_COOKIE_NAME: &str = "oauthCsrf";
+pub const CSRF_HEADER: &str = "x-csrf-token";
+pub const CSRF_FORM_FIELD: &str = "csrf_token";
+
+// Validates a request to a state-changing filter endpoint. It must be a POST request, and
+// come from the same origin by its Origin or Referer header, or carry the double-submit CSRF token.
+pub fn validate_request(request: &Request) -> Result<(), DownStreamResponse> {
+    if !request.header(":method").is_some_and(|method| method.eq_ignore_ascii_case("POST")) {
+        return Err(DownStreamResponse::error(
+            vec![("Allow".to_string(), "POST".to_string())],
+            ErrorCode::MethodNotAllowed,
+            "Method not allowed".to_string(),
+        ));
+    }
+    let same_origin = request.url().origin().ascii_serialization();
+    match request_origin(request) {
+        Some(origin) if origin == same_origin => Ok(()),
+        Some(origin) => Err(forbidden(&format!("cross-origin request from {}", origin))),
+        None => {
+            let submitted = request.header(CSRF_HEADER)
+                .map(|token| token.to_string())
+                .or_else(|| request.param(CSRF_FORM_FIELD));
+            match (request.cookie(CSRF_COOKIE_NAME), submitted) {
+                (Some(cookie), Some(submitted)) if !cookie.is_empty() && cookie == submitted => Ok(()),
+                _ => Err(forbidden("missing or invalid CSRF token")),
+            }
+        }
+    }
+}
+
+// Origin the request was sent from, by its Origin header or else its Referer header
+pub fn request_origin(request: &Request) -> Option<String> {
+    request.header("origin")
+        .filter(|origin| *origin != "null")
+        .map(|origin| origin.to_string())
+        .or_else(|| {
+            request.header("referer")
+                .and_then(|referer| referer.parse::<Url>().ok())
+                .map(|referer| referer.origin().ascii_serialization())
+        })
+}
+
+// Page asking the end-user to confirm the action with a form posted to the request path
+pub fn confirmation_page(request: &Request, action: &str) -> DownStreamResponse {
+    let token = util::new_random_verifier(32).secret().to_string();
+    let cookie = CookieBuilder::new(CSRF_COOKIE_NAME, token.clone())
+        .secure(true)
+        .same_site(SameSite::Strict)
+        .path("/")
+        .finish();
+    let body = format!(
+        "<!DOCTYPE html><html><head><title>{action}</title></head><body><form method=\"post\" action=\"{path}\">\
+         <input type=\"hidden\" name=\"{field}\" value=\"{token}\"><button type=\"submit\">{action}</button></form></body></html>",
+        action = util::escape_html(action),
+        path = util::escape_html(request.url().path()),
+        field = CSRF_FORM_FIELD,
+        token = token,
+    );
+    DownStreamResponse::html(vec![("Set-Cookie".to_string(), cookie.to_string())], 200, body)
+}
+
+fn forbidden(reason: &str) -> DownStreamResponse {
+    log::warn!("Rejected request to filter endpoint, reason={}", reason);
+    DownStreamResponse::error(vec![], ErrorCode::AccessDenied, format!("Forbidden, {}", reason))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::csrf;
+    use crate::oauth_client_types::Request;
+
+    fn test_request(method: &str, headers: Vec<(&str, &str)>) -> Request {
+        let mut headers: Vec<(String, String)> = headers.iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect();
+        headers.push((":method".to_string(), method.to_string()));
+        headers.push(("x-forwarded-proto".to_string(), "https".to_string()));
+        headers.push((":authority".to_string(), "app.example.com".to_string()));
+        headers.push((":path".to_string(), "/sign_out".to_string()));
+        Request::new(headers).unwrap()
+    }
+
+    #[test]
+    fn validate_request() {
+        assert_eq!(csrf::validate_request(&test_request("GET", vec![("origin", "https://app.example.com")])).unwrap_err().code(), 405);
+        assert!(csrf::validate_request(&test_request("POST", vec![("origin", "https://app.example.com")])).is_ok());
+        assert!(csrf::validate_request(&test_request("POST", vec![("referer", "https://app.example.com/home")])).is_ok());
+        assert_eq!(csrf::validate_request(&test_request("POST", vec![("origin", "https://evil.example.com")])).unwrap_err().code(), 403);
+
+        // Without Origin and Referer the double-submit token is required
+        assert!(csrf::validate_request(&test_request("POST", vec![])).is_err());
+        assert!(csrf::validate_request(&test_request("POST", vec![("cookie", "oauthCsrf=abc"), ("x-csrf-token", "abc")])).is_ok());
+        assert!(csrf::validate_request(&test_request("POST", vec![("cookie", "oauthCsrf=abc"), ("x-csrf-token", "xyz")])).is_err());
+        let request = test_request("POST", vec![("cookie", "other=1; oauthCsrf=abc"), ("content-type", "application/x-www-form-urlencoded")])
+            .with_form(b"csrf_token=abc");
+        assert!(csrf::validate_request(&request).is_ok());
+    }
+
+    #[test]
+    fn confirmation_page() {
+        let page = csrf::confirmation_page(&test_request("GET", vec![]), "Sign out");
+        assert_eq!(page.content_type(), Some("text/html; charset=utf-8"));
+        assert!(page.headers().iter().any(|(name, value)| *name == "Set-Cookie" && value.starts_with("oauthCsrf=") && !value.contains("HttpOnly")));
+        assert!(page.body().contains("<form method=\"post\" action=\"/sign_out\">"));
+    }
+}
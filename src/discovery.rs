@@ -11,6 +11,7 @@ pub const MIME_TYPE_JSON: &str = "application/json";
 pub const MIME_TYPE_JWKS: &str = "application/jwk-set+json";
 pub const MIME_TYPE_JWT: &str = "application/jwt";
 const CONFIG_URL_SUFFIX: &str = ".well-known/openid-configuration";
+const AUTHORIZATION_SERVER_METADATA_PATH: &str = "/.well-known/oauth-authorization-server";
 const OPENID_SCOPE: &str = "openid";
 
 #[derive(Debug, Clone)]
@@ -37,17 +38,36 @@ impl error::Error for ConfigError {}
 
 #[derive(Clone, Debug, serde::Deserialize, serde::Serialize)]
 pub struct ProviderMetadata {
-    issuer: Url,
+    // Kept as published, parsing would add a trailing slash to issuers without a path
+    issuer: String,
     authorization_endpoint: Url,
     #[serde(skip_serializing_if = "Option::is_none")]
     token_endpoint: Option<Url>,
     #[serde(skip_serializing_if = "Option::is_none")]
     userinfo_endpoint: Option<Url>,
-    jwks_uri: Url,
+    // Required by OpenID Connect, optional in OAuth authorization server metadata
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    jwks_uri: Option<Url>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    introspection_endpoint: Option<Url>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pushed_authorization_request_endpoint: Option<Url>,
+    #[serde(default)]
+    require_pushed_authorization_requests: bool,
+    #[serde(default)]
+    request_parameter_supported: bool,
+    #[serde(default)]
+    authorization_response_iss_parameter_supported: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    request_object_signing_alg_values_supported: Option<Vec<String>>,
     #[serde(skip_serializing_if = "Option::is_none")]
     scopes_supported: Option<Vec<String>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    token_endpoint_auth_methods_supported: Option<Vec<String>>,
     response_types_supported: Vec<String>,
+    #[serde(default)]
     subject_types_supported: Vec<String>,
+    #[serde(default)]
     id_token_signing_alg_values_supported: Vec<String>,
 }
 
@@ -65,12 +85,19 @@ impl ProviderMetadata {
         id_token_signing_alg_values_supported: Vec<String>,
     ) -> ProviderMetadata {
         ProviderMetadata {
-            issuer,
+            issuer: issuer.to_string(),
             authorization_endpoint,
             token_endpoint,
             userinfo_endpoint,
-            jwks_uri,
+            jwks_uri: Some(jwks_uri),
+            introspection_endpoint: None,
+            pushed_authorization_request_endpoint: None,
+            require_pushed_authorization_requests: false,
+            request_parameter_supported: false,
+            authorization_response_iss_parameter_supported: false,
+            request_object_signing_alg_values_supported: None,
             scopes_supported,
+            token_endpoint_auth_methods_supported: None,
             response_types_supported,
             subject_types_supported,
             id_token_signing_alg_values_supported
@@ -84,7 +111,7 @@ impl ProviderMetadata {
             })
     }
 
-    pub fn issuer(&self) -> &Url {
+    pub fn issuer(&self) -> &str {
         &self.issuer
     }
 
@@ -96,7 +123,94 @@ impl ProviderMetadata {
         &self.token_endpoint
     }
 
-    pub fn jwks_url(&self) -> Url {
+    pub fn userinfo_endpoint(&self) -> &Option<Url> {
+        &self.userinfo_endpoint
+    }
+
+    pub fn introspection_endpoint(&self) -> &Option<Url> {
+        &self.introspection_endpoint
+    }
+
+    pub fn pushed_authorization_request_endpoint(&self) -> &Option<Url> {
+        &self.pushed_authorization_request_endpoint
+    }
+
+    pub fn require_pushed_authorization_requests(&self) -> bool {
+        self.require_pushed_authorization_requests
+    }
+
+    pub fn authorization_response_iss_parameter_supported(&self) -> bool {
+        self.authorization_response_iss_parameter_supported
+    }
+
+    // Returns an error if the provider does not accept request objects signed with the alg
+    pub fn validate_request_objects(&self, alg: &str) -> Result<(), ConfigError> {
+        if !self.request_parameter_supported {
+            return Err(ConfigError::Validation(
+                "Provider does not support the request parameter".to_string(),
+            ));
+        }
+        match &self.request_object_signing_alg_values_supported {
+            Some(algs) if !algs.iter().any(|supported| supported == alg) => Err(ConfigError::Validation(
+                format!("Provider does not support request objects signed with {}", alg),
+            )),
+            _ => Ok(()),
+        }
+    }
+
+    // Validates the metadata against the configuration, the error lists every mismatch.
+    // Issuers are compared without trailing slash, OpenID Connect Discovery 1.0 section 4.3.
+    pub fn validate(&self, issuer: &str, scopes: &[String], require_https: bool) -> Result<(), ConfigError> {
+        let mut mismatches = vec![];
+        if !issuer_matches(&self.issuer, issuer) {
+            mismatches.push(format!("issuer {} does not match the configured issuer {}", self.issuer, issuer));
+        }
+        let issuer_url = Url::parse(&self.issuer).ok();
+        if issuer_url.is_none() {
+            mismatches.push(format!("issuer {} is not a URL", self.issuer));
+        }
+        if require_https {
+            let endpoints = vec![
+                ("issuer", issuer_url.as_ref()),
+                ("authorization_endpoint", Some(&self.authorization_endpoint)),
+                ("token_endpoint", self.token_endpoint.as_ref()),
+                ("userinfo_endpoint", self.userinfo_endpoint.as_ref()),
+                ("jwks_uri", self.jwks_uri.as_ref()),
+                ("introspection_endpoint", self.introspection_endpoint.as_ref()),
+                ("pushed_authorization_request_endpoint", self.pushed_authorization_request_endpoint.as_ref()),
+            ];
+            for (name, url) in endpoints {
+                match url {
+                    Some(url) if url.scheme() != "https" => mismatches.push(format!("{} {} does not use https", name, url)),
+                    _ => {}
+                }
+            }
+        }
+        if !self.response_types_supported.iter().any(|response_type| response_type == "code") {
+            mismatches.push("response type code is not supported".to_string());
+        }
+        if let Some(scopes_supported) = &self.scopes_supported {
+            for scope in scopes.iter().filter(|scope| !scopes_supported.contains(scope)) {
+                mismatches.push(format!("scope {} is not supported", scope));
+            }
+        }
+        match mismatches.is_empty() {
+            true => Ok(()),
+            false => Err(ConfigError::Validation(format!(
+                "Provider metadata does not match the configuration: {}",
+                mismatches.join(", ")
+            ))),
+        }
+    }
+
+    // Defaults to client_secret_basic if omitted, OpenID Connect Discovery 1.0 section 3
+    pub fn token_endpoint_auth_methods_supported(&self) -> Vec<String> {
+        self.token_endpoint_auth_methods_supported
+            .clone()
+            .unwrap_or_else(|| vec!["client_secret_basic".to_string()])
+    }
+
+    pub fn jwks_url(&self) -> Option<Url> {
         self.jwks_uri.clone()
     }
 }
@@ -112,6 +226,21 @@ pub fn discovery_request(issuer_url: &Url) -> Result<HttpRequest, ParseError> {
     ))
 }
 
+// OAuth 2.0 Authorization Server Metadata request, the well-known path is inserted before the path of the issuer (RFC 8414 section 3)
+pub fn authorization_server_metadata_request(issuer_url: &Url) -> Result<HttpRequest, ParseError> {
+    let mut metadata_url = issuer_url.clone();
+    let issuer_path = issuer_url.path().trim_end_matches('/');
+    metadata_url.set_path(&format!("{}{}", AUTHORIZATION_SERVER_METADATA_PATH, issuer_path));
+    metadata_url.set_query(None);
+
+    Ok(HttpRequest::new(
+        metadata_url,
+        Method::GET,
+        vec![(ACCEPT.to_string(), MIME_TYPE_JSON.to_string())],
+        Vec::new(),
+    ))
+}
+
 pub fn jwks_request(jwks_url: Url) -> HttpRequest {
     HttpRequest::new(
         jwks_url,
@@ -136,7 +265,7 @@ pub fn discovery_response(
         serde_json::from_slice::<ProviderMetadata>(discovery_response.body.as_slice())
             .map_err(|err| ConfigError::Parse(err.to_string()))?;
 
-    if provider_metadata.issuer != issuer_url {
+    if !issuer_matches(&provider_metadata.issuer, issuer_url.as_str()) {
         Err(ConfigError::Validation(format!(
             "Unexpected issuer URI {}, expected {}",
             provider_metadata.issuer, issuer_url
@@ -146,6 +275,10 @@ pub fn discovery_response(
     }
 }
 
+// Compares issuers ignoring a trailing slash, as parsing adds one to URLs without a path
+fn issuer_matches(issuer: &str, expected: &str) -> bool {
+    issuer.trim_end_matches('/') == expected.trim_end_matches('/')
+}
 
 #[derive(Debug, Clone, Deserialize, PartialEq, Serialize)]
 pub struct JsonWebKeySet
@@ -213,6 +346,23 @@ mod tests {
         assert!(result.unwrap().url().to_string().contains("customissuer"))
     }
 
+    #[test]
+    fn authorization_server_metadata_request() {
+        let request = discovery::authorization_server_metadata_request(&"https://issuer/tenant1/".parse().unwrap()).unwrap();
+        assert_eq!(request.url(), "https://issuer/.well-known/oauth-authorization-server/tenant1");
+        let request = discovery::authorization_server_metadata_request(&"https://issuer".parse().unwrap()).unwrap();
+        assert_eq!(request.url(), "https://issuer/.well-known/oauth-authorization-server");
+
+        // OAuth metadata lacks the fields only OpenID Connect requires
+        let metadata = discovery::ProviderMetadata::from_bytes("{
+            \"issuer\": \"https://issuer\",
+            \"authorization_endpoint\": \"https://issuer/authorize\",
+            \"token_endpoint\": \"https://issuer/token\",
+            \"response_types_supported\": [\"code\"]
+        }".to_string().into_bytes()).unwrap();
+        assert!(metadata.jwks_url().is_none());
+    }
+
     #[test]
     fn discovery_response() {
         let body = "{
@@ -240,6 +390,57 @@ mod tests {
         assert!(result.is_ok())
     }
 
+    #[test]
+    fn validate_request_objects() {
+        let metadata = "{
+            \"issuer\": \"https://issuer\",
+            \"authorization_endpoint\": \"https://issuer/auth\",
+            \"jwks_uri\": \"https://issuer/jwks\",
+            \"response_types_supported\": [\"code\"],
+            \"subject_types_supported\": [\"public\"],
+            \"id_token_signing_alg_values_supported\": [\"RS256\"]
+        }";
+        let provider_metadata = discovery::ProviderMetadata::from_bytes(metadata.as_bytes().to_vec()).unwrap();
+        assert!(provider_metadata.validate_request_objects("RS256").is_err());
+
+        let metadata = metadata.replace(
+            "\"jwks_uri\"",
+            "\"request_parameter_supported\": true, \"request_object_signing_alg_values_supported\": [\"ES256\"], \"jwks_uri\"",
+        );
+        let provider_metadata = discovery::ProviderMetadata::from_bytes(metadata.into_bytes()).unwrap();
+        assert!(provider_metadata.validate_request_objects("ES256").is_ok());
+        assert!(provider_metadata.validate_request_objects("RS256").is_err());
+    }
+
+    #[test]
+    fn validate() {
+        let metadata = "{
+            \"issuer\": \"https://issuer/\",
+            \"authorization_endpoint\": \"https://issuer/auth\",
+            \"token_endpoint\": \"http://issuer/token\",
+            \"jwks_uri\": \"https://issuer/jwks\",
+            \"scopes_supported\": [\"openid\", \"email\"],
+            \"response_types_supported\": [\"code\", \"id_token\"]
+        }";
+        let provider_metadata = discovery::ProviderMetadata::from_bytes(metadata.as_bytes().to_vec()).unwrap();
+        let scopes = vec!["openid".to_string(), "email".to_string()];
+        assert!(provider_metadata.validate("https://issuer", &scopes, false).is_ok());
+
+        // Every mismatch is reported
+        let scopes = vec!["openid".to_string(), "groups".to_string()];
+        let error = provider_metadata.validate("https://other", &scopes, true).unwrap_err().to_string();
+        assert!(error.contains("issuer https://issuer/ does not match"));
+        assert!(error.contains("token_endpoint http://issuer/token does not use https"));
+        assert!(error.contains("scope groups is not supported"));
+        assert!(!error.contains("jwks_uri"));
+
+        let provider_metadata = discovery::ProviderMetadata::from_bytes(
+            metadata.replace("\"code\", ", "").into_bytes(),
+        ).unwrap();
+        let error = provider_metadata.validate("https://issuer/", &[], false).unwrap_err().to_string();
+        assert!(error.contains("response type code is not supported"));
+    }
+
     #[test]
     fn jwks_response() {
         let body = "{
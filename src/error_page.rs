@@ -0,0 +1,242 @@
+use serde::{Deserialize, Serialize};
+
+use crate::messages::DownStreamResponse;
+use crate::oauth_client::START_PATH;
+use crate::util;
+
+// Stable codes of the errors the filter responds with, the `error` of JSON error responses
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorCode {
+    InvalidRequest,
+    InvalidState,
+    NoSession,
+    InvalidSession,
+    AuthenticationRequired,
+    TokensExpired,
+    AuthorizationFailed,
+    RefreshFailed,
+    AccessDenied,
+    NotFound,
+    MethodNotAllowed,
+    InternalError,
+    ConfigurationError,
+    ProviderError,
+    InvalidToken,
+    NotReady,
+    RefreshInProgress,
+}
+
+impl ErrorCode {
+    pub fn status(&self) -> u64 {
+        match self {
+            ErrorCode::InvalidRequest | ErrorCode::InvalidState | ErrorCode::NoSession | ErrorCode::InvalidSession => 400,
+            ErrorCode::AuthenticationRequired | ErrorCode::TokensExpired | ErrorCode::AuthorizationFailed | ErrorCode::RefreshFailed => 401,
+            ErrorCode::AccessDenied => 403,
+            ErrorCode::NotFound => 404,
+            ErrorCode::MethodNotAllowed => 405,
+            ErrorCode::InternalError | ErrorCode::ConfigurationError => 500,
+            ErrorCode::ProviderError | ErrorCode::InvalidToken => 502,
+            ErrorCode::NotReady | ErrorCode::RefreshInProgress => 503,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ErrorCode::InvalidRequest => "invalid_request",
+            ErrorCode::InvalidState => "invalid_state",
+            ErrorCode::NoSession => "no_session",
+            ErrorCode::InvalidSession => "invalid_session",
+            ErrorCode::AuthenticationRequired => "authentication_required",
+            ErrorCode::TokensExpired => "tokens_expired",
+            ErrorCode::AuthorizationFailed => "authorization_failed",
+            ErrorCode::RefreshFailed => "refresh_failed",
+            ErrorCode::AccessDenied => "access_denied",
+            ErrorCode::NotFound => "not_found",
+            ErrorCode::MethodNotAllowed => "method_not_allowed",
+            ErrorCode::InternalError => "internal_error",
+            ErrorCode::ConfigurationError => "configuration_error",
+            ErrorCode::ProviderError => "provider_error",
+            ErrorCode::InvalidToken => "invalid_token",
+            ErrorCode::NotReady => "not_ready",
+            ErrorCode::RefreshInProgress => "refresh_in_progress",
+        }
+    }
+
+    // Page shown to browsers for the error, authentication and authorization failures get the access denied page
+    pub fn page(&self) -> StatusPage {
+        match self.status() {
+            401 | 403 => StatusPage::AccessDenied,
+            503 => StatusPage::NotReady,
+            _ => StatusPage::LoginError,
+        }
+    }
+}
+
+// Pages shown to end-users, each can be replaced with a template
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum StatusPage {
+    LoginError,
+    AccessDenied,
+    SignedOut,
+    NotReady,
+}
+
+// Templates of the status pages. {status}, {error}, {message}, {request_id} and {login_url} are replaced
+// with the escaped values.
+#[derive(Deserialize, Clone, Debug, Default)]
+pub struct ErrorPages {
+    login_error: Option<String>,
+    access_denied: Option<String>,
+    signed_out: Option<String>,
+    not_ready: Option<String>,
+}
+
+const DEFAULT_LOGIN_ERROR: &str = "<!DOCTYPE html><html><head><title>Sign in failed</title></head><body>\
+    <h1>Sign in failed</h1><p>{message}</p><p><a href=\"{login_url}\">Sign in again</a></p>\
+    <p><small>Error {status} {error}, request ID {request_id}</small></p></body></html>";
+const DEFAULT_ACCESS_DENIED: &str = "<!DOCTYPE html><html><head><title>Access denied</title></head><body>\
+    <h1>Access denied</h1><p>{message}</p><p><a href=\"{login_url}\">Sign in</a></p>\
+    <p><small>Error {status} {error}, request ID {request_id}</small></p></body></html>";
+const DEFAULT_SIGNED_OUT: &str = "<!DOCTYPE html><html><head><title>Signed out</title></head><body>\
+    <h1>Signed out</h1><p><a href=\"{login_url}\">Sign in again</a></p></body></html>";
+const DEFAULT_NOT_READY: &str = "<!DOCTYPE html><html><head><title>Not ready</title></head><body>\
+    <h1>Not ready</h1><p>Signing in is not available yet, please try again in a moment.</p>\
+    <p><small>Request ID {request_id}</small></p></body></html>";
+
+impl ErrorPages {
+    fn template(&self, page: StatusPage) -> &str {
+        let template = match page {
+            StatusPage::LoginError => &self.login_error,
+            StatusPage::AccessDenied => &self.access_denied,
+            StatusPage::SignedOut => &self.signed_out,
+            StatusPage::NotReady => &self.not_ready,
+        };
+        template.as_deref().unwrap_or(match page {
+            StatusPage::LoginError => DEFAULT_LOGIN_ERROR,
+            StatusPage::AccessDenied => DEFAULT_ACCESS_DENIED,
+            StatusPage::SignedOut => DEFAULT_SIGNED_OUT,
+            StatusPage::NotReady => DEFAULT_NOT_READY,
+        })
+    }
+
+    // Renders responses with a status page by content negotiation. Requests accepting HTML get the page,
+    // other requests get errors as JSON. Responses with a content type are sent as is.
+    pub fn render(&self, response: DownStreamResponse, request_headers: &[(String, String)]) -> DownStreamResponse {
+        let page = match response.page() {
+            Some(page) if response.content_type().is_none() => page,
+            _ => return response,
+        };
+        let header = |name: &str| {
+            request_headers.iter()
+                .find(|(key, _)| key.eq_ignore_ascii_case(name))
+                .map(|(_, value)| value.as_str())
+        };
+        let (headers, status, message) = response.serialize();
+        let error = response.error_code().map(|error| error.as_str()).unwrap_or_default();
+        if !header("accept").is_some_and(|accept| accept.contains("text/html")) {
+            return match response.error_code() {
+                Some(_) => {
+                    let body = serde_json::json!({"status": status, "error": error, "message": message});
+                    DownStreamResponse::json(headers, status, serde_json::to_string_pretty(&body).unwrap())
+                }
+                None => response,
+            };
+        }
+        let status_text = status.to_string();
+        let body = substitute(self.template(page), &[
+            ("{status}", &status_text),
+            ("{error}", error),
+            ("{message}", &message),
+            ("{request_id}", header("x-request-id").unwrap_or_default()),
+            ("{login_url}", START_PATH),
+        ]);
+        DownStreamResponse::html(headers, status, body)
+    }
+}
+
+// Replaces the placeholders of the template with the escaped values in a single pass, so placeholders in the
+// values are left as they are
+fn substitute(template: &str, values: &[(&str, &str)]) -> String {
+    let mut body = String::with_capacity(template.len());
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        body.push_str(&rest[..start]);
+        rest = &rest[start..];
+        match values.iter().find(|(placeholder, _)| rest.starts_with(placeholder)) {
+            Some((placeholder, value)) => {
+                body.push_str(&util::escape_html(value));
+                rest = &rest[placeholder.len()..];
+            }
+            None => {
+                body.push('{');
+                rest = &rest[1..];
+            }
+        }
+    }
+    body.push_str(rest);
+    body
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::error_page::{ErrorCode, ErrorPages, StatusPage};
+    use crate::messages::DownStreamResponse;
+
+    fn test_headers(accept: &str) -> Vec<(String, String)> {
+        vec![
+            ("accept".to_string(), accept.to_string()),
+            ("x-request-id".to_string(), "req-1".to_string()),
+        ]
+    }
+
+    #[test]
+    fn codes() {
+        assert_eq!(ErrorCode::InvalidState.status(), 400);
+        assert_eq!(serde_json::to_string(&ErrorCode::InvalidState).unwrap(), "\"invalid_state\"");
+        assert_eq!(ErrorCode::TokensExpired.page(), StatusPage::AccessDenied);
+        assert_eq!(ErrorCode::NotReady.page(), StatusPage::NotReady);
+        assert_eq!(ErrorCode::ProviderError.page(), StatusPage::LoginError);
+    }
+
+    #[test]
+    fn render() {
+        let pages: ErrorPages = serde_json::from_value(serde_json::json!({
+            "login_error": "<p>{status} {error}: {message} ({request_id}) {login_url}</p>"
+        })).unwrap();
+        let error = || DownStreamResponse::error(vec![], ErrorCode::InvalidState, "Invalid <state>".to_string());
+
+        let page = pages.render(error(), &test_headers("text/html,application/xhtml+xml"));
+        assert_eq!(page.content_type(), Some("text/html; charset=utf-8"));
+        assert_eq!(page.code(), 400);
+        assert_eq!(page.body(), "<p>400 invalid_state: Invalid &lt;state&gt; (req-1) /auth</p>");
+
+        // Placeholders in the values are not replaced, values are escaped once
+        let injected = DownStreamResponse::error(vec![], ErrorCode::InvalidState, "{request_id} &lt; {login_url}".to_string());
+        let headers = vec![
+            ("accept".to_string(), "text/html".to_string()),
+            ("x-request-id".to_string(), "{message}<".to_string()),
+        ];
+        assert_eq!(
+            pages.render(injected, &headers).body(),
+            "<p>400 invalid_state: {request_id} &amp;lt; {login_url} ({message}&lt;) /auth</p>"
+        );
+
+        let json = pages.render(error(), &test_headers("application/json"));
+        assert_eq!(json.content_type(), Some("application/json"));
+        let body: serde_json::Value = serde_json::from_str(json.body()).unwrap();
+        assert_eq!(body["error"], "invalid_state");
+        assert_eq!(body["message"], "Invalid <state>");
+
+        // Default templates are used for pages without a template
+        let page = pages.render(
+            DownStreamResponse::new(vec![], 200, "Signed Out".to_string()).with_page(StatusPage::SignedOut),
+            &test_headers("text/html"),
+        );
+        assert!(page.body().contains("<h1>Signed out</h1>"));
+
+        // Responses with a content type are sent as is
+        let response = pages.render(DownStreamResponse::html(vec![], 200, "<p>page</p>".to_string()), &test_headers("text/html"));
+        assert_eq!(response.body(), "<p>page</p>");
+    }
+}
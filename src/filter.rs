@@ -1,24 +1,43 @@
+mod authorization;
+mod bff;
 mod cache;
+mod client_auth;
 mod config;
+mod csrf;
 mod discovery;
+mod error_page;
+mod introspection;
 mod messages;
 pub mod mock_overrides;
 mod oauth_client;
 mod oauth_client_types;
+mod post_replay;
+mod provider;
+mod redirect_loop;
 mod session;
+mod token_exchange;
 mod util;
 
 use crate::cache::SharedCache;
-use crate::config::{FilterConfig, RawFilterConfig};
+use crate::config::{FilterConfig, RawFilterConfig, DEFAULT_PROVIDER};
 use crate::discovery::{ConfigError, JsonWebKeySet, ProviderMetadata};
-use crate::messages::{DownStreamResponse, ErrorBody, HttpRequest, TokenResponse};
-use crate::oauth_client::{CALLBACK_PATH, SIGN_OUT_PATH, START_PATH};
-use crate::oauth_client_types::{Access, ClientError, Redirect, Request, TokenRequest};
-use crate::session::SessionCache;
+use crate::error_page::{ErrorCode, ErrorPages};
+use crate::messages::{DownStreamResponse, HttpRequest, TokenResponse};
+use crate::oauth_client::{
+    CALLBACK_PATH, FRONTEND_TOKEN_PATH, SESSION_INFO_PATH, SIGN_OUT_PATH, START_PATH,
+    UPSTREAM_HEADERS,
+};
+use crate::oauth_client_types::{
+    Access, CallbackError, ClientError, FrontendToken, Redirect, Request, TokenRequest,
+};
+use crate::provider::{Provider, Providers};
+use crate::session::{Session, SessionCache, SessionUpdate};
+use crate::token_exchange::ExchangeTarget;
 use proxy_wasm::traits::{Context, HttpContext, RootContext};
 use proxy_wasm::types::{Action, ContextType, LogLevel, Status};
 use std::cell::RefCell;
-use std::ops::Deref;
+use std::collections::HashMap;
+use std::ops::{Deref, DerefMut};
 use std::option::Option::Some;
 use std::time::Duration;
 use url;
@@ -28,31 +47,77 @@ use url::{ParseError, Url};
 #[no_mangle]
 pub fn _start() {
     proxy_wasm::set_log_level(LogLevel::Debug);
-    proxy_wasm::set_root_context(|_| -> Box<dyn RootContext> {
-        Box::new(OAuthRootContext {
-            config: None,
-            provider_metadata: None,
-            jwks: None,
-            request_active: false,
-        })
-    });
+    proxy_wasm::set_root_context(|_| -> Box<dyn RootContext> { Box::new(OAuthRootContext::new(None)) });
 }
 
 struct OAuthRootContext {
     config: Option<RawFilterConfig>,
-    provider_metadata: Option<ProviderMetadata>,
-    jwks: Option<JsonWebKeySet>,
+    // Discovered metadata and keys by provider name
+    provider_metadata: HashMap<String, ProviderMetadata>,
+    jwks: HashMap<String, JsonWebKeySet>,
+    // Provider names of pending discovery and JWKS requests by token ID
+    discovery_calls: HashMap<u32, String>,
     request_active: bool,
 }
 
+// Responds to requests received before the filter is ready with the not ready page
+struct NotReadyFilter {
+    error_pages: ErrorPages,
+}
+
+impl HttpContext for NotReadyFilter {
+    fn on_http_request_headers(&mut self, _: usize) -> Action {
+        let response = self.error_pages.render(
+            DownStreamResponse::error(
+                vec![("Retry-After".to_string(), "2".to_string())],
+                ErrorCode::NotReady,
+                "Provider discovery has not completed".to_string(),
+            ),
+            &self.get_http_request_headers(),
+        );
+        let mut headers = response.headers();
+        headers.push(("Content-Type", response.content_type().unwrap_or("application/json")));
+        self.send_http_response(response.code(), headers, Some(response.body().as_bytes()));
+        Action::Pause
+    }
+}
+
+impl Context for NotReadyFilter {}
+
 struct OAuthFilter {
     config: FilterConfig,
     oauth_client: crate::oauth_client::OAuthClient,
+    // All providers if more than one is configured, config and oauth_client belong to the selected provider
+    providers: Option<Providers>,
     cache: RefCell<SharedCache>,
+    pending_call: Option<PendingCall>,
+    // Body buffered before the request is handled, a form encoded callback or sign out request, or a POST request
+    // saved with the authorization request
+    form_body: Option<Vec<u8>>,
+    awaiting_form_body: bool,
+}
+
+// HTTP calls to the authorization server the filter is waiting for a response to
+enum PendingCall {
+    TokenRequest,
+    Introspection(String),
+    TokenExchange(String, ExchangeTarget),
+    // Pushed authorization request, with the headers of the pending redirect
+    PushedAuthorization(Vec<(String, String)>),
+    // Userinfo request, with the redirect and session update of the token response
+    Userinfo(Redirect, Box<SessionUpdate>),
+    // Refresh token request for the front-end token endpoint
+    Refresh,
+    // Refresh token request for expired session tokens, the request is handled again once they are refreshed
+    SessionRefresh,
 }
 
 impl OAuthFilter {
-    fn new(config: FilterConfig, cache: SharedCache) -> Result<OAuthFilter, ParseError> {
+    fn new(
+        config: FilterConfig,
+        providers: Option<Providers>,
+        cache: SharedCache,
+    ) -> Result<OAuthFilter, ParseError> {
         log::debug!("Creating new HttpContext");
         log::debug!("Cache for HttpContext = {:?}", cache);
         log::debug!("Config for HttpContext = {:?}", config);
@@ -62,21 +127,36 @@ impl OAuthFilter {
         Ok(OAuthFilter {
             config,
             oauth_client,
+            providers,
             cache,
+            pending_call: None,
+            form_body: None,
+            awaiting_form_body: false,
         })
     }
 
-    fn send_error(&self, code: u32, response: crate::messages::ErrorBody) {
-        let body = serde_json::to_string_pretty(&response).unwrap();
-        log::error!("{}", body);
-        self.send_http_response(
-            code,
-            vec![("Content-Type", "application/json")],
-            Some(body.as_bytes()),
-        );
+    fn send_error(&self, code: ErrorCode, message: String) {
+        self.send_downstream_response(DownStreamResponse::error(vec![], code, message));
     }
 
-    fn send_error_response(&self, response: DownStreamResponse) {
+    // Responses with a status page are rendered as HTML or JSON by the Accept header of the request
+    fn send_downstream_response(&self, response: DownStreamResponse) {
+        if let Some(error) = response.error_code() {
+            log::error!("Error response, error={} message={}", error.as_str(), response.body());
+        }
+        let response = self
+            .config
+            .error_pages()
+            .render(response, &self.get_http_request_headers());
+        if let Some(content_type) = response.content_type() {
+            let mut headers = response.headers();
+            headers.push(("Content-Type", content_type));
+            return self.send_http_response(
+                response.code(),
+                headers,
+                Some(response.body().as_bytes()),
+            );
+        }
         let body = serde_json::to_string_pretty(&response).unwrap();
         let mut headers = response.headers();
         headers.push(("Content-Type", "application/json"));
@@ -85,6 +165,11 @@ impl OAuthFilter {
         self.send_http_response(response.code(), headers, Some(body.as_bytes()));
     }
 
+    // The session info endpoint is only answered if enabled, other paths below it are forwarded
+    fn is_session_info(&self, path: &str) -> bool {
+        self.config.session_info() && path == SESSION_INFO_PATH
+    }
+
     // Send redirect response to end-user
     fn respond_with_redirect(&self, url: Url, headers: Vec<(String, String)>) {
         let mut headers: Vec<(&str, &str)> = headers
@@ -96,13 +181,52 @@ impl OAuthFilter {
         self.send_http_response(302, headers, None);
     }
 
-    // Parse session cookie from request headers
+    // Send the end-user back to the request that started the authorization, saved POST requests are replayed
+    fn return_to_request(&self, redirect: Redirect) {
+        match redirect.replay() {
+            Some(saved_post) => self.send_downstream_response(
+                saved_post.replay_page(redirect.url(), redirect.headers().clone()),
+            ),
+            None => self.respond_with_redirect(redirect.url().clone(), redirect.headers().clone()),
+        }
+    }
+
+    // Parse session cookie from request headers. Cross site form_post authorization responses only carry
+    // the authorization cookie, which is accepted for sessions with a pending authorization request.
     fn session(&self, headers: &Vec<(String, String)>) -> Option<crate::session::Session> {
-        crate::session::Session::_from_headers(
+        let cache = self.cache.borrow();
+        let session = crate::session::Session::_from_headers(
             self.config.cookie_name().to_string(),
             headers,
-            self.cache.borrow().deref(),
-        )
+            cache.deref(),
+        );
+        if session.is_none() && self.config.form_post() {
+            return crate::session::Session::_from_headers(
+                crate::session::authorization_cookie_name(self.config.cookie_name()),
+                headers,
+                cache.deref(),
+            )
+            .filter(|session| session.authorization_verifiers().is_some());
+        }
+        session
+    }
+
+    // Remove filter owned headers and the session cookie from the request forwarded upstream
+    fn strip_request_headers(&self, headers: &[(String, String)]) {
+        for name in UPSTREAM_HEADERS.iter() {
+            self.set_http_request_header(name, None);
+        }
+
+        let cookies: Vec<&str> = headers
+            .iter()
+            .filter(|(name, _)| name == "cookie")
+            .map(|(_, value)| value.as_str())
+            .collect();
+        if !cookies.is_empty() {
+            let cookies =
+                crate::session::remove_cookie(self.config.cookie_name(), &cookies.join("; "));
+            self.set_http_request_header("cookie", cookies.as_deref());
+        }
     }
 
     // Call the client by right method depending on the request path
@@ -113,32 +237,203 @@ impl OAuthFilter {
     ) -> Result<FilterAction, ClientError> {
         let mut cache = self.cache.borrow_mut();
         if request.url().path().starts_with(CALLBACK_PATH) {
+            if let Some((error, update)) = self
+                .oauth_client
+                .callback_error(&request, session.as_ref())?
+            {
+                cache.set(update);
+                cache.store(self).unwrap(); // TODO handle errors
+                return match error {
+                    CallbackError::Retry(redirect) => self.authorization_redirect(redirect),
+                    CallbackError::Redirect(redirect) => Ok(FilterAction::Redirect(redirect)),
+                    CallbackError::Response(response) => Ok(FilterAction::Response(response)),
+                };
+            }
             let token_request = self.oauth_client.callback(request, session)?;
             Ok(FilterAction::TokenRequest(token_request))
         } else if request.url().path().starts_with(START_PATH) {
+            if let Some(response) = self.oauth_client.redirect_loop(&request) {
+                return Ok(FilterAction::Response(response));
+            }
             let (redirect, update) = self.oauth_client.start(request)?;
             cache.set(update);
             cache.store(self).unwrap(); // TODO handle errors
-            Ok(FilterAction::Redirect(redirect))
+            self.authorization_redirect(redirect)
         } else if request.url().path().starts_with(SIGN_OUT_PATH) {
+            // Signing out changes state, GET requests get a page confirming it with a POST
+            let is_get = request
+                .header(":method")
+                .is_some_and(|method| method.eq_ignore_ascii_case("GET"));
+            if is_get {
+                return Ok(FilterAction::Response(csrf::confirmation_page(&request, "Sign out")));
+            }
+            if let Err(response) = csrf::validate_request(&request) {
+                return Ok(FilterAction::Response(response));
+            }
             let (response, update) = self.oauth_client.sign_out(session)?;
             cache.set(update);
             cache.store(self).unwrap(); // TODO handle errors
             Ok(FilterAction::Response(response))
+        } else if self.is_session_info(request.url().path()) {
+            Ok(FilterAction::Response(
+                self.oauth_client.session_info(session.as_ref()),
+            ))
+        } else if request.url().path().starts_with(FRONTEND_TOKEN_PATH) && self.config.bff().is_some() {
+            match self
+                .oauth_client
+                .frontend_token(&request, session.as_ref())?
+            {
+                FrontendToken::Token(response) => Ok(FilterAction::Response(response)),
+                FrontendToken::Refresh(request, update) => {
+                    cache.set(*update);
+                    cache.store(self).unwrap(); // TODO handle errors
+                    Ok(FilterAction::Refresh(request))
+                }
+            }
+        } else if self
+            .config
+            .rule(request.url().path())
+            .is_some_and(|rule| rule.is_bearer())
+        {
+            let access = match self.oauth_client.bearer(&request, cache.deref()) {
+                Access::Allowed(headers) => {
+                    self.oauth_client
+                        .token_exchange(&request, None, headers, cache.deref())
+                }
+                access => access,
+            };
+            Ok(FilterAction::from_access(access))
         } else {
-            match self.oauth_client.proxy(session)? {
-                Access::Denied(response) => Ok(FilterAction::Response(response)),
-                Access::Allowed(headers) => Ok(FilterAction::Allow(headers)),
+            match self.oauth_client.proxy(&request, session.clone())? {
+                // Authenticated sessions are authorized against the rule for the request path
+                Access::Allowed(headers) => {
+                    match self
+                        .oauth_client
+                        .introspect_session(session.as_ref(), cache.deref())
+                    {
+                        Some(Access::Introspect(token)) => {
+                            return Ok(FilterAction::Introspect(token))
+                        }
+                        Some(Access::Denied(response)) => {
+                            return Ok(FilterAction::Response(response))
+                        }
+                        _ => {}
+                    }
+                    if let Some((redirect, update)) =
+                        self.oauth_client.step_up(&request, session.as_ref())?
+                    {
+                        if let Some(response) = self.oauth_client.redirect_loop(&request) {
+                            return Ok(FilterAction::Response(response));
+                        }
+                        cache.set(update);
+                        cache.store(self).unwrap(); // TODO handle errors
+                        return self.authorization_redirect(redirect);
+                    }
+                    match self.oauth_client.authorize(&request, session.as_ref()) {
+                        Ok(_) => Ok(FilterAction::from_access(self.oauth_client.token_exchange(
+                            &request,
+                            session.as_ref(),
+                            headers,
+                            cache.deref(),
+                        ))),
+                        Err(response) => Ok(FilterAction::Response(response)),
+                    }
+                }
+                Access::Refresh(refresh_request, update) => {
+                    cache.set(*update);
+                    cache.store(self).unwrap(); // TODO handle errors
+                    Ok(FilterAction::RefreshSession(refresh_request))
+                }
+                Access::Renew(redirect, update) => {
+                    if let Some(response) = self.oauth_client.redirect_loop(&request) {
+                        return Ok(FilterAction::Response(response));
+                    }
+                    cache.set(*update);
+                    cache.store(self).unwrap(); // TODO handle errors
+                    self.authorization_redirect(redirect)
+                }
                 Access::UnAuthenticated => {
+                    if let Some(response) = self.oauth_client.redirect_loop(&request) {
+                        return Ok(FilterAction::Response(response));
+                    }
+                    // POST bodies are saved with the authorization request to be replayed after the callback
+                    let saved_post = match (self.config.post_replay(), &self.form_body) {
+                        (Some(post_replay), None) if post_replay.accepts(&request) => {
+                            if request.has_empty_body() {
+                                post_replay.save(&request, &[])
+                            } else {
+                                return Ok(FilterAction::BufferBody);
+                            }
+                        }
+                        (Some(post_replay), Some(body)) => post_replay.save(&request, body),
+                        _ => None,
+                    };
                     // Clean up
                     let (redirect, update) = self.oauth_client.start(request)?;
+                    let update = update.saved_post(saved_post);
                     cache.set(update);
                     cache.store(self).unwrap(); // TODO handle errors
-                    Ok(FilterAction::Redirect(redirect))
+                    self.authorization_redirect(redirect)
                 }
+                access => Ok(FilterAction::from_access(access)),
             }
         }
     }
+
+    // Switch to the provider selected by the request. Sessions of other providers are not used,
+    // unauthenticated requests without a selection get the provider picker page if enabled.
+    fn select_provider(
+        &mut self,
+        request: &Request,
+        session: Option<Session>,
+    ) -> Result<Option<Session>, DownStreamResponse> {
+        let providers = match &self.providers {
+            None => return Ok(session),
+            Some(providers) => providers,
+        };
+        let session_provider = session
+            .as_ref()
+            .map(|session| session.provider().unwrap_or(DEFAULT_PROVIDER).to_string());
+        let provider = match providers.select(request, session_provider.as_deref()) {
+            Some(provider) => provider,
+            None => {
+                let path = request.url().path();
+                let authenticates = !path.starts_with(CALLBACK_PATH)
+                    && !path.starts_with(SIGN_OUT_PATH)
+                    && !self.is_session_info(path)
+                    && !path.starts_with(FRONTEND_TOKEN_PATH)
+                    && !self.config.rule(path).is_some_and(|rule| rule.is_bearer());
+                match providers.picker_page(request) {
+                    Some(page) if session.is_none() && authenticates => {
+                        return Err(page)
+                    }
+                    _ => providers.default(),
+                }
+            }
+        };
+        let provider = provider.clone();
+        if self.config.provider() != Some(provider.name()) {
+            log::debug!("Selected provider = {}", provider.name());
+            self.oauth_client = crate::oauth_client::OAuthClient::new(provider.config().clone())
+                .map_err(|err| {
+                    ClientError::new(ErrorCode::ConfigurationError, format!("Invalid provider config, error={}", err))
+                        .response()
+                })?;
+            self.config = provider.config().clone();
+        }
+        Ok(session.filter(|_| session_provider.as_deref().is_none_or(|name| name == provider.name())))
+    }
+
+    // Redirect to the authorization server, the parameters are pushed to the provider first if enabled
+    fn authorization_redirect(&self, redirect: Redirect) -> Result<FilterAction, ClientError> {
+        match self.oauth_client.pushed_authorization_request(&redirect)? {
+            Some(request) => Ok(FilterAction::PushAuthorization(
+                request,
+                redirect.headers().clone(),
+            )),
+            None => Ok(FilterAction::Redirect(redirect)),
+        }
+    }
 }
 
 // Represent actions the filter carries out during OAuth
@@ -147,38 +442,123 @@ enum FilterAction {
     Redirect(Redirect),
     Response(DownStreamResponse),
     Allow(Vec<(String, String)>),
+    Introspect(String),
+    Exchange(String, ExchangeTarget),
+    PushAuthorization(TokenRequest, Vec<(String, String)>),
+    Refresh(TokenRequest),
+    RefreshSession(TokenRequest),
+    // The request body is needed to handle the request
+    BufferBody,
 }
 
-// Implement http functions related to this request.
-// This is the core of the filter code.
-impl HttpContext for OAuthFilter {
-    // This callback will be invoked when request headers arrive
-    fn on_http_request_headers(&mut self, _: usize) -> Action {
+impl FilterAction {
+    // Action for an access decision, unauthenticated requests are rejected
+    fn from_access(access: Access) -> FilterAction {
+        match access {
+            Access::Allowed(headers) => FilterAction::Allow(headers),
+            Access::Denied(response) => FilterAction::Response(response),
+            Access::Introspect(token) => FilterAction::Introspect(token),
+            Access::Exchange(subject_token, target) => FilterAction::Exchange(subject_token, target),
+            // Renewals and refreshes are started from the session, which must store the update first
+            Access::Renew(..) | Access::Refresh(..) => FilterAction::Response(DownStreamResponse::error(
+                vec![],
+                ErrorCode::AccessDenied,
+                "Tokens expired".to_string(),
+            )),
+            Access::UnAuthenticated => FilterAction::Response(DownStreamResponse::error(
+                vec![],
+                ErrorCode::AuthenticationRequired,
+                "Authentication required".to_string(),
+            )),
+        }
+    }
+}
+
+impl OAuthFilter {
+    // Handle the request with the current request headers, also used to resume paused requests
+    fn handle_request(&mut self) -> Action {
         let headers = self.get_http_request_headers();
         let user_session = self.session(&headers);
 
-        let request = Request::new(headers);
+        let request = Request::new(headers.clone());
         let request = if let Err(error) = request {
-            self.send_error_response(error.response());
+            self.send_downstream_response(error.response());
             return Action::Pause;
         } else {
             request.unwrap()
         };
 
+        // Authorization responses with response_mode=form_post are handled once the body is buffered
+        let accepts_form = (request.url().path().starts_with(CALLBACK_PATH)
+            || request.url().path().starts_with(SIGN_OUT_PATH))
+            && request.is_form_post();
+        let request = match &self.form_body {
+            Some(body) if accepts_form => request.with_form(body),
+            None if accepts_form && request.has_empty_body() => request,
+            None if accepts_form => {
+                self.awaiting_form_body = true;
+                return Action::Pause;
+            }
+            _ => request,
+        };
+
+        let user_session = match self.select_provider(&request, user_session) {
+            Ok(session) => session,
+            Err(response) => {
+                self.send_downstream_response(response);
+                return Action::Pause;
+            }
+        };
+
         match self.endpoint(request, user_session) {
             Ok(filter_action) => match filter_action {
                 FilterAction::TokenRequest(request) => {
-                    match self.dispatch_http_call(
-                        self.config.auth_cluster(),
-                        request.headers(),
-                        Some(request.body()),
-                        vec![],
-                        Duration::from_secs(20),
-                    ) {
-                        Ok(_) => {}
-                        Err(error) => {
-                            log::error!("Failed to dispatch token request to cluster = {} Envoy status = {:?}", self.config.auth_cluster(), error)
+                    if self.dispatch(&request).is_ok() {
+                        self.pending_call = Some(PendingCall::TokenRequest);
+                    }
+                    Action::Pause
+                }
+                FilterAction::Introspect(token) => {
+                    match self.oauth_client.introspection_request(&token) {
+                        Ok(request) => {
+                            if self.dispatch(&request).is_ok() {
+                                self.pending_call = Some(PendingCall::Introspection(token));
+                            }
                         }
+                        Err(error) => self.send_downstream_response(error.response()),
+                    }
+                    Action::Pause
+                }
+                FilterAction::Exchange(subject_token, target) => {
+                    match self
+                        .oauth_client
+                        .token_exchange_request(&subject_token, &target)
+                    {
+                        Ok(request) => {
+                            if self.dispatch(&request).is_ok() {
+                                self.pending_call =
+                                    Some(PendingCall::TokenExchange(subject_token, target));
+                            }
+                        }
+                        Err(error) => self.send_downstream_response(error.response()),
+                    }
+                    Action::Pause
+                }
+                FilterAction::PushAuthorization(request, headers) => {
+                    if self.dispatch(&request).is_ok() {
+                        self.pending_call = Some(PendingCall::PushedAuthorization(headers));
+                    }
+                    Action::Pause
+                }
+                FilterAction::Refresh(request) => {
+                    if self.dispatch(&request).is_ok() {
+                        self.pending_call = Some(PendingCall::Refresh);
+                    }
+                    Action::Pause
+                }
+                FilterAction::RefreshSession(request) => {
+                    if self.dispatch(&request).is_ok() {
+                        self.pending_call = Some(PendingCall::SessionRefresh);
                     }
                     Action::Pause
                 }
@@ -186,23 +566,241 @@ impl HttpContext for OAuthFilter {
                     self.respond_with_redirect(redirect.url().clone(), redirect.headers().clone());
                     Action::Pause
                 }
+                FilterAction::BufferBody => {
+                    self.awaiting_form_body = true;
+                    Action::Pause
+                }
                 FilterAction::Response(response) => {
-                    self.send_error_response(response);
+                    self.send_downstream_response(response);
                     Action::Pause
                 }
                 FilterAction::Allow(token_headers) => {
+                    self.strip_request_headers(&headers);
                     for header in token_headers {
-                        self.add_http_request_header(header.0.as_str(), header.1.as_str());
+                        self.set_http_request_header(header.0.as_str(), Some(header.1.as_str()));
                     }
                     Action::Continue
                 }
             },
             Err(error) => {
-                self.send_error_response(error.response());
+                self.send_downstream_response(error.response());
                 Action::Pause
             }
         }
     }
+
+    // Dispatch HTTP request to the authorization server cluster
+    fn dispatch(&self, request: &TokenRequest) -> Result<u32, Status> {
+        let result = self.dispatch_http_call(
+            self.config.auth_cluster(),
+            request.headers(),
+            Some(request.body()),
+            vec![],
+            Duration::from_secs(20),
+        );
+        if let Err(error) = &result {
+            log::error!(
+                "Failed to dispatch request to cluster = {} Envoy status = {:?}",
+                self.config.auth_cluster(),
+                error
+            );
+            self.send_error(
+                ErrorCode::InternalError,
+                "Failed to dispatch request to authorization server".to_string(),
+            );
+        }
+        result
+    }
+
+    // Cache the exchanged token and resume handling of the paused request
+    fn token_exchange_response(
+        &mut self,
+        subject_token: String,
+        target: ExchangeTarget,
+        body_size: usize,
+    ) {
+        let body = self
+            .get_http_call_response_body(0, body_size)
+            .unwrap_or_default();
+        let result = {
+            let mut cache = self.cache.borrow_mut();
+            self.oauth_client
+                .token_exchange_response(&subject_token, &target, body.as_slice(), cache.deref_mut())
+                .map(|_| cache.store(self).unwrap()) // TODO handle errors
+        };
+        match result {
+            Ok(_) => {
+                if let Action::Continue = self.handle_request() {
+                    self.resume_http_request()
+                }
+            }
+            Err(error) => self.send_downstream_response(error.response()),
+        }
+    }
+
+    // Redirect the end-user with the request URI of the pushed authorization request
+    fn pushed_authorization_response(&self, headers: Vec<(String, String)>, body_size: usize) {
+        let body = self
+            .get_http_call_response_body(0, body_size)
+            .unwrap_or_default();
+        match self
+            .oauth_client
+            .pushed_authorization_response(body.as_slice(), headers)
+        {
+            Ok(redirect) => {
+                self.respond_with_redirect(redirect.url().clone(), redirect.headers().clone())
+            }
+            Err(error) => self.send_downstream_response(error.response()),
+        }
+    }
+
+    // Store the refreshed tokens and respond with the new access token
+    fn refresh_response(&self, body_size: usize) {
+        let body = self
+            .get_http_call_response_body(0, body_size)
+            .unwrap_or_default();
+        let headers = self.get_http_request_headers();
+        let session = self.session(&headers);
+        let result = {
+            let mut cache = self.cache.borrow_mut();
+            self.oauth_client
+                .refresh_response(body.as_slice(), session.as_ref())
+                .map(|update| {
+                    let response = update
+                        .tokens()
+                        .map(|tokens| self.oauth_client.frontend_token_response(tokens));
+                    cache.set(update);
+                    cache.store(self).unwrap(); // TODO handle errors
+                    response
+                })
+        };
+        match result {
+            Ok(Some(response)) => self.send_downstream_response(response),
+            Ok(None) => self.send_downstream_response(
+                ClientError::new(ErrorCode::InternalError, "Refreshed session without tokens".to_string()).response(),
+            ),
+            Err(error) => self.send_downstream_response(error.response()),
+        }
+    }
+
+    // Store the refreshed tokens and handle the request again with them
+    fn session_refresh_response(&mut self, body_size: usize) {
+        let body = self
+            .get_http_call_response_body(0, body_size)
+            .unwrap_or_default();
+        let headers = self.get_http_request_headers();
+        let session = self.session(&headers);
+        let result = {
+            let mut cache = self.cache.borrow_mut();
+            self.oauth_client
+                .refresh_response(body.as_slice(), session.as_ref())
+                .and_then(|update| {
+                    // Tokens that expired already would be refreshed again when the request is handled
+                    if !update.tokens().is_some_and(|tokens| tokens.is_access_token_valid().unwrap_or(false)) {
+                        return Err(ClientError::new(ErrorCode::RefreshFailed, "Refreshed access token is expired".to_string()));
+                    }
+                    cache.set(update);
+                    cache.store(self).unwrap(); // TODO handle errors
+                    Ok(())
+                })
+        };
+        match result {
+            Ok(_) => {
+                if let Action::Continue = self.handle_request() {
+                    self.resume_http_request()
+                }
+            }
+            Err(error) => self.send_downstream_response(error.response()),
+        }
+    }
+
+    // Store the session with the userinfo claims and redirect the end-user back
+    fn userinfo_response(&self, redirect: Redirect, update: SessionUpdate, body_size: usize) {
+        let status = self.http_call_status();
+        if status.as_deref() != Some("200") {
+            return self.send_downstream_response(
+                ClientError::new(ErrorCode::ProviderError, format!("Userinfo request failed, status={:?}", status))
+                    .response(),
+            );
+        }
+        let body = self
+            .get_http_call_response_body(0, body_size)
+            .unwrap_or_default();
+        match self.oauth_client.userinfo_response(body.as_slice(), update) {
+            Ok(update) => {
+                let mut cache = self.cache.borrow_mut();
+                cache.set(update);
+                cache.store(self).unwrap(); // TODO handle errors
+                self.return_to_request(redirect)
+            }
+            Err(error) => self.send_downstream_response(error.response()),
+        }
+    }
+
+    // Status of the response to the dispatched HTTP call
+    fn http_call_status(&self) -> Option<String> {
+        self.get_http_call_response_headers()
+            .into_iter()
+            .find(|(name, _)| name == ":status")
+            .map(|(_, value)| value)
+    }
+
+    // Cache the introspection result and resume handling of the paused request
+    fn introspection_response(&mut self, token: String, body_size: usize) {
+        let status = self.http_call_status();
+        if status.as_deref() != Some("200") {
+            return self.send_downstream_response(
+                ClientError::new(ErrorCode::ProviderError, format!("Introspection request failed, status={:?}", status))
+                    .response(),
+            );
+        }
+        let body = self
+            .get_http_call_response_body(0, body_size)
+            .unwrap_or_default();
+        let result = {
+            let mut cache = self.cache.borrow_mut();
+            self.oauth_client
+                .introspection_response(&token, body.as_slice(), cache.deref_mut())
+                .map(|_| cache.store(self).unwrap()) // TODO handle errors
+        };
+        match result {
+            Ok(_) => {
+                if let Action::Continue = self.handle_request() {
+                    self.resume_http_request()
+                }
+            }
+            Err(error) => self.send_downstream_response(error.response()),
+        }
+    }
+}
+
+// Implement http functions related to this request.
+// This is the core of the filter code.
+impl HttpContext for OAuthFilter {
+    // This callback will be invoked when request headers arrive
+    fn on_http_request_headers(&mut self, _: usize) -> Action {
+        self.handle_request()
+    }
+
+    // Buffers the body of form_post callback requests, other bodies are passed through
+    fn on_http_request_body(&mut self, body_size: usize, end_of_stream: bool) -> Action {
+        // The request is held until the response of the pending call allows or rejects it
+        if self.pending_call.is_some() {
+            return Action::Pause;
+        }
+        if !self.awaiting_form_body {
+            return Action::Continue;
+        }
+        if !end_of_stream {
+            return Action::Pause;
+        }
+        self.awaiting_form_body = false;
+        self.form_body = Some(
+            self.get_http_request_body(0, body_size)
+                .unwrap_or_default(),
+        );
+        self.handle_request()
+    }
 }
 
 impl Context for OAuthFilter {
@@ -213,13 +811,44 @@ impl Context for OAuthFilter {
         body_size: usize,
         _num_trailers: usize,
     ) {
+        match self.pending_call.take() {
+            Some(PendingCall::Introspection(token)) => {
+                log::debug!("Introspection response from auth server received");
+                return self.introspection_response(token, body_size);
+            }
+            Some(PendingCall::TokenExchange(subject_token, target)) => {
+                log::debug!("Token exchange response from auth server received");
+                return self.token_exchange_response(subject_token, target, body_size);
+            }
+            Some(PendingCall::PushedAuthorization(headers)) => {
+                log::debug!("Pushed authorization response from auth server received");
+                return self.pushed_authorization_response(headers, body_size);
+            }
+            Some(PendingCall::Refresh) => {
+                log::debug!("Refresh token response from auth server received");
+                return self.refresh_response(body_size);
+            }
+            Some(PendingCall::SessionRefresh) => {
+                log::debug!("Session refresh token response from auth server received");
+                return self.session_refresh_response(body_size);
+            }
+            Some(PendingCall::Userinfo(redirect, update)) => {
+                log::debug!("Userinfo response from auth server received");
+                return self.userinfo_response(redirect, *update, body_size);
+            }
+            _ => {}
+        }
+
         log::debug!("Token response from auth server received");
         if let Some(body) = self.get_http_call_response_body(0, body_size) {
             match serde_json::from_slice::<crate::messages::TokenResponse>(body.as_slice()) {
                 Ok(response) => {
                     match response {
                         crate::messages::TokenResponse::Error(response) => {
-                            self.send_error(500, response.to_error_body())
+                            self.send_error(
+                                ErrorCode::ProviderError,
+                                format!("Token endpoint error={}", response.to_error_body().serialize()),
+                            )
                         }
                         crate::messages::TokenResponse::Success(response) => {
                             log::debug!("access token found");
@@ -232,15 +861,18 @@ impl Context for OAuthFilter {
                                 .token_response(TokenResponse::Success(response), user_session)
                             {
                                 Ok((redirect, update)) => {
+                                    if let Some(request) = self.oauth_client.userinfo_request(&update) {
+                                        if self.dispatch(&request).is_ok() {
+                                            self.pending_call = Some(PendingCall::Userinfo(redirect, Box::new(update)));
+                                        }
+                                        return;
+                                    }
                                     let mut cache = self.cache.borrow_mut();
                                     cache.set(update);
                                     cache.store(self).unwrap(); // TODO handle errors
-                                    self.respond_with_redirect(
-                                        redirect.url().clone(),
-                                        redirect.headers().clone(),
-                                    )
+                                    self.return_to_request(redirect)
                                 }
-                                Err(error) => self.send_error_response(error.response()),
+                                Err(error) => self.send_downstream_response(error.response()),
                             }
                         }
                     }
@@ -249,23 +881,15 @@ impl Context for OAuthFilter {
                     let error_message = String::from_utf8(body);
                     log::debug!("Error response from token endpoint={:?}", error_message);
                     self.send_error(
-                        500,
-                        ErrorBody::new(
-                            "500".to_string(),
-                            format!("Invalid token response:  {:?}", error_message),
-                            None,
-                        ),
+                        ErrorCode::ProviderError,
+                        format!("Invalid token response:  {:?}", error_message),
                     );
                 }
             };
         } else {
             self.send_error(
-                500,
-                ErrorBody::new(
-                    "500".to_string(),
-                    format!("Received invalid payload from authorization server"),
-                    None,
-                ),
+                ErrorCode::ProviderError,
+                "Received invalid payload from authorization server".to_string(),
             );
         }
     }
@@ -275,12 +899,19 @@ impl Context for OAuthRootContext {
     // Handle http discovery responses during configuration
     fn on_http_call_response(
         &mut self,
-        _token_id: u32,
+        token_id: u32,
         _num_headers: usize,
         body_size: usize,
         _num_trailers: usize,
     ) {
         log::debug!("OAuthRootContext received HTTP response");
+        let provider = match self.discovery_calls.remove(&token_id) {
+            None => {
+                log::error!("No pending discovery request for token_id={}", token_id);
+                return;
+            }
+            Some(provider) => provider,
+        };
         let bytes = match self.get_http_call_response_body(0, body_size) {
             None => {
                 log::error!("No body in HTTP response");
@@ -289,21 +920,38 @@ impl Context for OAuthRootContext {
             Some(bytes) => bytes,
         };
 
-        if self.provider_metadata.is_none() {
+        if !self.provider_metadata.contains_key(&provider) {
             match ProviderMetadata::from_bytes(bytes) {
                 Ok(provider_metadata) => {
-                    self.provider_metadata = Some(provider_metadata);
-                    let request =
-                        discovery::jwks_request(self.provider_metadata.clone().unwrap().jwks_url());
-                    match self.dispatch(request) {
-                        Ok(_) => {
-                            log::debug!("successfully dispatched JWKS request")
+                    if let Some(Err(error)) = self
+                        .raw_config(&provider)
+                        .map(|config| config.validate_provider_metadata(&provider_metadata))
+                    {
+                        log::error!("ERROR config incompatible with ProviderMetadata = {}", error);
+                        panic!("Config incompatible with ProviderMetadata") // Crash hard here as we cannot serve requests
+                    }
+                    log::debug!("Provider Metadata configured for provider={}: {:?}", provider, provider_metadata);
+                    let jwks_url = provider_metadata.jwks_url();
+                    self.provider_metadata.insert(provider.clone(), provider_metadata);
+                    match jwks_url {
+                        Some(jwks_url) => match self.dispatch(&provider, discovery::jwks_request(jwks_url)) {
+                            Ok(_) => {
+                                log::debug!("successfully dispatched JWKS request")
+                            }
+                            Err(err) => {
+                                log::error!("Failed to JWKS request, Envoy status = {:?}", err)
+                            }
+                        },
+                        // OAuth authorization servers without keys
+                        None if !self.raw_config(&provider).is_none_or(|config| config.is_oidc()) => {
+                            self.jwks.insert(provider, JsonWebKeySet::new(vec![]));
+                            self.stop_discovery_if_done();
                         }
-                        Err(err) => {
-                            log::error!("Failed to JWKS request, Envoy status = {:?}", err)
+                        None => {
+                            log::error!("ERROR ProviderMetadata without jwks_uri for provider={}", provider);
+                            panic!("ProviderMetadata without jwks_uri") // Crash hard here as we cannot serve requests
                         }
                     }
-                    log::debug!("Provider Metadata configured: {:?}", self.provider_metadata)
                 }
                 Err(error) => {
                     log::error!("ERROR parsing ProviderMetadata = {}", error);
@@ -313,9 +961,9 @@ impl Context for OAuthRootContext {
         } else {
             match JsonWebKeySet::from_bytes(bytes) {
                 Ok(jwks) => {
-                    self.jwks = Some(jwks);
-                    log::debug!("JWKS configured: {:?}", self.jwks);
-                    self.stop_discovery();
+                    log::debug!("JWKS configured for provider={}: {:?}", provider, jwks);
+                    self.jwks.insert(provider, jwks);
+                    self.stop_discovery_if_done();
                 }
                 Err(error) => {
                     log::error!("ERROR parsing JsonWebKeySet = {}", error);
@@ -348,10 +996,21 @@ impl RootContext for OAuthRootContext {
             }
         };
         log::debug!("Filter configured with:\n{:?}", raw_config);
+        if let Err(error) = raw_config.validate() {
+            log::error!("ERROR invalid config during initialization = {}", error);
+            return false;
+        }
 
+        for (provider, config) in raw_config.provider_configs() {
+            if let Ok(Some((provider_metadata, jwks))) = config.static_discovery() {
+                log::debug!("Static provider metadata and JWKS configured for provider={}", provider);
+                self.provider_metadata.insert(provider.to_string(), provider_metadata);
+                self.jwks.insert(provider.to_string(), jwks);
+            }
+        }
         self.config = Some(raw_config);
 
-        if self.config.clone().unwrap().is_oidc() {
+        if !self.undiscovered_providers().is_empty() {
             self.start_discovery();
         }
         true
@@ -361,12 +1020,14 @@ impl RootContext for OAuthRootContext {
     fn on_tick(&mut self) {
         log::debug!("RootContext tick, request active={}", self.request_active);
         if !self.request_active {
-            match self.dispatch_discovery() {
-                Ok(_) => {
-                    log::debug!("successfully dispatched discovery request")
-                }
-                Err(_) => {
-                    log::error!("Failed to dispatch discovery request")
+            for provider in self.undiscovered_providers() {
+                match self.dispatch_discovery(&provider) {
+                    Ok(_) => {
+                        log::debug!("successfully dispatched discovery request for provider={}", provider)
+                    }
+                    Err(_) => {
+                        log::error!("Failed to dispatch discovery request for provider={}", provider)
+                    }
                 }
             }
         }
@@ -381,6 +1042,12 @@ impl RootContext for OAuthRootContext {
                 None
             }
             Some(filter_config) => {
+                if !self.is_ready() {
+                    log::warn!("Provider discovery has not completed, responding with the not ready page");
+                    return Some(Box::new(NotReadyFilter {
+                        error_pages: filter_config.error_pages().clone(),
+                    }));
+                }
                 let cache = match SharedCache::from_host(self) {
                     Ok(cache) => {
                         log::debug!("Stored cache returned from host");
@@ -399,25 +1066,7 @@ impl RootContext for OAuthRootContext {
                         cache
                     }
                 };
-                match filter_config.is_oidc() {
-                    false => match filter_config.oauth_config() {
-                        Ok(config) => Some(Box::new(OAuthFilter::new(config, cache).unwrap())),
-                        Err(error) => {
-                            panic!("ERROR during HttpContext OAuth configuration = {}", error)
-                        }
-                    },
-                    true => {
-                        match filter_config.oidc_config(
-                            &self.provider_metadata.clone().unwrap(),
-                            &self.jwks.clone().unwrap(),
-                        ) {
-                            Ok(config) => Some(Box::new(OAuthFilter::new(config, cache).unwrap())),
-                            Err(error) => {
-                                panic!("ERROR during HttpContext OIDC configuration = {}", error)
-                            }
-                        }
-                    }
-                }
+                Some(Box::new(self.oauth_filter(filter_config, cache)))
             }
         }
     }
@@ -428,6 +1077,43 @@ impl RootContext for OAuthRootContext {
 }
 
 impl OAuthRootContext {
+    fn new(config: Option<RawFilterConfig>) -> OAuthRootContext {
+        OAuthRootContext {
+            config,
+            provider_metadata: HashMap::new(),
+            jwks: HashMap::new(),
+            discovery_calls: HashMap::new(),
+            request_active: false,
+        }
+    }
+
+    // Filter for a request, with the providers if several are configured
+    fn oauth_filter(&self, filter_config: &RawFilterConfig, cache: SharedCache) -> OAuthFilter {
+        if filter_config.providers().is_empty() {
+            let config = self.filter_config(DEFAULT_PROVIDER, filter_config);
+            return OAuthFilter::new(config, None, cache).unwrap();
+        }
+
+        // All providers share the session cookie, sessions remember their provider
+        let default = Provider::default(
+            self.filter_config(DEFAULT_PROVIDER, filter_config)
+                .with_provider(DEFAULT_PROVIDER, filter_config.cookie_name()),
+        );
+        let providers = filter_config
+            .providers()
+            .iter()
+            .map(|provider| {
+                let config = self
+                    .filter_config(&provider.name, &provider.config)
+                    .with_provider(&provider.name, filter_config.cookie_name());
+                Provider::new(provider, config)
+            })
+            .collect();
+        let providers = Providers::new(default, providers, filter_config.provider_picker());
+        let config = providers.default().config().clone();
+        OAuthFilter::new(config, Some(providers), cache).unwrap()
+    }
+
     // Activate RootContext tick handler which will dispatch discovery request
     fn start_discovery(&self) {
         // Cannot dispatch HTTP calls in config handler, so we need to dispatch in tick handler
@@ -440,9 +1126,78 @@ impl OAuthRootContext {
         self.set_tick_period(Duration::from_secs(0))
     }
 
-    // Dispatch a OIDC discovery request to the authorization server
-    fn dispatch_discovery(&self) -> Result<(), discovery::ConfigError> {
-        let config = if let Some(config) = &self.config {
+    // Deactivate RootContext tick handler once all providers are discovered
+    fn stop_discovery_if_done(&self) {
+        if self.undiscovered_providers().is_empty() {
+            self.stop_discovery();
+        }
+    }
+
+    // Raw configuration of the provider
+    fn raw_config(&self, provider: &str) -> Option<&RawFilterConfig> {
+        self.config.as_ref().and_then(|config| {
+            config
+                .provider_configs()
+                .into_iter()
+                .find(|(name, _)| *name == provider)
+                .map(|(_, config)| config)
+        })
+    }
+
+    // Requests can only be handled once the metadata and JWKS of every provider are discovered
+    fn is_ready(&self) -> bool {
+        self.undiscovered_providers().is_empty()
+    }
+
+    // Names of the providers configured through discovery
+    fn discovered_providers(&self) -> Vec<String> {
+        self.config.as_ref().map_or(vec![], |config| {
+            config
+                .provider_configs()
+                .into_iter()
+                .filter(|(_, config)| config.requires_discovery())
+                .map(|(name, _)| name.to_string())
+                .collect()
+        })
+    }
+
+    // Names of the providers without discovered or static provider metadata and JWKS
+    fn undiscovered_providers(&self) -> Vec<String> {
+        self.discovered_providers()
+            .into_iter()
+            .filter(|provider| !self.jwks.contains_key(provider))
+            .collect()
+    }
+
+    // Filter config of the provider, with the discovered metadata if any
+    fn filter_config(&self, provider: &str, raw_config: &RawFilterConfig) -> FilterConfig {
+        match raw_config.is_oidc() {
+            false => match self.provider_metadata.get(provider).map_or_else(
+                || raw_config.oauth_config(),
+                |metadata| raw_config.discovered_oauth_config(metadata, self.jwks.get(provider).unwrap()),
+            ) {
+                Ok(config) => config,
+                Err(error) => {
+                    panic!("ERROR during HttpContext OAuth configuration = {}", error)
+                }
+            },
+            true => {
+                match raw_config.oidc_config(
+                    self.provider_metadata.get(provider).unwrap(),
+                    self.jwks.get(provider).unwrap(),
+                ) {
+                    Ok(config) => config,
+                    Err(error) => {
+                        panic!("ERROR during HttpContext OIDC configuration = {}", error)
+                    }
+                }
+            }
+        }
+    }
+
+    // Dispatch a OIDC discovery request to the authorization server of the provider
+    fn dispatch_discovery(&mut self, provider: &str) -> Result<(), discovery::ConfigError> {
+        let config = if let Some(config) = self.raw_config(provider) {
             config
         } else {
             return Err(ConfigError::BadState(
@@ -450,10 +1205,11 @@ impl OAuthRootContext {
             ));
         };
 
-        let request = discovery::discovery_request(&config.issuer().parse().unwrap())
+        let request = config
+            .discovery_request()
             .map_err(|err| ConfigError::Parse(err.to_string()))?;
 
-        let result = self.dispatch(request);
+        let result = self.dispatch(provider, request);
         if result.is_err() {
             log::error!(
                 "ERROR when attempting http request to discovery endpoint, error={:?}",
@@ -464,19 +1220,144 @@ impl OAuthRootContext {
         Ok(())
     }
 
-    // Dispatch HTTP request to the authorization server
-    fn dispatch(&self, request: HttpRequest) -> Result<u32, Status> {
-        log::debug!(
-            "HTTP request to cluster={}  request={:?}",
-            &self.config.as_ref().unwrap().cluster(),
-            request
-        );
-        self.dispatch_http_call(
-            &self.config.as_ref().unwrap().cluster(),
+    // Dispatch HTTP request to the authorization server of the provider
+    fn dispatch(&mut self, provider: &str, request: HttpRequest) -> Result<u32, Status> {
+        let cluster = self.raw_config(provider).unwrap().cluster().to_string();
+        log::debug!("HTTP request to cluster={}  request={:?}", cluster, request);
+        let token_id = self.dispatch_http_call(
+            &cluster,
             request.headers(),
             None,
             vec![],
             Duration::from_secs(5),
-        )
+        )?;
+        self.discovery_calls.insert(token_id, provider.to_string());
+        Ok(token_id)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_filter() -> OAuthFilter {
+        let config = FilterConfig::oauth(
+            "sessioncookie",
+            "cluster",
+            "https://issuer",
+            &"https://redirect".parse().unwrap(),
+            &"https://authorization".parse().unwrap(),
+            &"https://token".parse().unwrap(),
+            "myclient",
+            "mysecret",
+            vec!["email".to_string()],
+            time::Duration::hours(1),
+            vec![]);
+        OAuthFilter::new(config, None, SharedCache::new()).unwrap()
+    }
+
+    #[test]
+    fn body_without_pending_call() {
+        let mut filter = test_filter();
+        assert!(matches!(filter.on_http_request_body(10, true), Action::Continue));
+    }
+
+    #[test]
+    fn body_during_introspection() {
+        let mut filter = test_filter();
+        filter.pending_call = Some(PendingCall::Introspection("opaquetoken".to_string()));
+        assert!(matches!(filter.on_http_request_body(10, false), Action::Pause));
+        assert!(matches!(filter.on_http_request_body(10, true), Action::Pause));
+        assert!(matches!(filter.pending_call, Some(PendingCall::Introspection(_))));
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn body_during_token_exchange() {
+        let mut filter = test_filter();
+        let target = ExchangeTarget::new("api.example.com", "https://api.example.com", vec![]);
+        filter.pending_call = Some(PendingCall::TokenExchange("accesstoken".to_string(), target));
+        assert!(matches!(filter.on_http_request_body(10, true), Action::Pause));
+        assert!(matches!(filter.pending_call, Some(PendingCall::TokenExchange(_, _))));
+    }
+
+    #[test]
+    fn body_during_form_post_token_request() {
+        let mut filter = test_filter();
+        filter.pending_call = Some(PendingCall::TokenRequest);
+        filter.awaiting_form_body = true;
+        assert!(matches!(filter.on_http_request_body(10, true), Action::Pause));
+        assert!(filter.awaiting_form_body);
+        assert!(filter.form_body.is_none());
+        assert!(matches!(filter.pending_call, Some(PendingCall::TokenRequest)));
+    }
+
+    #[test]
+    fn session_info_path() {
+        let mut filter = test_filter();
+        assert!(!filter.is_session_info("/oauth2/userinfo"));
+
+        filter.config = filter.config.clone().with_session_info(true, vec![], false);
+        assert!(filter.is_session_info("/oauth2/userinfo"));
+        assert!(!filter.is_session_info("/oauth2/userinfo/profile"));
+        assert!(!filter.is_session_info("/oauth2/userinfos"));
+    }
+
+    fn test_raw_config(extra: &str) -> RawFilterConfig {
+        serde_json::from_str(&format!("
+        {{
+        \"redirect_uri\": \"https://app.example.com/callback\",
+        \"cookie_name\": \"appsession\",
+        \"auth_cluster\": \"cluster_mock_auth\",
+        \"issuer\": \"https://issuer.example.com\",
+        \"token_uri\": \"https://issuer.example.com/token\",
+        \"auth_uri\": \"https://issuer.example.com/authorize\",
+        \"client_id\": \"myclient\",
+        \"scopes\": [\"email\"],
+        {}
+        }}", extra)).unwrap()
+    }
+
+    #[test]
+    fn providers_share_session_cookie() {
+        let raw_config = test_raw_config("\"providers\": [{
+            \"name\": \"acme\",
+            \"hosts\": [\"acme.example.com\"],
+            \"redirect_uri\": \"https://acme.example.com/callback\",
+            \"auth_cluster\": \"cluster_acme\",
+            \"issuer\": \"https://acme.example.com\",
+            \"token_uri\": \"https://acme.example.com/token\",
+            \"auth_uri\": \"https://acme.example.com/authorize\",
+            \"client_id\": \"acmeclient\",
+            \"cookie_name\": \"acmesession\",
+            \"scopes\": [\"email\"]
+        }]");
+        let root = OAuthRootContext::new(Some(raw_config.clone()));
+        assert!(root.is_ready());
+
+        let filter = root.oauth_filter(&raw_config, SharedCache::new());
+        assert_eq!(filter.config.provider(), Some(DEFAULT_PROVIDER));
+        let request = crate::oauth_client_types::Request::new(vec![
+            (":authority".to_string(), "acme.example.com".to_string()),
+            (":path".to_string(), "/".to_string()),
+            ("x-forwarded-proto".to_string(), "https".to_string()),
+        ]).unwrap();
+        let acme = filter.providers.as_ref().unwrap().select(&request, None).unwrap();
+        assert_eq!(acme.config().provider(), Some("acme"));
+        assert_eq!(acme.config().cookie_name(), "appsession");
+        assert_eq!(acme.config().auth_cluster(), "cluster_acme");
+
+        // A single provider is not tagged
+        let raw_config = test_raw_config("\"providers\": []");
+        let filter = OAuthRootContext::new(Some(raw_config.clone())).oauth_filter(&raw_config, SharedCache::new());
+        assert!(filter.providers.is_none());
+        assert_eq!(filter.config.provider(), None);
+    }
+
+    #[test]
+    fn not_ready_before_discovery() {
+        let raw_config = test_raw_config("\"mode\": \"oidc\"");
+        let root = OAuthRootContext::new(Some(raw_config));
+        assert!(!root.is_ready());
+        assert!(root.create_http_context(1).is_some());
+    }
+}
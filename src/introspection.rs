@@ -0,0 +1,105 @@
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::discovery::ConfigError;
+
+pub trait IntrospectionCache {
+    fn get_introspection(&self, token: &str) -> Option<CachedIntrospection>;
+    fn set_introspection(&mut self, token: &str, introspection: CachedIntrospection);
+}
+
+// Token introspection response, RFC 7662 section 2.2
+#[derive(Debug, Clone, Deserialize)]
+pub struct IntrospectionResponse {
+    active: bool,
+    #[serde(default)]
+    exp: Option<u64>,
+    // All members of the response, used as the claims of the token
+    #[serde(flatten)]
+    claims: Map<String, Value>,
+}
+
+impl IntrospectionResponse {
+    pub fn from_bytes(bytes: &[u8]) -> Result<IntrospectionResponse, ConfigError> {
+        serde_json::from_slice::<IntrospectionResponse>(bytes)
+            .map_err(|err| ConfigError::Parse(err.to_string()))
+    }
+}
+
+// Introspection result kept in the shared cache until it expires
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CachedIntrospection {
+    active: bool,
+    expires_at: u64,
+    claims: Map<String, Value>,
+}
+
+impl CachedIntrospection {
+    // Caches the response for the ttl in seconds, but never beyond the expiry of an active token.
+    // Active responses with an exp in the past are cached as inactive.
+    pub fn new(response: IntrospectionResponse, ttl: u64) -> CachedIntrospection {
+        let now = now();
+        let active = response.active && response.exp.is_none_or(|exp| exp > now);
+        let expires_at = match (active, response.exp) {
+            (true, Some(exp)) => (now + ttl).min(exp),
+            _ => now + ttl,
+        };
+        CachedIntrospection {
+            active,
+            expires_at,
+            claims: response.claims,
+        }
+    }
+
+    pub fn is_expired(&self) -> bool {
+        now() >= self.expires_at
+    }
+
+    // Returns true if the token was active. Expired results are not returned by the cache,
+    // except the result received for the current request.
+    pub fn is_active(&self) -> bool {
+        self.active
+    }
+
+    pub fn claims(&self) -> &Map<String, Value> {
+        &self.claims
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|now| now.as_secs())
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::introspection::{now, CachedIntrospection, IntrospectionResponse};
+
+    #[test]
+    fn cached_introspection() {
+        let body = format!(
+            "{{\"active\": true, \"scope\": \"read write\", \"sub\": \"tester\", \"exp\": {}}}",
+            now() + 30
+        );
+        let response = IntrospectionResponse::from_bytes(body.as_bytes()).unwrap();
+        let cached = CachedIntrospection::new(response, 300);
+        assert!(cached.is_active());
+        // Active results are not cached beyond the token expiry
+        assert!(cached.expires_at <= now() + 30);
+        assert_eq!(cached.claims().get("scope").unwrap(), "read write");
+
+        let response = IntrospectionResponse::from_bytes(b"{\"active\": false}").unwrap();
+        let cached = CachedIntrospection::new(response, 300);
+        assert!(!cached.is_active());
+        assert!(!cached.is_expired());
+
+        let expired = format!("{{\"active\": true, \"exp\": {}}}", now() - 10);
+        let response = IntrospectionResponse::from_bytes(expired.as_bytes()).unwrap();
+        let cached = CachedIntrospection::new(response, 300);
+        assert!(!cached.is_active());
+        assert!(!cached.is_expired());
+    }
+}
@@ -3,6 +3,8 @@ use serde::{Deserialize, Serialize};
 use std::time::Duration;
 use url::Url;
 
+use crate::error_page::{ErrorCode, StatusPage};
+
 type Headers = Vec<(String, String)>;
 
 // Struct for sending responses directly back to the end-user from the filter
@@ -13,17 +15,87 @@ pub struct DownStreamResponse {
 
     status: u64,
     body: String,
+    // Responses with a content type are sent as is, other responses are serialized as JSON
+    #[serde(skip)]
+    content_type: Option<&'static str>,
+    // Error of the response and the status page browsers get for it
+    #[serde(skip)]
+    error: Option<ErrorCode>,
+    #[serde(skip)]
+    page: Option<StatusPage>,
 }
 
+const CONTENT_TYPE_HTML: &str = "text/html; charset=utf-8";
+const CONTENT_TYPE_JSON: &str = "application/json";
+
 impl DownStreamResponse {
     pub fn new(headers: Headers, status: u64, body: String) -> Self {
         DownStreamResponse {
             headers,
             status,
             body,
+            content_type: None,
+            error: None,
+            page: None,
+        }
+    }
+
+    // Error response, rendered as the status page of the error or as JSON
+    pub fn error(headers: Headers, error: ErrorCode, message: String) -> Self {
+        DownStreamResponse {
+            headers,
+            status: error.status(),
+            body: message,
+            content_type: None,
+            error: Some(error),
+            page: Some(error.page()),
+        }
+    }
+
+    pub fn with_page(mut self, page: StatusPage) -> Self {
+        self.page = Some(page);
+        self
+    }
+
+    pub fn html(headers: Headers, status: u64, body: String) -> Self {
+        DownStreamResponse {
+            headers,
+            status,
+            body,
+            content_type: Some(CONTENT_TYPE_HTML),
+            error: None,
+            page: None,
         }
     }
 
+    // Response with a JSON document as the body
+    pub fn json(headers: Headers, status: u64, body: String) -> Self {
+        DownStreamResponse {
+            headers,
+            status,
+            body,
+            content_type: Some(CONTENT_TYPE_JSON),
+            error: None,
+            page: None,
+        }
+    }
+
+    pub fn content_type(&self) -> Option<&str> {
+        self.content_type
+    }
+
+    pub fn error_code(&self) -> Option<ErrorCode> {
+        self.error
+    }
+
+    pub fn page(&self) -> Option<StatusPage> {
+        self.page
+    }
+
+    pub fn body(&self) -> &str {
+        &self.body
+    }
+
     pub fn code(&self) -> u32 {
         self.status as u32
     }
@@ -72,6 +144,8 @@ pub struct SuccessfulResponse {
     pub token_type: Option<String>,
     pub scope: Option<String>,
     expires_in: Option<u64>,
+    #[serde(default)]
+    pub refresh_token: Option<String>,
 }
 
 impl SuccessfulResponse {
@@ -88,6 +162,7 @@ impl SuccessfulResponse {
             token_type,
             scope,
             expires_in,
+            refresh_token: None,
         }
     }
 
@@ -99,6 +174,20 @@ impl SuccessfulResponse {
     }
 }
 
+// Response from the pushed authorization request endpoint, RFC 9126 section 2.2
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+pub enum PushedAuthorizationResponse {
+    Error(ErrorResponse),
+    Success(PushedAuthorization),
+}
+
+#[derive(Debug, Deserialize)]
+pub struct PushedAuthorization {
+    pub request_uri: String,
+    pub expires_in: u64,
+}
+
 #[derive(Serialize)]
 pub struct ErrorBody {
     status: String,
@@ -108,14 +197,6 @@ pub struct ErrorBody {
 }
 
 impl ErrorBody {
-    pub fn new(status: String, error: String, description: Option<String>) -> ErrorBody {
-        ErrorBody {
-            status,
-            error,
-            error_description: description,
-        }
-    }
-
     pub fn serialize(&self) -> String {
         serde_json::to_string_pretty(&self).unwrap()
     }
@@ -213,6 +294,7 @@ mod tests {
             token_type: None,
             scope: Some("openid email profile".to_string()),
             expires_in: None,
+            refresh_token: None,
         });
         let serialized = serde_json::to_string(&test_success).unwrap();
         let deserialized: TokenResponse = serde_json::from_str(&serialized).unwrap();
@@ -107,4 +107,122 @@ pub mod overrides {
         Status::Ok
     }
 
+    #[no_mangle]
+    pub extern "C" fn proxy_get_shared_data(
+        key_data: *const u8,
+        key_size: usize,
+        return_value_data: *mut *mut u8,
+        return_value_size: *mut usize,
+        return_cas: *mut u32,
+    ) -> Status {
+        Status::Ok
+    }
+
+    #[no_mangle]
+    pub extern "C" fn proxy_set_shared_data(
+        key_data: *const u8,
+        key_size: usize,
+        value_data: *const u8,
+        value_size: usize,
+        cas: u32,
+    ) -> Status {
+        Status::Ok
+    }
+
+    #[no_mangle]
+    pub extern "C" fn proxy_replace_header_map_value(
+        map_type: MapType,
+        key_data: *const u8,
+        key_size: usize,
+        value_data: *const u8,
+        value_size: usize,
+    ) -> Status {
+        Status::Ok
+    }
+
+    #[no_mangle]
+    pub extern "C" fn proxy_remove_header_map_value(
+        map_type: MapType,
+        key_data: *const u8,
+        key_size: usize,
+    ) -> Status {
+        Status::Ok
+    }
+
+    #[no_mangle]
+    pub extern "C" fn proxy_send_local_response(
+        status_code: u32,
+        status_code_details_data: *const u8,
+        status_code_details_size: usize,
+        body_data: *const u8,
+        body_size: usize,
+        headers_data: *const u8,
+        headers_size: usize,
+        grpc_status: i32,
+    ) -> Status {
+        Status::Ok
+    }
+
+    #[no_mangle]
+    pub extern "C" fn proxy_continue_request() -> Status {
+        Status::Ok
+    }
+
+    #[no_mangle]
+    pub extern "C" fn proxy_continue_response() -> Status {
+        Status::Ok
+    }
+
+    #[no_mangle]
+    pub extern "C" fn proxy_clear_route_cache() -> Status {
+        Status::Ok
+    }
+
+    #[no_mangle]
+    pub extern "C" fn proxy_get_header_map_value(
+        map_type: MapType,
+        key_data: *const u8,
+        key_size: usize,
+        return_value_data: *mut *mut u8,
+        return_value_size: *mut usize,
+    ) -> Status {
+        Status::Ok
+    }
+
+    #[no_mangle]
+    pub extern "C" fn proxy_add_header_map_value(
+        map_type: MapType,
+        key_data: *const u8,
+        key_size: usize,
+        value_data: *const u8,
+        value_size: usize,
+    ) -> Status {
+        Status::Ok
+    }
+
+    #[no_mangle]
+    pub extern "C" fn proxy_set_header_map_pairs(
+        map_type: MapType,
+        map_data: *const u8,
+        map_size: usize,
+    ) -> Status {
+        Status::Ok
+    }
+
+    #[no_mangle]
+    pub extern "C" fn proxy_set_buffer_bytes(
+        buffer_type: BufferType,
+        start: usize,
+        size: usize,
+        buffer_data: *const u8,
+        buffer_size: usize,
+    ) -> Status {
+        Status::Ok
+    }
+
+    #[no_mangle]
+    pub extern "C" fn proxy_set_tick_period_milliseconds(period: u32) -> Status {
+        Status::Ok
+    }
+
 }
\ No newline at end of file
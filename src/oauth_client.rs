@@ -5,10 +5,18 @@ use oauth2::basic::BasicClient;
 use time::Duration;
 use url::{Url, ParseError};
 
+use crate::error_page::{ErrorCode, StatusPage};
 use crate::util;
-use crate::messages::{DownStreamResponse, TokenResponse};
-use crate::oauth_client_types::{Access, ClientError, Redirect, Request, TokenRequest};
-use crate::session::{Session, SessionType, SessionUpdate};
+use crate::redirect_loop;
+use crate::messages::{DownStreamResponse, PushedAuthorizationResponse, TokenResponse};
+use crate::oauth_client_types::{Access, CallbackError, ClientError, FrontendToken, Redirect, Request, TokenRequest};
+use crate::session::{self, AuthorizationTokens, Session, SessionType, SessionUpdate, ID_TOKEN_HEADER};
+use crate::authorization::{bearer_challenge, TokenClaims};
+use crate::introspection::{CachedIntrospection, IntrospectionCache, IntrospectionResponse};
+use serde_json::{json, Map, Value};
+use crate::token_exchange::{ExchangeTarget, ExchangedToken, TokenExchangeCache};
+use oauth2::http::header::AUTHORIZATION;
+use std::time::SystemTime;
 use crate::config::FilterConfig;
 use std::option::Option::Some;
 
@@ -16,7 +24,18 @@ use std::option::Option::Some;
 pub static CALLBACK_PATH: &str = "/callback";
 pub static START_PATH: &str  = "/auth";
 pub static SIGN_OUT_PATH: &str = "/sign_out";
+// Returns the current user of the session to front-ends
+pub static SESSION_INFO_PATH: &str = "/oauth2/userinfo";
+// Returns the access token of the session to front-ends, if enabled
+pub static FRONTEND_TOKEN_PATH: &str = "/oauth2/token";
 pub static CLIENT_PATHS: (&str, &str, &str) = (CALLBACK_PATH, START_PATH, SIGN_OUT_PATH);
+// Request headers owned by the filter, incoming values are always removed before forwarding upstream
+pub static UPSTREAM_HEADERS: [&str; 2] = ["Authorization", ID_TOKEN_HEADER];
+// Step-up requests started in a row before requests are denied, counted while the tokens of the previous one are recent
+const STEP_UP_ATTEMPTS: u32 = 2;
+const STEP_UP_WINDOW: std::time::Duration = std::time::Duration::from_secs(60);
+// Time a pending refresh of the session tokens blocks other refreshes, in case its response is lost
+const REFRESH_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(30);
 
 
 pub(crate) struct OAuthClient
@@ -55,126 +74,608 @@ impl OAuthClient
     pub fn sign_out(&self, session: Option<Session>) -> Result<(DownStreamResponse, SessionUpdate), ClientError> {
         match session {
             None => {
-                Err(ClientError::new(400, "No session to sign out from".to_string(), None))
+                Err(ClientError::new(ErrorCode::NoSession, "No session to sign out from".to_string()))
             }
             Some(session) => {
                 let header = session.clear_cookie_header_tuple(self.config.cookie_name());
-                Ok((DownStreamResponse::new(vec![header], 200, "Signed Out".to_string()), session.end_session()))
+                Ok((DownStreamResponse::new(vec![header], 200, "Signed Out".to_string()).with_page(StatusPage::SignedOut), session.end_session()))
             }
         }
     }
 
     // Starts a new Authentication Code flow. Note that it does not invalidate any already active sessions in the cache
     pub fn start(&self, request: Request) -> Result<(Redirect, SessionUpdate), ClientError> {
-        let (redirect_url, state, verifier) = self.authorization_server_redirect();
+        let extra_params = self.config.rule(request.url().path())
+            .map(|rule| rule.extra_params().clone())
+            .unwrap_or_default();
+        let (redirect_url, state, verifier) = self.authorization_server_redirect(&extra_params)?;
+
+        let update = SessionUpdate::auth_request(self.valid_url(request.url()).to_string(), state, verifier)
+            .silent(self.is_silent(&extra_params))
+            .provider(self.config.provider());
+        let mut headers = vec![update.set_cookie_header_tuple(self.config.cookie_name(), self.config.cookie_expire())];
+        headers.append(&mut self.authorization_cookie(&update));
+        headers.append(&mut self.attempt_cookie(&request));
+        Ok((Redirect::new(redirect_url, headers), update))
+    }
+
+    // Cookie counting the authorization request, if redirect loops are detected
+    fn attempt_cookie(&self, request: &Request) -> Vec<(String, String)> {
+        match self.config.redirect_loop_threshold() {
+            0 => vec![],
+            _ => vec![redirect_loop::attempt_cookie(request, self.config.redirect_loop_window())],
+        }
+    }
+
+    // Diagnostic page instead of another authorization request, if the browser keeps starting authorization
+    // requests without completing them
+    pub fn redirect_loop(&self, request: &Request) -> Option<DownStreamResponse> {
+        let threshold = self.config.redirect_loop_threshold();
+        if threshold == 0 {
+            return None
+        }
+        let (attempts, _) = redirect_loop::attempts(request, self.config.redirect_loop_window());
+        if attempts < threshold {
+            return None
+        }
+        log::warn!("Redirect loop detected, attempts={} path={}", attempts, request.url().path());
+        Some(redirect_loop::diagnostic_page(request, self.config.cookie_name(), attempts))
+    }
+
+    // Cookie identifying the session on the callback, if authorization responses are cross site form_post requests
+    fn authorization_cookie(&self, update: &SessionUpdate) -> Vec<(String, String)> {
+        match self.config.form_post() {
+            true => vec![update.authorization_cookie_header_tuple(self.config.cookie_name(), CALLBACK_PATH)],
+            false => vec![],
+        }
+    }
 
-        let update = SessionUpdate::auth_request(self.valid_url(request.url()).to_string(), state, verifier);
-        let header = update.set_cookie_header_tuple(self.config.cookie_name(), self.config.cookie_expire());
-        Ok((Redirect::new(redirect_url, vec![header]), update))
+    // Request pushing the parameters of the authorization redirect to the provider, if enabled
+    pub fn pushed_authorization_request(&self, redirect: &Redirect) -> Result<Option<TokenRequest>, ClientError> {
+        self.config.pushed_authorization_request(redirect.url())
+            .map(|request| request.map(TokenRequest::new))
+            .map_err(client_authentication_error)
+    }
+
+    // Redirect to the authorization endpoint with the request URI from the pushed authorization response
+    pub fn pushed_authorization_response(&self, body: &[u8], headers: Vec<(String, String)>) -> Result<Redirect, ClientError> {
+        match serde_json::from_slice::<PushedAuthorizationResponse>(body) {
+            Ok(PushedAuthorizationResponse::Success(response)) => {
+                log::debug!("Pushed authorization request expires in {} seconds", response.expires_in);
+                Ok(Redirect::new(self.config.pushed_authorization_url(&response.request_uri), headers))
+            }
+            Ok(PushedAuthorizationResponse::Error(error)) =>
+                Err(ClientError::new(ErrorCode::ProviderError, format!("Pushed authorization request error={}", error.to_error_body().serialize()))),
+            Err(error) =>
+                Err(ClientError::new(ErrorCode::ProviderError, format!("Invalid pushed authorization response, error={}", error))),
+        }
+    }
+
+    // Starts a step-up Authentication Code flow if the path requires a stronger or more recent authentication
+    // than the session has. The session keeps its tokens until the step-up succeeds.
+    pub fn step_up(&self, request: &Request, session: Option<&Session>) -> Result<Option<(Redirect, SessionUpdate)>, ClientError> {
+        let (rule, session) = match (self.config.rule(request.url().path()), session) {
+            (Some(rule), Some(session)) => (rule, session),
+            _ => return Ok(None),
+        };
+        let tokens = match &session.data {
+            SessionType::Tokens(tokens) if rule.requires_step_up(tokens) => tokens,
+            _ => return Ok(None),
+        };
+        log::info!("Path={} requires step-up authentication", request.url().path());
+
+        // Background requests can't follow the redirect
+        if !request.is_navigation() {
+            return Err(ClientError::new(ErrorCode::AuthenticationRequired, format!("Path={} requires step-up authentication", request.url().path())))
+        }
+        let attempts = if tokens.issued_within(STEP_UP_WINDOW) { tokens.step_ups() } else { 0 };
+        if attempts >= STEP_UP_ATTEMPTS {
+            return Err(ClientError::new(ErrorCode::AccessDenied, format!("Step-up authentication did not meet the requirements of path={}", request.url().path())))
+        }
+
+        let (redirect_url, state, verifier) = self.authorization_server_redirect(&rule.step_up_params())?;
+        let update = session.step_up(tokens, attempts + 1, self.valid_url(request.url()).to_string(), state, verifier);
+        let mut headers = self.authorization_cookie(&update);
+        headers.append(&mut self.attempt_cookie(request));
+        Ok(Some((Redirect::new(redirect_url, headers), update)))
     }
 
     pub fn callback(&self, request: Request, session: Option<Session>) -> Result<TokenRequest, ClientError>{
 
         let session = if let None = session {
-            return Err(ClientError::new(500, "No session for this request".to_string(), None));
+            return Err(ClientError::new(ErrorCode::NoSession, "No session for this request".to_string()));
         } else {  session.unwrap() };
 
-        let verifiers = if let SessionType::AuthorizationRequest(verifiers) = session.data {
+        let verifiers = if let Some(verifiers) = session.authorization_verifiers() {
             verifiers
-        } else { return Err(ClientError::new(500, "Session for authorization callback is not valid".to_string(), None))};
+        } else { return Err(ClientError::new(ErrorCode::InvalidSession, "Session for authorization callback is not valid".to_string()))};
 
         let code = request.authorization_code();
         let state = request.state();
         match (code, state) {
             (Some(code), Some(state)) => {
-                verifiers.validate_state(state);
-                let request = self.create_token_request(code, verifiers.code_verifiers());
+                if !verifiers.validate_state(state) {
+                    return Err(ClientError::new(ErrorCode::InvalidState, "Invalid state in authorization response".to_string()))
+                }
+                self.validate_iss_parameter(&request)?;
+                let request = self.create_token_request(code, verifiers.code_verifiers())?;
                 Ok(TokenRequest::new(request))
             }
             _ => {
                 log::warn!("Received request={:?} on callback endpoint without required parameters", request);
-                Err(ClientError::new(400,"Received request on callback endpoint without required parameters".to_string(), None))
+                Err(ClientError::new(ErrorCode::InvalidRequest, "Received request on callback endpoint without required parameters".to_string()))
             }
         }
     }
 
+    // Handles error responses from the authorization endpoint. Silent requests failing with login_required or
+    // interaction_required are retried interactively, login_required with prompt=login. Otherwise the pending authorization request ends and the end-user is sent to the
+    // configured error page or redirect.
+    pub fn callback_error(&self, request: &Request, session: Option<&Session>) -> Result<Option<(CallbackError, SessionUpdate)>, ClientError> {
+        let error = match request.authorization_error() {
+            None => return Ok(None),
+            Some(error) => error,
+        };
+        let session = session.ok_or_else(|| ClientError::new(ErrorCode::NoSession, "No session for this request".to_string()))?;
+        let verifiers = session.authorization_verifiers()
+            .ok_or_else(|| ClientError::new(ErrorCode::InvalidSession, "Session for authorization callback is not valid".to_string()))?;
+        if !request.state().is_some_and(|state| verifiers.validate_state(state)) {
+            return Err(ClientError::new(ErrorCode::InvalidState, "Invalid state in authorization error response".to_string()))
+        }
+        self.validate_iss_parameter(request)?;
+        log::info!("Authorization error response, error={} description={:?}", error.error, error.error_description);
+
+        let needs_interaction = error.error == "login_required" || error.error == "interaction_required";
+        if needs_interaction && verifiers.is_silent() {
+            let request_url = verifiers.request_url();
+            let path = Url::parse(&request_url).map(|url| url.path().to_string()).unwrap_or_default();
+            let mut params = match (&session.data, self.config.rule(&path)) {
+                (SessionType::Tokens(_), Some(rule)) => rule.step_up_params(),
+                (_, Some(rule)) => rule.extra_params().clone(),
+                (_, None) => vec![],
+            };
+            params.retain(|(name, _)| name != "prompt");
+            if error.error == "login_required" {
+                params.push(("prompt".to_string(), "login".to_string()));
+            }
+
+            let (redirect_url, state, verifier) = self.authorization_server_redirect(&params)?;
+            let update = session.retry_authorization(request_url, state, verifier);
+            let headers = self.authorization_cookie(&update);
+            return Ok(Some((CallbackError::Retry(Redirect::new(redirect_url, headers)), update)))
+        }
+
+        // Sessions without tokens are ended, so their cookie is cleared
+        let headers = match &session.data {
+            SessionType::Tokens(_) => vec![],
+            _ => vec![session.clear_cookie_header_tuple(self.config.cookie_name())],
+        };
+        let code = if error.error == "access_denied" { ErrorCode::AccessDenied } else { ErrorCode::AuthorizationFailed };
+        let response = match (self.config.error_redirect_uri(), self.config.error_page()) {
+            (Some(uri), _) => {
+                let mut url = uri.clone();
+                {
+                    let mut query = url.query_pairs_mut();
+                    query.append_pair("error", &error.error);
+                    if let Some(description) = &error.error_description {
+                        query.append_pair("error_description", description);
+                    }
+                    if let Some(error_uri) = &error.error_uri {
+                        query.append_pair("error_uri", error_uri);
+                    }
+                }
+                CallbackError::Redirect(Redirect::new(url, headers))
+            }
+            (None, Some(page)) => CallbackError::Response(DownStreamResponse::html(
+                headers,
+                code.status(),
+                page.replace("{error}", &util::escape_html(&error.error))
+                    .replace("{error_description}", &util::escape_html(error.error_description.as_deref().unwrap_or_default())),
+            )),
+            (None, None) => CallbackError::Response(DownStreamResponse::error(
+                headers,
+                code,
+                format!("Authorization error={}, description={}", error.error, error.error_description.unwrap_or_default()),
+            )),
+        };
+        Ok(Some((response, session.cancel_authorization())))
+    }
+
     pub fn token_response(&self, response: TokenResponse, session: Option<Session>) -> Result<(Redirect, SessionUpdate), ClientError>{
         match response {
             TokenResponse::Error(error) =>
-                Err(ClientError::new(500, format!("Token endpoint error={}", error.to_error_body().serialize()), None)),
+                Err(ClientError::new(ErrorCode::ProviderError, format!("Token endpoint error={}", error.to_error_body().serialize()))),
             TokenResponse::Success(response) => {
                 let access_token = response.access_token.clone();
                 let id_token = response.id_token.clone();
                 let expires_in = response.expires_in();
-                let refresh_token = response.id_token.clone();
+                let refresh_token = response.refresh_token.clone();
 
                 // validate id token
                 if let Some(id_token) = &id_token {
                     match self.config.validate_token(id_token) {
                         Ok(_) => {}
                         Err(error) => {
-                            return Err(ClientError::new(500, error.to_string(), None))
+                            return Err(ClientError::new(ErrorCode::InvalidToken, error.to_string()))
                         }
                     }
                 }
                 let session = if let Some(session) = session {
                     session
                 } else {
-                    return Err(ClientError::new(500, "Token response handling error, no session for the response".to_string(), None));
+                    return Err(ClientError::new(ErrorCode::NoSession, "Token response handling error, no session for the response".to_string()));
                 };
 
-                match &session.data {
-                    SessionType::AuthorizationRequest(verifiers) => {
+                // The authorization completed, so earlier attempts are not part of a redirect loop
+                let mut headers = if self.config.redirect_loop_threshold() > 0 {
+                    vec![redirect_loop::clear_cookie()]
+                } else {
+                    vec![]
+                };
+                if self.config.form_post() {
+                    headers.push(session::clear_authorization_cookie_header_tuple(self.config.cookie_name(), CALLBACK_PATH));
+                }
+                match session.authorization_verifiers() {
+                    Some(verifiers) => {
                         Ok((Redirect::new(
                             verifiers.request_url().parse().unwrap(),
-                            vec![]),
+                            headers).with_replay(verifiers.saved_post().cloned()),
                          session.token_response(access_token, expires_in, id_token, refresh_token)))
                     }
-                    _ => Err(ClientError::new(500, format!("Token response handling error, session does not contain authorization request verifiers, session type={:?}", session.data.type_id()), None)),
+                    None => Err(ClientError::new(ErrorCode::InvalidSession, format!("Token response handling error, session does not contain authorization request verifiers, session type={:?}", session.data.type_id()))),
                 }
             }
         }
     }
 
-    pub fn proxy(&self, session: Option<Session>) -> Result<Access, ClientError>{
+    pub fn proxy(&self, request: &Request, session: Option<Session>) -> Result<Access, ClientError>{
         match session {
             None => Ok(Access::UnAuthenticated),
             Some(session) => {
-                match session.data {
+                match &session.data {
                     SessionType::Tokens(tokens) => {
                         match tokens.is_access_token_valid() {
                             Ok(is_valid) => {
                                 match is_valid {
                                     true => Ok(Access::Allowed(tokens.upstream_headers_tuple())),
                                     false => {
-                                        // TODO use refresh token if valid
-                                        Ok(Access::Denied(DownStreamResponse::new(vec![], 403, "Tokens expired".to_string())))
+                                        // Refresh tokens may be rotated, so concurrent requests don't refresh the tokens again
+                                        if let Some(refresh_token) = tokens.refresh_token() {
+                                            if tokens.is_refreshing(REFRESH_TIMEOUT) {
+                                                return Err(ClientError::new(ErrorCode::RefreshInProgress, "Tokens are being refreshed".to_string()))
+                                            }
+                                            return self.config.refresh_request(refresh_token)
+                                                .map(|request| Access::Refresh(TokenRequest::new(request), Box::new(session.refreshing(tokens))))
+                                                .map_err(client_authentication_error)
+                                        }
+                                        if let Some((redirect, update)) = self.silent_renewal(request, &session, tokens)? {
+                                            return Ok(Access::Renew(redirect, Box::new(update)))
+                                        }
+                                        // POST requests with a body that can be replayed log in again, so the data is not lost
+                                        if self.config.post_replay().is_some_and(|post_replay| post_replay.accepts(request)) {
+                                            return Ok(Access::UnAuthenticated)
+                                        }
+                                        Ok(Access::Denied(DownStreamResponse::error(vec![], ErrorCode::AccessDenied, "Tokens expired".to_string())))
                                     }
                                 }
                             }
-                            Err(err) => Err(ClientError::new(500, format!("Error occurred while getting system time, error={}", err), None)),
+                            Err(err) => Err(ClientError::new(ErrorCode::InternalError, format!("Error occurred while getting system time, error={}", err))),
                         }
                     }
-                    _ => Ok(Access::Denied(DownStreamResponse::new(vec![], 403, "UnAuthorized session".to_string())))
+                    _ => Ok(Access::Denied(DownStreamResponse::error(vec![], ErrorCode::AccessDenied, "UnAuthorized session".to_string())))
+                }
+            }
+        }
+    }
+
+    // Renews an expired session with a silent authorization request (prompt=none), if enabled. Only navigations
+    // can follow the redirect, and the attempts are counted so failing renewals don't loop.
+    fn silent_renewal(&self, request: &Request, session: &Session, tokens: &AuthorizationTokens) -> Result<Option<(Redirect, SessionUpdate)>, ClientError> {
+        if !request.is_navigation() || tokens.silent_renewals() >= self.config.silent_renewal_attempts() {
+            return Ok(None)
+        }
+        let mut params = self.config.rule(request.url().path())
+            .map(|rule| rule.extra_params().clone())
+            .unwrap_or_default();
+        params.retain(|(name, _)| name != "prompt");
+        params.push(("prompt".to_string(), "none".to_string()));
+        log::info!("Renewing expired session, attempt={}", tokens.silent_renewals() + 1);
+
+        let (redirect_url, state, verifier) = self.authorization_server_redirect(&params)?;
+        let update = session.silent_renewal(tokens, self.valid_url(request.url()).to_string(), state, verifier);
+        let mut headers = self.authorization_cookie(&update);
+        headers.append(&mut self.attempt_cookie(request));
+        Ok(Some((Redirect::new(redirect_url, headers), update)))
+    }
+
+    // Authenticates requests in bearer mode by validating the access token in the Authorization header.
+    // JWT access tokens are validated locally, opaque tokens through the cached introspection result.
+    pub fn bearer(&self, request: &Request, cache: &dyn IntrospectionCache) -> Access {
+        let rule = self.config.rule(request.url().path());
+        let token = match request.bearer_token() {
+            None => return Access::Denied(bearer_challenge(401, None, "Bearer token required", &[])),
+            Some(token) => token,
+        };
+
+        let claims = if util::jwt_claims(token).is_some() {
+            let audiences = rule.map(|rule| rule.audiences().clone()).unwrap_or_default();
+            if let Err(error) = self.config.validate_access_token(token, &audiences) {
+                log::info!("Invalid bearer token for path={}, error={}", request.url().path(), error);
+                return Access::Denied(bearer_challenge(401, Some("invalid_token"), &error.to_string(), &[]));
+            }
+            let tokens = AuthorizationTokens::new(SystemTime::now(), token.to_string(), None, None, None);
+            TokenClaims::from_tokens(&tokens)
+        } else if self.config.introspection_enabled() {
+            match cache.get_introspection(token) {
+                None => return Access::Introspect(token.to_string()),
+                Some(introspection) if introspection.is_active() =>
+                    TokenClaims::new(Map::new(), introspection.claims().clone()),
+                Some(_) => return Access::Denied(bearer_challenge(401, Some("invalid_token"), "The access token is not active", &[])),
+            }
+        } else {
+            return Access::Denied(bearer_challenge(401, Some("invalid_token"), "Opaque access tokens require introspection", &[]));
+        };
+
+        if let Some(rule) = rule.and_then(|rule| rule.authorization()) {
+            let granted = claims.scopes();
+            if !rule.scopes().iter().all(|scope| granted.contains(scope)) {
+                return Access::Denied(bearer_challenge(403, Some("insufficient_scope"), "Insufficient scope for the request", rule.scopes()));
+            }
+            if let Err(reason) = rule.evaluate(&claims) {
+                return Access::Denied(crate::authorization::access_denied(reason));
+            }
+        }
+        Access::Allowed(vec![(AUTHORIZATION.to_string(), format!("Bearer {}", token))])
+    }
+
+    // Checks that the session access token is still active when introspection of session tokens is enabled
+    pub fn introspect_session(&self, session: Option<&Session>, cache: &dyn IntrospectionCache) -> Option<Access> {
+        if !self.config.introspect_session_tokens() {
+            return None;
+        }
+        let tokens = match session.map(|session| &session.data) {
+            Some(SessionType::Tokens(tokens)) => tokens,
+            _ => return None,
+        };
+        match cache.get_introspection(tokens.access_token()) {
+            None => Some(Access::Introspect(tokens.access_token().to_string())),
+            Some(introspection) if introspection.is_active() => None,
+            Some(_) => Some(Access::Denied(DownStreamResponse::error(vec![], ErrorCode::AuthenticationRequired, "The session access token is not active".to_string()))),
+        }
+    }
+
+    // Request to the introspection endpoint for the token
+    pub fn introspection_request(&self, token: &str) -> Result<TokenRequest, ClientError> {
+        match self.config.introspection_request(token) {
+            Ok(Some(request)) => Ok(TokenRequest::new(request)),
+            Ok(None) => Err(ClientError::new(ErrorCode::ConfigurationError, "Token introspection is not configured".to_string())),
+            Err(error) => Err(client_authentication_error(error)),
+        }
+    }
+
+    // Caches the introspection response for the token
+    pub fn introspection_response(&self, token: &str, body: &[u8], cache: &mut dyn IntrospectionCache) -> Result<(), ClientError> {
+        let response = IntrospectionResponse::from_bytes(body)
+            .map_err(|error| ClientError::new(ErrorCode::ProviderError, format!("Invalid introspection response, error={}", error)))?;
+        cache.set_introspection(token, CachedIntrospection::new(response, self.config.introspection_cache_ttl()));
+        Ok(())
+    }
+
+    // Allowed requests to token exchange target hosts are sent with a token exchanged for the audience of the
+    // target instead of the upstream headers. The subject token is the access token of the session, or the
+    // validated bearer token of the request without a session.
+    pub fn token_exchange(&self, request: &Request, session: Option<&Session>, headers: Vec<(String, String)>, cache: &dyn TokenExchangeCache) -> Access {
+        let target = match request.url().host_str().and_then(|host| self.config.exchange_target(host)) {
+            Some(target) => target,
+            None => return Access::Allowed(headers),
+        };
+        let subject_token = match (session.map(|session| &session.data), request.bearer_token()) {
+            (Some(SessionType::Tokens(tokens)), _) => tokens.access_token(),
+            (None, Some(token)) => token,
+            _ => return Access::Denied(bearer_challenge(401, None, "Subject token required for token exchange", &[])),
+        };
+
+        match cache.get_exchanged_token(subject_token, target.audience()) {
+            Some(token) => Access::Allowed(vec![(AUTHORIZATION.to_string(), format!("Bearer {}", token.access_token()))]),
+            None => Access::Exchange(subject_token.to_string(), target.clone()),
+        }
+    }
+
+    // Request for the userinfo of the tokens in the update, if enabled
+    pub fn userinfo_request(&self, update: &SessionUpdate) -> Option<TokenRequest> {
+        let tokens = update.tokens()?;
+        self.config.userinfo_request(tokens.access_token()).map(TokenRequest::new)
+    }
+
+    // Stores the selected userinfo claims with the tokens, the sub claim must match the ID token
+    pub fn userinfo_response(&self, body: &[u8], update: SessionUpdate) -> Result<SessionUpdate, ClientError> {
+        let claims = serde_json::from_slice::<Map<String, Value>>(body)
+            .map_err(|err| ClientError::new(ErrorCode::ProviderError, format!("Invalid userinfo response, error={}", err)))?;
+        let id_token_sub = update.tokens()
+            .and_then(|tokens| tokens.id_token())
+            .and_then(util::jwt_claims)
+            .and_then(|id_token_claims| id_token_claims.get("sub").cloned());
+        if let Some(sub) = id_token_sub {
+            if claims.get("sub") != Some(&sub) {
+                return Err(ClientError::new(ErrorCode::ProviderError, "Userinfo sub does not match the ID token".to_string()));
+            }
+        }
+        let selected = self.config.userinfo_claims();
+        let claims = claims.into_iter()
+            .filter(|(name, _)| selected.is_empty() || selected.contains(name))
+            .collect();
+        Ok(update.userinfo(claims))
+    }
+
+    pub fn token_exchange_request(&self, subject_token: &str, target: &ExchangeTarget) -> Result<TokenRequest, ClientError> {
+        self.config.token_exchange_request(subject_token, target)
+            .map(TokenRequest::new)
+            .map_err(client_authentication_error)
+    }
+
+    // Caches the token from the token exchange response
+    pub fn token_exchange_response(&self, subject_token: &str, target: &ExchangeTarget, body: &[u8], cache: &mut dyn TokenExchangeCache) -> Result<(), ClientError> {
+        match serde_json::from_slice::<TokenResponse>(body) {
+            Ok(TokenResponse::Success(response)) => {
+                let token = ExchangedToken::new(response.access_token.clone(), response.expires_in());
+                cache.set_exchanged_token(subject_token, target.audience(), token);
+                Ok(())
+            }
+            Ok(TokenResponse::Error(error)) =>
+                Err(ClientError::new(ErrorCode::ProviderError, format!("Token exchange error={}", error.to_error_body().serialize()))),
+            Err(error) =>
+                Err(ClientError::new(ErrorCode::ProviderError, format!("Invalid token exchange response, error={}", error))),
+        }
+    }
+
+    // Subject, selected claims, token expiry and authentication time of the session.
+    // The raw tokens are only included if enabled.
+    pub fn session_info(&self, session: Option<&Session>) -> DownStreamResponse {
+        let tokens = match session.map(|session| &session.data) {
+            Some(SessionType::Tokens(tokens)) if tokens.is_access_token_valid().unwrap_or(false) => tokens,
+            _ => return DownStreamResponse::error(vec![], ErrorCode::AuthenticationRequired, "Authentication required".to_string()),
+        };
+        let claims = TokenClaims::from_tokens(tokens);
+        let selected: Map<String, Value> = self.config.session_info_claims().iter()
+            .filter_map(|name| claims.get(name).map(|value| (name.clone(), value.clone())))
+            .collect();
+        let mut body = json!({
+            "sub": claims.get("sub"),
+            "claims": selected,
+            "expires_at": tokens.expires_at(),
+            "auth_time": tokens.auth_time(),
+        });
+        if self.config.session_info_tokens() {
+            body["access_token"] = json!(tokens.access_token());
+            body["id_token"] = json!(tokens.id_token());
+        }
+        DownStreamResponse::json(
+            vec![("Cache-Control".to_string(), "no-store".to_string())],
+            200,
+            body.to_string(),
+        )
+    }
+
+    // Access token of the session for front-ends, refreshed first if it is close to expiry.
+    // Requests must carry the custom header and come from an allowed origin.
+    pub fn frontend_token(&self, request: &Request, session: Option<&Session>) -> Result<FrontendToken, ClientError> {
+        let bff = match self.config.bff() {
+            None => return Err(ClientError::new(ErrorCode::NotFound, "Not found".to_string())),
+            Some(bff) => bff,
+        };
+        if let Err(reason) = bff.validate_request(request) {
+            log::warn!("Rejected front-end token request, reason={}", reason);
+            return Err(ClientError::new(ErrorCode::AccessDenied, format!("Forbidden, {}", reason)));
+        }
+        let (session, tokens) = match session.map(|session| (session, &session.data)) {
+            Some((session, SessionType::Tokens(tokens))) => (session, tokens),
+            _ => return Err(ClientError::new(ErrorCode::AuthenticationRequired, "Authentication required".to_string())),
+        };
+        if tokens.expires_within(bff.refresh_before()) {
+            match tokens.refresh_token() {
+                // Refresh tokens may be rotated, so concurrent requests don't refresh the tokens again
+                Some(refresh_token) if !tokens.is_refreshing(REFRESH_TIMEOUT) => {
+                    return self.config.refresh_request(refresh_token)
+                        .map(|request| FrontendToken::Refresh(TokenRequest::new(request), Box::new(session.refreshing(tokens))))
+                        .map_err(client_authentication_error);
+                }
+                Some(_) if !tokens.is_access_token_valid().unwrap_or(false) => {
+                    return Err(ClientError::new(ErrorCode::RefreshInProgress, "Tokens are being refreshed".to_string()));
+                }
+                None if !tokens.is_access_token_valid().unwrap_or(false) => {
+                    return Err(ClientError::new(ErrorCode::TokensExpired, "Tokens expired".to_string()));
                 }
+                _ => {}
             }
         }
+        Ok(FrontendToken::Token(self.frontend_token_response(tokens)))
+    }
+
+    pub fn frontend_token_response(&self, tokens: &AuthorizationTokens) -> DownStreamResponse {
+        let body = json!({
+            "access_token": tokens.access_token(),
+            "token_type": "Bearer",
+            "expires_at": tokens.expires_at(),
+        });
+        DownStreamResponse::json(
+            vec![("Cache-Control".to_string(), "no-store".to_string())],
+            200,
+            body.to_string(),
+        )
     }
 
-    fn authorization_server_redirect(&self) -> (Url, String, String) {
+    // Replaces the tokens of the session with the refreshed tokens
+    pub fn refresh_response(&self, body: &[u8], session: Option<&Session>) -> Result<SessionUpdate, ClientError> {
+        let response = match serde_json::from_slice::<TokenResponse>(body) {
+            Ok(TokenResponse::Success(response)) => response,
+            Ok(TokenResponse::Error(error)) =>
+                return Err(ClientError::new(ErrorCode::RefreshFailed, format!("Token refresh failed, error={}", error.to_error_body().serialize()))),
+            Err(error) =>
+                return Err(ClientError::new(ErrorCode::ProviderError, format!("Invalid refresh token response, error={}", error))),
+        };
+        if let Some(id_token) = &response.id_token {
+            self.config.validate_token(id_token)
+                .map_err(|error| ClientError::new(ErrorCode::InternalError, error.to_string()))?;
+        }
+        session
+            .and_then(|session| session.refreshed(
+                response.access_token.clone(),
+                response.expires_in(),
+                response.id_token.clone(),
+                response.refresh_token.clone(),
+            ))
+            .ok_or_else(|| ClientError::new(ErrorCode::AuthenticationRequired, "Authentication required".to_string()))
+    }
+
+    // Evaluates the authorization rule for the request path against the claims of the session tokens
+    pub fn authorize(&self, request: &Request, session: Option<&Session>) -> Result<(), DownStreamResponse> {
+        let rule = match self.config.rule(request.url().path()).and_then(|rule| rule.authorization()) {
+            None => return Ok(()),
+            Some(rule) => rule,
+        };
+        let result = match session.map(|session| &session.data) {
+            Some(SessionType::Tokens(tokens)) => rule.evaluate(&TokenClaims::from_tokens(tokens)),
+            _ => Err("session has no tokens".to_string()),
+        };
+        result.map_err(|reason| {
+            log::info!("Request to path={} denied by authorization rule, reason={}", request.url().path(), reason);
+            crate::authorization::access_denied(reason)
+        })
+    }
+
+    // Mix-up defense, the authorization response must come from the issuer of this filter
+    fn validate_iss_parameter(&self, request: &Request) -> Result<(), ClientError> {
+        self.config.validate_iss_parameter(request.param("iss").as_deref())
+            .map_err(|reason| {
+                log::warn!("Rejected authorization response, reason={}", reason);
+                ClientError::new(ErrorCode::InvalidRequest, format!("Invalid authorization response, {}", reason))
+            })
+    }
+
+    // Returns true if the authorization request is sent with prompt=none, parameters override configured ones
+    fn is_silent(&self, extra_params: &[(String, String)]) -> bool {
+        extra_params.iter()
+            .chain(self.config.extra_authorization_params().iter())
+            .find(|(name, _)| name == "prompt")
+            .is_some_and(|(_, value)| value == "none")
+    }
+
+    fn authorization_server_redirect(&self, extra_params: &[(String, String)]) -> Result<(Url, String, String), ClientError> {
         let verifier = util::new_random_verifier(32);
         let pkce_challenge =
             PkceCodeChallenge::from_code_verifier_sha256(&verifier);
         let (auth_url, csrf_token) =
-            self.config.authorization_url(pkce_challenge);
+            self.config.authorization_url(pkce_challenge, extra_params)
+                .map_err(|error| ClientError::new(ErrorCode::InternalError, format!("Could not create authorization request, error={}", error)))?;
 
         let state = csrf_token.secret().clone();
 
-        (auth_url, state, verifier.secret().to_string())
+        Ok((auth_url, state, verifier.secret().to_string()))
     }
 
-    fn create_token_request(&self, code: String, code_verifier: Option<String>) -> HttpRequest {
+    fn create_token_request(&self, code: String, code_verifier: Option<String>) -> Result<HttpRequest, ClientError> {
         self.config.token_request(code, code_verifier)
+            .map_err(client_authentication_error)
     }
 
     fn valid_url(&self, url: &Url) -> Url {
@@ -190,6 +691,10 @@ impl OAuthClient
     }
 }
 
+fn client_authentication_error(error: jwt_simple::Error) -> ClientError {
+    ClientError::new(ErrorCode::ConfigurationError, format!("Could not authenticate request to the authorization server, error={}", error))
+}
+
 
 impl ClientConfig {
 
@@ -234,10 +739,15 @@ mod tests {
 
     use super::*;
     use crate::config::{FilterConfig};
+    use crate::authorization::{AuthorizationRule, PathRule};
+    use crate::cache::SharedCache;
+    use crate::bff::BffConfig;
+    use crate::post_replay::PostReplayConfig;
+    use crate::token_exchange::ExchangeTarget;
     use time::{NumericalDuration, NumericalStdDurationShort};
     use crate::discovery::{JsonWebKeySet, ProviderMetadata};
     use jsonwebkey::{JsonWebKey, Key, RsaPublic, PublicExponent, ByteVec};
-    use jwt_simple::prelude::{RS256PublicKey, RS256KeyPair, RSAKeyPairLike, JWTClaims, Claims};
+    use jwt_simple::prelude::{RS256PublicKey, RS256KeyPair, RSAKeyPairLike, RSAPublicKeyLike, JWTClaims, Claims};
     use jwt_simple::claims::NoCustomClaims;
     use jsonwebkey::Algorithm::RS256;
 
@@ -256,13 +766,22 @@ mod tests {
             vec![])
     }
 
-    fn test_oidc_config(keypair: RS256KeyPair) -> FilterConfig {
+    fn test_keypair() -> RS256KeyPair {
+        let raw_der = "MIIEvQIBADALBgkqhkiG9w0BAQEEggSpMIIEpQIBAAKCAQEAwFV/hfsq9vZtWG4AIx6CByy+vSHUceLxch9+W7AYLEqHEjazuJrznetYFAJnqfujbP9VRACHCDbCx7a3Rj2RRtzmgDSdQvn5g7lYZ3Ljk2rr8dEU3x+ST+i4Ggjooa2t1Z+ukQkt0AJT4ViZopP1CzvOzwSFnCfFAZozun9nSWo7hwICVW2iA3QT3AxKPB5T2G+bi0oQ6Hz20mWQ1xYhhLzjv2uYYptf6R2JHvam+UQesKfHn/72zYGeUCHJKFYZ9WRGn8VdyDhjYPO/B4sTcIsfNadUMWvl0j9F7RFkUcgPqSbBz2zRYnetV8M81o0x81ZuJoQM+KgmcJR6ldRkYQIDAQABAoIBAQCGv5/PY2/vhCiJis+nyyj4E94yLbBkdcYI2Y2yeQYGidRl61Mwo8bIPekAY8ry6WzO4XiAYwo3s/3048s1d87/YaZP7gy7rtyj78fC+lf8oS1axswjoj/kojvRaapqE381qmEoFdE16VuRBnzkzVohm65BlX5yL96zR7C0GaxoXr8eoPdgxecHDr3ikVykGYzmyOOszoPD8XIXdD8eXOT43LNk8HNmV6mGANrG/52leCH6H3o8ofbcaJSDXj4cFW9CZccuUo0lcc/C6gfaY8/iOHiFBI5kDEZ1kqlOM5fiWLV6z/moQxac53yXls/pw4B0LmuwLU1+5kBV4N9KjUUdAoGBAMZE9G8/uAsooVKgfzEGztKhuX1vIP8hFPUeZ80ITr88Ljvk3PPcsZAUA2F4ls64P5s/i3pcGv4zGUxxCMO3xPu8e8oqkMOpxhm+5mzLc2uuV551Lfl1FDPV4dVKG518ubrCdOgcslvQf4b1VPsGvWH0pjm64DKobVtbftyOU0p7AoGBAPhWHmx5zKxMZ0l3/nbf2RHNQ0PRpes7mVPXmxYMf/Fi1iTAwpft3OwHmmYNS9pYJWgR96QJUYy0H/pWCqrNX7pqdSRkVd/GH/bD/ctxtsu8vDXnQcZ++YQtnOa3N2whSWkru93yBHsF6WZhQINBBqWBb1hwyUtSjlowQiR4LrPTAoGAMFEvjEjn7fg50CwCswzorBXzYSkKzoHeXJnLTAEBR8M/tSLh4Z/fua/W3xMHFVt7TLcgo3H8tgP331QQgbmwDHTzaeITo0slVrLZ7ptqBkzXV2tRB+DLSpKN1W+Y6Tky/dtTqBkOMifOXDI/QmlzD4MSWzE6X74B4Gicst0QWS8CgYEAlLOkthnc6BM8Ce8zTWVO6uz5aUPBUXE3p8/ECtjjR9yCFDHkk+sXzbHjz1YI5K7Bf2a63dvCnDlDqLga3RnrR4n1qRv3m+5cN3w2nbry1V6naLOYXE6uBGr3t+Nf6XypI7PPY+BGtI/eqbJ0WeP3IeW2kcO/lT3TBcg94u6gMIcCgYEApXGp3yaUm7gmfn+mICgfxDkcSEVZe9GH/gZ1MNUsLBWthqOFJribC7pjbchvgAm9/oBgv5y3NH8TkdHP9CoKGWU+cPGc9SSjngQ1J7oTa3s67fDxb7mR/9WCYK1Bd46PD81NQmUHKsMdhSIitD9oprueup6gE2T3jvk67+vsAjE=";
+        RS256KeyPair::from_der(base64::decode(raw_der).unwrap().as_slice()).unwrap()
+    }
+
+    fn test_jwks(keypair: &RS256KeyPair) -> JsonWebKeySet {
         let public = keypair.public_key();
         let (n, e) = rsa_der::public_key_from_der(public.to_der().unwrap().as_slice()).unwrap();
         let jwk = jsonwebkey::JsonWebKey::new(Key::RSA {
             public: RsaPublic { e: PublicExponent {}, n: ByteVec::from(n) },
             private: None
         });
+        JsonWebKeySet::new(vec![jwk])
+    }
+
+    fn test_oidc_config(keypair: RS256KeyPair) -> FilterConfig {
 
         // let jwks = "{
         //     \"keys\" : [ {
@@ -286,7 +805,7 @@ mod tests {
             vec!["openid".to_string()],
             Duration::hours(1),
             vec![],
-            JsonWebKeySet::new(vec![jwk]),
+            test_jwks(&keypair),
             ProviderMetadata::new(
                 "https://issuer".parse().unwrap(),
                 "https://redirect".parse().unwrap(),
@@ -425,6 +944,52 @@ mod tests {
         assert!(matches!(update.create_session().data, SessionType::AuthorizationRequest(..)));
     }
 
+    #[test]
+    fn redirect_loop() {
+        let client = crate::oauth_client::OAuthClient::new(test_oauth_config().with_redirect_loop(2, 60)).unwrap();
+        assert!(client.redirect_loop(&test_request()).is_none());
+
+        // Each authorization request counts an attempt in the cookie sent back by the browser
+        let (redirect, _) = client.start(test_request()).unwrap();
+        let cookie = redirect.headers()[1].1.split(';').next().unwrap().to_string();
+        assert!(cookie.starts_with("oauthAttempts=1."));
+        let request = Request::new( vec![
+            ("x-forwarded-proto".to_string(), "http".to_string()),
+            (":authority".to_string(), "localhost".to_string()),
+            (":path".to_string(), "/test-path".to_string()),
+            ("cookie".to_string(), cookie.replace("oauthAttempts=1.", "oauthAttempts=2.")),
+        ]).unwrap();
+        let response = client.redirect_loop(&request).unwrap();
+        assert_eq!(response.code(), 400);
+
+        // Step-ups are counted as well
+        let rule = PathRule::new("/admin", None).with_step_up(vec!["Level4".to_string()], None);
+        let step_up_client = crate::oauth_client::OAuthClient::new(
+            test_oauth_config().with_rules(vec![rule]).with_redirect_loop(2, 60)
+        ).unwrap();
+        let admin_request = Request::new( vec![
+            ("x-forwarded-proto".to_string(), "http".to_string()),
+            (":authority".to_string(), "localhost".to_string()),
+            (":path".to_string(), "/admin/users".to_string()),
+            (":method".to_string(), "GET".to_string()),
+            ("sec-fetch-mode".to_string(), "navigate".to_string()),
+        ]).unwrap();
+        let (_, session) = test_authorized_request();
+        let (redirect, _) = step_up_client.step_up(&admin_request, Some(&session)).unwrap().unwrap();
+        assert!(redirect.headers().iter().any(|(_, value)| value.starts_with("oauthAttempts=1.")));
+
+        // Detection is off by default
+        let (redirect, _) = test_oauth_client().start(test_request()).unwrap();
+        assert!(!redirect.headers().iter().any(|(_, value)| value.starts_with("oauthAttempts=")));
+
+        // Completed authorizations clear the attempts
+        let (_, callback_session) = test_callback_session();
+        let keypair = test_keypair();
+        let client = crate::oauth_client::OAuthClient::new(test_oidc_config(keypair.clone()).with_redirect_loop(2, 60)).unwrap();
+        let (redirect, _) = client.token_response(test_successful_token_response(keypair), Some(callback_session)).unwrap();
+        assert!(redirect.headers()[0].1.starts_with("oauthAttempts=;"));
+    }
+
     #[test]
     fn callback() {
         let client = test_oauth_client();
@@ -442,16 +1007,167 @@ mod tests {
     }
 
 
+    #[test]
+    fn callback_iss_parameter() {
+        let callback_request = |query: &str| Request::new( vec![
+            ("x-forwarded-proto".to_string(), "http".to_string()),
+            (":authority".to_string(), "localhost".to_string()),
+            (":path".to_string(), format!("/callback?code=1234abcd&state=123{}", query))
+        ]).unwrap();
+        let (_, session) = test_callback_session();
+
+        // The iss parameter is checked if present
+        let client = test_oauth_client();
+        assert!(client.callback(callback_request(""), Some(session.clone())).is_ok());
+        assert!(client.callback(callback_request("&iss=https%3A%2F%2Fissuer"), Some(session.clone())).is_ok());
+        assert!(client.callback(callback_request("&iss=https%3A%2F%2Fissuer%2F"), Some(session.clone())).is_err());
+        assert!(client.callback(callback_request("&iss=https%3A%2F%2FISSUER"), Some(session.clone())).is_err());
+        let error = client.callback(callback_request("&iss=https%3A%2F%2Fattacker"), Some(session.clone())).unwrap_err();
+        assert_eq!(error.response().error_code(), Some(ErrorCode::InvalidRequest));
+        assert_eq!(error.response().code(), 400);
+        assert!(client.callback_error(&callback_request("&error=access_denied&iss=https%3A%2F%2Fattacker"), Some(&session)).is_err());
+
+        // Providers advertising the iss parameter must send it
+        let client = crate::oauth_client::OAuthClient::new(test_oauth_config().with_iss_parameter_required(true)).unwrap();
+        assert!(client.callback(callback_request(""), Some(session.clone())).is_err());
+        assert!(client.callback(callback_request("&iss=https%3A%2F%2Fissuer"), Some(session)).is_ok());
+    }
+
+    #[test]
+    fn callback_form_post() {
+        let client = crate::oauth_client::OAuthClient::new(test_oauth_config().with_form_post(true)).unwrap();
+        let (redirect, update) = client.start(test_request()).unwrap();
+        assert!(redirect.url().query_pairs().any(|(name, value)| name == "response_mode" && value == "form_post"));
+        // The session cookie stays Lax, the cross site POST of the authorization response carries the
+        // short-lived authorization cookie instead
+        assert!(redirect.headers()[0].1.contains("SameSite=Lax"));
+        assert!(redirect.headers()[1].1.contains("-authorization="));
+        assert!(redirect.headers()[1].1.contains("SameSite=None"));
+        assert!(redirect.headers()[1].1.contains("Path=/callback"));
+        assert!(matches!(update.create_session().data, SessionType::AuthorizationRequest(..)));
+
+        let request = Request::new( vec![
+            ("x-forwarded-proto".to_string(), "http".to_string()),
+            (":authority".to_string(), "localhost".to_string()),
+            (":path".to_string(), "/callback".to_string()),
+            (":method".to_string(), "POST".to_string()),
+            ("content-type".to_string(), "application/x-www-form-urlencoded".to_string()),
+        ]).unwrap();
+        assert!(request.is_form_post());
+        assert!(!request.has_empty_body());
+        let request = request.with_form(b"code=1234abcd&state=123");
+        assert_eq!(request.authorization_code().unwrap(), "1234abcd");
+
+        let (_, callback_session) = test_callback_session();
+        let token_request = client.callback(request, Some(callback_session)).unwrap();
+        assert!(String::from_utf8(token_request.body().to_vec()).unwrap().contains("code=1234abcd"));
+    }
+
+    #[test]
+    fn callback_error() {
+        let error_request = |query: &str| Request::new( vec![
+            ("x-forwarded-proto".to_string(), "http".to_string()),
+            (":authority".to_string(), "localhost".to_string()),
+            (":path".to_string(), format!("/callback?{}", query))
+        ]).unwrap();
+        let client = test_oauth_client();
+        let (_, session) = test_callback_session();
+
+        assert!(client.callback_error(&test_callback_request(), Some(&session)).unwrap().is_none());
+        // The state is validated for error responses as well
+        assert!(client.callback_error(&error_request("error=access_denied&state=456"), Some(&session)).is_err());
+
+        match client.callback_error(&error_request("error=access_denied&error_description=denied&state=123"), Some(&session)).unwrap() {
+            Some((CallbackError::Response(response), update)) => {
+                assert_eq!(response.code(), 403);
+                assert!(response.headers()[0].1.contains("Max-Age=0"));
+                assert!(matches!(update.create_session().data, SessionType::Empty));
+            }
+            _ => panic!("Expected error response"),
+        }
+
+        let config = test_oauth_config().with_error_handling(None, Some("<p>{error}: {error_description}</p>".to_string()));
+        let client = crate::oauth_client::OAuthClient::new(config).unwrap();
+        match client.callback_error(&error_request("error=consent_required&error_description=%3Cb%3E&state=123"), Some(&session)).unwrap() {
+            Some((CallbackError::Response(response), _)) => {
+                assert_eq!(response.content_type(), Some("text/html; charset=utf-8"));
+                assert_eq!(response.body(), "<p>consent_required: &lt;b&gt;</p>");
+            }
+            _ => panic!("Expected error page"),
+        }
+
+        let config = test_oauth_config().with_error_handling(Some(Url::parse("https://app/error").unwrap()), None);
+        let client = crate::oauth_client::OAuthClient::new(config).unwrap();
+        match client.callback_error(&error_request("error=access_denied&state=123"), Some(&session)).unwrap() {
+            Some((CallbackError::Redirect(redirect), _)) =>
+                assert_eq!(redirect.url().as_str(), "https://app/error?error=access_denied"),
+            _ => panic!("Expected error redirect"),
+        }
+    }
+
+    #[test]
+    fn callback_error_silent() {
+        let config = FilterConfig::oauth(
+            "sessioncookie",
+            "cluster",
+            "https://issuer",
+            &"https://redirect".parse().unwrap(),
+            &"https://authorization".parse().unwrap(),
+            &"https://token".parse().unwrap(),
+            "myclient",
+            "mysecret",
+            vec![],
+            Duration::hours(1),
+            vec![("prompt".to_string(), "none".to_string())]);
+        let client = crate::oauth_client::OAuthClient::new(config).unwrap();
+        let (redirect, update) = client.start(test_request()).unwrap();
+        let session = update.create_session();
+        let state = redirect.url().query_pairs().find(|(name, _)| name == "state").unwrap().1.to_string();
+
+        let request = Request::new( vec![
+            ("x-forwarded-proto".to_string(), "http".to_string()),
+            (":authority".to_string(), "localhost".to_string()),
+            (":path".to_string(), format!("/callback?error=login_required&state={}", state))
+        ]).unwrap();
+        match client.callback_error(&request, Some(&session)).unwrap() {
+            Some((CallbackError::Retry(redirect), update)) => {
+                let prompts: Vec<String> = redirect.url().query_pairs()
+                    .filter(|(name, _)| name == "prompt")
+                    .map(|(_, value)| value.to_string())
+                    .collect();
+                assert_eq!(prompts, vec!["login".to_string()]);
+                // The retry is not silent, so it is not retried again
+                let session = update.create_session();
+                assert!(!session.authorization_verifiers().unwrap().is_silent());
+            }
+            _ => panic!("Expected retry with prompt=login"),
+        }
+    }
+
     #[test]
     fn token_response() {
-        //RS256KeyPair::from_der()
-        let raw_der = "MIIEvQIBADALBgkqhkiG9w0BAQEEggSpMIIEpQIBAAKCAQEAwFV/hfsq9vZtWG4AIx6CByy+vSHUceLxch9+W7AYLEqHEjazuJrznetYFAJnqfujbP9VRACHCDbCx7a3Rj2RRtzmgDSdQvn5g7lYZ3Ljk2rr8dEU3x+ST+i4Ggjooa2t1Z+ukQkt0AJT4ViZopP1CzvOzwSFnCfFAZozun9nSWo7hwICVW2iA3QT3AxKPB5T2G+bi0oQ6Hz20mWQ1xYhhLzjv2uYYptf6R2JHvam+UQesKfHn/72zYGeUCHJKFYZ9WRGn8VdyDhjYPO/B4sTcIsfNadUMWvl0j9F7RFkUcgPqSbBz2zRYnetV8M81o0x81ZuJoQM+KgmcJR6ldRkYQIDAQABAoIBAQCGv5/PY2/vhCiJis+nyyj4E94yLbBkdcYI2Y2yeQYGidRl61Mwo8bIPekAY8ry6WzO4XiAYwo3s/3048s1d87/YaZP7gy7rtyj78fC+lf8oS1axswjoj/kojvRaapqE381qmEoFdE16VuRBnzkzVohm65BlX5yL96zR7C0GaxoXr8eoPdgxecHDr3ikVykGYzmyOOszoPD8XIXdD8eXOT43LNk8HNmV6mGANrG/52leCH6H3o8ofbcaJSDXj4cFW9CZccuUo0lcc/C6gfaY8/iOHiFBI5kDEZ1kqlOM5fiWLV6z/moQxac53yXls/pw4B0LmuwLU1+5kBV4N9KjUUdAoGBAMZE9G8/uAsooVKgfzEGztKhuX1vIP8hFPUeZ80ITr88Ljvk3PPcsZAUA2F4ls64P5s/i3pcGv4zGUxxCMO3xPu8e8oqkMOpxhm+5mzLc2uuV551Lfl1FDPV4dVKG518ubrCdOgcslvQf4b1VPsGvWH0pjm64DKobVtbftyOU0p7AoGBAPhWHmx5zKxMZ0l3/nbf2RHNQ0PRpes7mVPXmxYMf/Fi1iTAwpft3OwHmmYNS9pYJWgR96QJUYy0H/pWCqrNX7pqdSRkVd/GH/bD/ctxtsu8vDXnQcZ++YQtnOa3N2whSWkru93yBHsF6WZhQINBBqWBb1hwyUtSjlowQiR4LrPTAoGAMFEvjEjn7fg50CwCswzorBXzYSkKzoHeXJnLTAEBR8M/tSLh4Z/fua/W3xMHFVt7TLcgo3H8tgP331QQgbmwDHTzaeITo0slVrLZ7ptqBkzXV2tRB+DLSpKN1W+Y6Tky/dtTqBkOMifOXDI/QmlzD4MSWzE6X74B4Gicst0QWS8CgYEAlLOkthnc6BM8Ce8zTWVO6uz5aUPBUXE3p8/ECtjjR9yCFDHkk+sXzbHjz1YI5K7Bf2a63dvCnDlDqLga3RnrR4n1qRv3m+5cN3w2nbry1V6naLOYXE6uBGr3t+Nf6XypI7PPY+BGtI/eqbJ0WeP3IeW2kcO/lT3TBcg94u6gMIcCgYEApXGp3yaUm7gmfn+mICgfxDkcSEVZe9GH/gZ1MNUsLBWthqOFJribC7pjbchvgAm9/oBgv5y3NH8TkdHP9CoKGWU+cPGc9SSjngQ1J7oTa3s67fDxb7mR/9WCYK1Bd46PD81NQmUHKsMdhSIitD9oprueup6gE2T3jvk67+vsAjE=";
-        let keypair = RS256KeyPair::from_der(base64::decode(raw_der).unwrap().as_slice()).unwrap();
+        let keypair = test_keypair();
         let client = test_oidc_client(keypair.clone());
         let response = test_successful_token_response(keypair);
         let (id, callback_session) = test_callback_session();
         let result = client.token_response(response, Some(callback_session));
         assert!(result.is_ok());
+
+        // ID tokens from another issuer or for another client are rejected
+        for (issuer, audience) in [("https://other", "myclient"), ("https://issuer", "otherclient")].iter() {
+            let claims = Claims::create(jwt_simple::prelude::Duration::from_hours(1))
+                .with_issuer(issuer)
+                .with_audience(audience);
+            let response = TokenResponse::Success(SuccessfulResponse::new(
+                "testaccesstoken".to_string(),
+                Some(test_keypair().sign(claims).unwrap()),
+                Some("bearer".to_string()),
+                None,
+                Some(120)));
+            let (_, callback_session) = test_callback_session();
+            let result = client.token_response(response, Some(callback_session));
+            assert!(matches!(result, Err(error) if error.response().error_code() == Some(ErrorCode::InvalidToken)));
+        }
     }
 
     #[test]
@@ -461,22 +1177,500 @@ mod tests {
 
 
         // Authenticated and valid sessions are accepted
-        let result = client.proxy(Some(session));
+        let result = client.proxy(&request, Some(session));
         assert!(result.is_ok());
         assert!(matches!(result.unwrap(), Access::Allowed(..)));
 
         // Empty (first request) session are unauthenticated
-        let result = client.proxy(None);
+        let result = client.proxy(&request, None);
         assert!(result.is_ok());
         assert!(matches!(result.unwrap(), Access::UnAuthenticated));
 
         // Sessions that are waiting for callback are denied
-        let result = client.proxy(Some(test_callback_session().1));
+        let result = client.proxy(&request, Some(test_callback_session().1));
         assert!(result.is_ok());
         assert!(matches!(result.unwrap(), Access::Denied(..)));
 
     }
 
+    #[test]
+    fn refresh_expired_session() {
+        let client = test_oauth_client();
+        let expired = Session::tokens(
+            "mysession".to_string(),
+            "testaccesstoken".to_string(),
+            Some(std::time::Duration::from_secs(0)),
+            None,
+            Some("testrefreshtoken".to_string()),
+        );
+        let (request, update) = match client.proxy(&test_request(), Some(expired)).unwrap() {
+            Access::Refresh(request, update) => (request, update),
+            _ => panic!("Expected refresh"),
+        };
+        assert_eq!(request.url().as_str(), "https://token/");
+        let body = String::from_utf8(request.body().to_vec()).unwrap();
+        assert!(body.contains("grant_type=refresh_token"));
+        assert!(body.contains("refresh_token=testrefreshtoken"));
+
+        // Concurrent requests wait for the pending refresh
+        let refreshing = update.create_session();
+        let result = client.proxy(&test_request(), Some(refreshing.clone()));
+        assert!(matches!(result, Err(error) if error.response().error_code() == Some(ErrorCode::RefreshInProgress)));
+
+        let update = client.refresh_response(b"{\"access_token\": \"newaccesstoken\", \"expires_in\": 300}", Some(&refreshing)).unwrap();
+        match client.proxy(&test_request(), Some(update.create_session())).unwrap() {
+            Access::Allowed(headers) => assert!(headers.iter().any(|(_, value)| value.contains("newaccesstoken"))),
+            _ => panic!("Expected refreshed session to be allowed"),
+        }
+    }
+
+    #[test]
+    fn silent_renewal() {
+        let client = crate::oauth_client::OAuthClient::new(test_oauth_config().with_silent_renewal(1)).unwrap();
+        let navigation = Request::new( vec![
+            ("x-forwarded-proto".to_string(), "http".to_string()),
+            (":authority".to_string(), "localhost".to_string()),
+            (":path".to_string(), "/resource".to_string()),
+            (":method".to_string(), "GET".to_string()),
+            ("sec-fetch-mode".to_string(), "navigate".to_string()),
+        ]).unwrap();
+        let expired = Session::tokens(
+            "mysession".to_string(),
+            "testaccesstoken".to_string(),
+            Some(std::time::Duration::from_secs(0)),
+            None,
+            None,
+        );
+
+        // Background requests can't follow the redirect and are denied
+        assert!(matches!(client.proxy(&test_request(), Some(expired.clone())).unwrap(), Access::Denied(..)));
+        // Without silent renewal expired sessions are denied
+        assert!(matches!(test_oauth_client().proxy(&navigation, Some(expired.clone())).unwrap(), Access::Denied(..)));
+        // Sessions with a refresh token are refreshed instead of renewed silently
+        let refreshable = Session::tokens(
+            "mysession".to_string(),
+            "testaccesstoken".to_string(),
+            Some(std::time::Duration::from_secs(0)),
+            None,
+            Some("testrefreshtoken".to_string()),
+        );
+        assert!(matches!(client.proxy(&navigation, Some(refreshable)).unwrap(), Access::Refresh(..)));
+
+        let (redirect, update) = match client.proxy(&navigation, Some(expired)).unwrap() {
+            Access::Renew(redirect, update) => (redirect, update),
+            _ => panic!("Expected silent renewal"),
+        };
+        assert!(redirect.url().query().unwrap().contains("prompt=none"));
+        let session = update.create_session();
+        assert!(session.authorization_verifiers().unwrap().is_silent());
+
+        // The attempt is counted, further renewals are denied until new tokens are issued
+        assert!(matches!(client.proxy(&navigation, Some(session.clone())).unwrap(), Access::Denied(..)));
+
+        // Form POSTs that can be replayed log in again instead
+        let form_post = Request::new( vec![
+            ("x-forwarded-proto".to_string(), "http".to_string()),
+            (":authority".to_string(), "localhost".to_string()),
+            (":path".to_string(), "/resource".to_string()),
+            (":method".to_string(), "POST".to_string()),
+            ("content-type".to_string(), "application/x-www-form-urlencoded".to_string()),
+            ("content-length".to_string(), "9".to_string()),
+            ("origin".to_string(), "http://localhost".to_string()),
+        ]).unwrap();
+        assert!(matches!(client.proxy(&form_post, Some(session.clone())).unwrap(), Access::Denied(..)));
+        let client_with_replay = crate::oauth_client::OAuthClient::new(
+            test_oauth_config().with_post_replay(Some(PostReplayConfig::new(8192, vec!["application/x-www-form-urlencoded".to_string()])))
+        ).unwrap();
+        assert!(matches!(client_with_replay.proxy(&form_post, Some(session.clone())).unwrap(), Access::UnAuthenticated));
+
+        // interaction_required falls back to an interactive authorization request
+        let state = redirect.url().query_pairs().find(|(name, _)| name == "state").unwrap().1.to_string();
+        let error_request = Request::new( vec![
+            ("x-forwarded-proto".to_string(), "http".to_string()),
+            (":authority".to_string(), "localhost".to_string()),
+            (":path".to_string(), format!("/callback?error=interaction_required&state={}", state))
+        ]).unwrap();
+        match client.callback_error(&error_request, Some(&session)).unwrap() {
+            Some((CallbackError::Retry(redirect), update)) => {
+                assert!(!redirect.url().query().unwrap().contains("prompt="));
+                assert!(!update.create_session().authorization_verifiers().unwrap().is_silent());
+            }
+            _ => panic!("Expected interactive retry"),
+        }
+    }
+
+    #[test]
+    fn frontend_token() {
+        let request = Request::new(vec![
+            ("x-forwarded-proto".to_string(), "https".to_string()),
+            (":authority".to_string(), "app".to_string()),
+            (":path".to_string(), "/oauth2/token".to_string()),
+            ("origin".to_string(), "https://app".to_string()),
+            ("x-csrf".to_string(), "1".to_string()),
+        ]).unwrap();
+        let (_, session) = test_valid_session();
+        assert!(test_oauth_client().frontend_token(&request, Some(&session)).is_err());
+
+        let config = test_oauth_config().with_bff(Some(BffConfig::new(vec!["https://app".to_string()], "X-CSRF", 60)));
+        let client = crate::oauth_client::OAuthClient::new(config).unwrap();
+        match client.frontend_token(&request, Some(&session)).unwrap() {
+            FrontendToken::Token(response) => assert!(response.body().contains("testaccesstoken")),
+            FrontendToken::Refresh(..) => panic!("Valid access tokens are not refreshed"),
+        }
+        assert!(client.frontend_token(&request, None).is_err());
+
+        // Tokens close to expiry are refreshed, keeping the ID token
+        let session = Session::tokens("mysession".to_string(), "testaccesstoken".to_string(),
+            Some(std::time::Duration::from_secs(30)), Some("testidtoken".to_string()), Some("testrefreshtoken".to_string()));
+        let refreshing = match client.frontend_token(&request, Some(&session)).unwrap() {
+            FrontendToken::Refresh(request, update) => {
+                let body = String::from_utf8(request.body().to_vec()).unwrap();
+                assert!(body.contains("grant_type=refresh_token"));
+                assert!(body.contains("refresh_token=testrefreshtoken"));
+                update.create_session()
+            }
+            FrontendToken::Token(_) => panic!("Tokens close to expiry are refreshed"),
+        };
+        // Concurrent requests get the current access token while the refresh is pending
+        assert!(matches!(client.frontend_token(&request, Some(&refreshing)).unwrap(), FrontendToken::Token(..)));
+        let update = client.refresh_response(b"{\"access_token\": \"newaccesstoken\", \"expires_in\": 300}", Some(&session)).unwrap();
+        let tokens = update.tokens().unwrap();
+        assert_eq!(tokens.access_token(), "newaccesstoken");
+        assert_eq!(tokens.id_token(), Some("testidtoken"));
+        assert_eq!(tokens.refresh_token(), Some("testrefreshtoken"));
+    }
+
+    #[test]
+    fn session_info() {
+        let client = test_oauth_client();
+        assert_eq!(client.session_info(None).code(), 401);
+
+        let id_token = test_keypair()
+            .sign(Claims::create(jwt_simple::prelude::Duration::from_hours(1)).with_subject("jane"))
+            .unwrap();
+        let session = Session::tokens(
+            "mysession".to_string(),
+            "testaccesstoken".to_string(),
+            Some(std::time::Duration::from_secs(120)),
+            Some(id_token),
+            None,
+        );
+        let response = client.session_info(Some(&session));
+        assert_eq!(response.code(), 200);
+        assert_eq!(response.content_type(), Some("application/json"));
+        let body: Value = serde_json::from_str(response.body()).unwrap();
+        assert_eq!(body["sub"], "jane");
+        assert!(body["expires_at"].as_u64().is_some());
+        assert!(body.get("access_token").is_none());
+
+        let client = crate::oauth_client::OAuthClient::new(test_oauth_config().with_session_info(true, vec![], true)).unwrap();
+        let body: Value = serde_json::from_str(client.session_info(Some(&session)).body()).unwrap();
+        assert_eq!(body["access_token"], "testaccesstoken");
+    }
+
+    #[test]
+    fn userinfo() {
+        let config = test_oauth_config()
+            .with_userinfo(Some("https://userinfo".parse().unwrap()), vec!["sub".to_string(), "groups".to_string()])
+            .with_rules(vec![PathRule::new("/admin", Some(AuthorizationRule::new(vec![], vec!["admins".to_string()], vec![], vec![], vec![])))]);
+        let client = crate::oauth_client::OAuthClient::new(config).unwrap();
+        let id_token = test_keypair()
+            .sign(Claims::create(jwt_simple::prelude::Duration::from_hours(1)).with_subject("jane"))
+            .unwrap();
+        let (_, session) = test_callback_session();
+        let update = || session.token_response("testaccesstoken".to_string(), None, Some(id_token.clone()), None);
+
+        let request = client.userinfo_request(&update()).unwrap();
+        assert!(request.headers().contains(&(":method", "GET")));
+        assert!(request.headers().contains(&("authorization", "Bearer testaccesstoken")));
+
+        // Only the selected claims are stored, and used by authorization rules
+        let update = client.userinfo_response(b"{\"sub\": \"jane\", \"groups\": [\"admins\"], \"address\": \"street\"}", update()).unwrap();
+        let claims = update.tokens().unwrap().userinfo();
+        assert!(claims.contains_key("groups"));
+        assert!(!claims.contains_key("address"));
+        let admin_request = Request::new( vec![
+            ("x-forwarded-proto".to_string(), "http".to_string()),
+            (":authority".to_string(), "localhost".to_string()),
+            (":path".to_string(), "/admin/users".to_string())
+        ]).unwrap();
+        assert!(client.authorize(&admin_request, Some(&update.create_session())).is_ok());
+
+        // The userinfo must be about the subject of the ID token
+        assert!(client.userinfo_response(b"{\"sub\": \"john\"}", session.token_response("testaccesstoken".to_string(), None, Some(id_token.clone()), None)).is_err());
+    }
+
+    #[test]
+    fn authorize() {
+        let rule = AuthorizationRule::new(vec![], vec!["admins".to_string()], vec![], vec![], vec![]);
+        let config = test_oauth_config().with_rules(vec![PathRule::new("/admin", Some(rule))]);
+        let client = crate::oauth_client::OAuthClient::new(config).unwrap();
+        let (request, session) = test_authorized_request();
+
+        // Paths without a rule only require authentication
+        assert!(client.authorize(&request, Some(&session)).is_ok());
+
+        // Session tokens without the required group claim are denied
+        let admin_request = Request::new( vec![
+            ("x-forwarded-proto".to_string(), "http".to_string()),
+            (":authority".to_string(), "localhost".to_string()),
+            (":path".to_string(), "/admin/users".to_string())
+        ]).unwrap();
+        let result = client.authorize(&admin_request, Some(&session));
+        assert!(result.is_err());
+        assert_eq!(result.unwrap_err().code(), 403);
+    }
+
+    #[test]
+    fn step_up() {
+        let rule = PathRule::new("/admin", None).with_step_up(vec!["Level4".to_string()], Some(300));
+        let config = test_oauth_config().with_rules(vec![rule]);
+        let client = crate::oauth_client::OAuthClient::new(config).unwrap();
+        let (request, session) = test_authorized_request();
+
+        // Paths without step-up requirements are not redirected
+        assert!(client.step_up(&request, Some(&session)).unwrap().is_none());
+
+        let admin_request = Request::new( vec![
+            ("x-forwarded-proto".to_string(), "http".to_string()),
+            (":authority".to_string(), "localhost".to_string()),
+            (":path".to_string(), "/admin/users".to_string()),
+            (":method".to_string(), "GET".to_string()),
+            ("sec-fetch-mode".to_string(), "navigate".to_string()),
+        ]).unwrap();
+        let (redirect, update) = client.step_up(&admin_request, Some(&session)).unwrap().unwrap();
+        let query = redirect.url().query().unwrap();
+        assert!(query.contains("acr_values=Level4"));
+        assert!(query.contains("max_age=300"));
+        assert!(query.contains("prompt=login"));
+
+        // The session keeps its tokens while the step-up is pending
+        let session = update.create_session();
+        assert!(matches!(session.data, SessionType::Tokens(..)));
+        assert!(session.authorization_verifiers().is_some());
+        assert!(matches!(client.proxy(&admin_request, Some(session.clone())).unwrap(), Access::Allowed(..)));
+
+        // Tokens of a step-up that doesn't meet the requirements start one more, then requests are denied
+        let session = session.token_response("testaccesstoken".to_string(), Some(std::time::Duration::from_secs(120)), None, None).create_session();
+        let (_, update) = client.step_up(&admin_request, Some(&session)).unwrap().unwrap();
+        let session = update.create_session().token_response("testaccesstoken".to_string(), Some(std::time::Duration::from_secs(120)), None, None).create_session();
+        assert_eq!(client.step_up(&admin_request, Some(&session)).unwrap_err().response().code(), 403);
+
+        // Background requests get 401 instead of a redirect
+        let fetch_request = Request::new( vec![
+            ("x-forwarded-proto".to_string(), "http".to_string()),
+            (":authority".to_string(), "localhost".to_string()),
+            (":path".to_string(), "/admin/users".to_string()),
+            (":method".to_string(), "GET".to_string()),
+            ("sec-fetch-mode".to_string(), "cors".to_string()),
+        ]).unwrap();
+        let (_, session) = test_authorized_request();
+        assert_eq!(client.step_up(&fetch_request, Some(&session)).unwrap_err().response().code(), 401);
+    }
+
+    #[test]
+    fn bearer() {
+        let keypair = test_keypair();
+        let rule = AuthorizationRule::new(vec!["read".to_string()], vec![], vec![], vec![], vec![]);
+        let config = test_oidc_config(keypair.clone())
+            .with_rules(vec![PathRule::new("/api", Some(rule)).with_bearer(vec!["api".to_string()])]);
+        let client = crate::oauth_client::OAuthClient::new(config).unwrap();
+        let cache = SharedCache::new();
+
+        let api_request = |token: Option<String>| {
+            let mut headers = vec![
+                ("x-forwarded-proto".to_string(), "http".to_string()),
+                (":authority".to_string(), "localhost".to_string()),
+                (":path".to_string(), "/api/resource".to_string())
+            ];
+            if let Some(token) = token {
+                headers.push(("authorization".to_string(), format!("Bearer {}", token)));
+            }
+            Request::new(headers).unwrap()
+        };
+        let access_token = |audience: &str, scope: &str| {
+            let claims = Claims::with_custom_claims(
+                serde_json::json!({"scope": scope}),
+                jwt_simple::prelude::Duration::from_hours(1))
+                .with_issuer("https://issuer")
+                .with_audience(audience);
+            keypair.sign(claims).unwrap()
+        };
+
+        // Requests without a bearer token are rejected without an error code
+        assert!(matches!(client.bearer(&api_request(None), &cache), Access::Denied(response) if response.code() == 401));
+
+        // Tokens for another audience are invalid
+        let result = client.bearer(&api_request(Some(access_token("other", "read"))), &cache);
+        assert!(matches!(result, Access::Denied(response) if response.code() == 401));
+
+        // Tokens without the required scope have insufficient scope
+        let result = client.bearer(&api_request(Some(access_token("api", "write"))), &cache);
+        assert!(matches!(result, Access::Denied(response) if response.code() == 403));
+
+        let result = client.bearer(&api_request(Some(access_token("api", "read write"))), &cache);
+        assert!(matches!(result, Access::Allowed(..)));
+
+        // OAuth authorization servers validate JWT access tokens with their JWKS as well
+        let rule = AuthorizationRule::new(vec!["read".to_string()], vec![], vec![], vec![], vec![]);
+        let config = test_oauth_config()
+            .with_jwks(test_jwks(&keypair))
+            .with_rules(vec![PathRule::new("/api", Some(rule)).with_bearer(vec!["api".to_string()])]);
+        let client = crate::oauth_client::OAuthClient::new(config).unwrap();
+        let result = client.bearer(&api_request(Some(access_token("api", "read"))), &cache);
+        assert!(matches!(result, Access::Allowed(..)));
+    }
+
+    #[test]
+    fn introspection() {
+        let rule = AuthorizationRule::new(vec!["read".to_string()], vec![], vec![], vec![], vec![]);
+        let config = test_oauth_config()
+            .with_rules(vec![PathRule::new("/api", Some(rule)).with_bearer(vec![])])
+            .with_introspection(Some("https://introspection".parse().unwrap()), true, 60);
+        let client = crate::oauth_client::OAuthClient::new(config).unwrap();
+        let mut cache = SharedCache::new();
+        let request = Request::new( vec![
+            ("x-forwarded-proto".to_string(), "http".to_string()),
+            (":authority".to_string(), "localhost".to_string()),
+            (":path".to_string(), "/api/resource".to_string()),
+            ("authorization".to_string(), "Bearer opaquetoken".to_string())
+        ]).unwrap();
+
+        // Opaque tokens without a cached result must be introspected
+        assert!(matches!(client.bearer(&request, &cache), Access::Introspect(token) if token == "opaquetoken"));
+        let introspection_request = client.introspection_request("opaquetoken").unwrap();
+        assert_eq!(introspection_request.url().clone(), Url::parse("https://introspection").unwrap());
+        assert!(String::from_utf8(introspection_request.body().to_vec()).unwrap().contains("token=opaquetoken"));
+
+        client.introspection_response("opaquetoken", b"{\"active\": true, \"scope\": \"read\"}", &mut cache).unwrap();
+        assert!(matches!(client.bearer(&request, &cache), Access::Allowed(..)));
+
+        client.introspection_response("opaquetoken", b"{\"active\": false}", &mut cache).unwrap();
+        assert!(matches!(client.bearer(&request, &cache), Access::Denied(response) if response.code() == 401));
+
+        // Session access tokens are introspected as well
+        let (_, session) = test_valid_session();
+        assert!(matches!(client.introspect_session(Some(&session), &cache), Some(Access::Introspect(..))));
+        client.introspection_response("testaccesstoken", b"{\"active\": true}", &mut cache).unwrap();
+        assert!(client.introspect_session(Some(&session), &cache).is_none());
+    }
+
+    #[test]
+    fn token_exchange() {
+        let config = test_oauth_config()
+            .with_token_exchange(None, vec![ExchangeTarget::new("api2", "api2-audience", vec![])]);
+        let client = crate::oauth_client::OAuthClient::new(config).unwrap();
+        let mut cache = SharedCache::new();
+        let request = |host: &str| Request::new( vec![
+            ("x-forwarded-proto".to_string(), "http".to_string()),
+            (":authority".to_string(), host.to_string()),
+            (":path".to_string(), "/resource".to_string()),
+            ("authorization".to_string(), "Bearer subjecttoken".to_string())
+        ]).unwrap();
+
+        let headers = || vec![(AUTHORIZATION.to_string(), "Bearer subjecttoken".to_string())];
+
+        // Requests to other hosts are not exchanged
+        assert!(matches!(client.token_exchange(&request("api1"), None, headers(), &cache), Access::Allowed(allowed) if allowed == headers()));
+
+        let target = match client.token_exchange(&request("api2"), None, headers(), &cache) {
+            Access::Exchange(subject_token, target) => {
+                assert_eq!(subject_token, "subjecttoken");
+                target
+            }
+            _ => panic!("Expected token exchange"),
+        };
+        let exchange_request = client.token_exchange_request("subjecttoken", &target).unwrap();
+        assert_eq!(exchange_request.url().clone(), Url::parse("https://token").unwrap());
+        let body = String::from_utf8(exchange_request.body().to_vec()).unwrap();
+        assert!(body.contains("grant_type=urn%3Aietf%3Aparams%3Aoauth%3Agrant-type%3Atoken-exchange"));
+        assert!(body.contains("audience=api2-audience"));
+
+        let response = b"{\"access_token\": \"exchangedtoken\", \"issued_token_type\": \"urn:ietf:params:oauth:token-type:access_token\", \"token_type\": \"Bearer\", \"expires_in\": 300}";
+        client.token_exchange_response("subjecttoken", &target, response, &mut cache).unwrap();
+        match client.token_exchange(&request("api2"), None, headers(), &cache) {
+            Access::Allowed(headers) => assert_eq!(headers[0].1, "Bearer exchangedtoken"),
+            _ => panic!("Expected cached exchanged token"),
+        }
+
+        // The access token of the session is the subject token of session requests
+        let (_, session) = test_valid_session();
+        assert!(matches!(client.token_exchange(&request("api2"), Some(&session), headers(), &cache),
+            Access::Exchange(subject_token, _) if subject_token == "testaccesstoken"));
+    }
+
+    #[test]
+    fn pushed_authorization() {
+        // Pushed authorization requests are disabled by default
+        let client = test_oauth_client();
+        let (redirect, _) = client.start(test_request()).unwrap();
+        assert!(client.pushed_authorization_request(&redirect).unwrap().is_none());
+
+        let config = test_oauth_config().with_pushed_authorization(Some(Url::parse("https://par").unwrap()));
+        let client = crate::oauth_client::OAuthClient::new(config).unwrap();
+        let (redirect, _) = client.start(test_request()).unwrap();
+        let request = client.pushed_authorization_request(&redirect).unwrap().unwrap();
+        assert_eq!(request.url().clone(), Url::parse("https://par").unwrap());
+        let body = String::from_utf8(request.body().to_vec()).unwrap();
+        assert!(body.contains("code_challenge_method=S256"));
+        assert!(body.contains("response_type=code"));
+        assert!(body.contains("state="));
+
+        let response = b"{\"request_uri\": \"urn:ietf:params:oauth:request_uri:6esc_11ACC5bwc014ltc14eY22c\", \"expires_in\": 60}";
+        let redirect = client.pushed_authorization_response(response, vec![("set-cookie".to_string(), "session".to_string())]).unwrap();
+        let query: Vec<(String, String)> = redirect.url().query_pairs().into_owned().collect();
+        assert_eq!(query.len(), 2);
+        assert!(query.contains(&("request_uri".to_string(), "urn:ietf:params:oauth:request_uri:6esc_11ACC5bwc014ltc14eY22c".to_string())));
+        assert_eq!(redirect.headers().len(), 1);
+
+        let error = b"{\"error\": \"invalid_request\", \"error_description\": \"invalid redirect_uri\"}";
+        assert!(client.pushed_authorization_response(error, vec![]).is_err());
+    }
+
+    #[test]
+    fn request_objects() {
+        let keypair = test_keypair();
+        let config = test_config_extra(vec!["openid".to_string()])
+            .with_request_objects(Some(crate::client_auth::ClientKey::RS256(Box::new(keypair.clone()))));
+        let client = crate::oauth_client::OAuthClient::new(config).unwrap();
+        let (redirect, _) = client.start(test_request()).unwrap();
+
+        // Only the parameters required by OpenID Connect are duplicated outside the request object
+        let query: Vec<(String, String)> = redirect.url().query_pairs().into_owned().collect();
+        let names: Vec<&str> = query.iter().map(|(name, _)| name.as_str()).collect();
+        assert_eq!(names, vec!["client_id", "response_type", "scope", "request"]);
+
+        let claims = keypair.public_key()
+            .verify_token::<Map<String, serde_json::Value>>(&query[3].1, None)
+            .unwrap();
+        assert_eq!(claims.issuer.unwrap(), "myclient");
+        assert!(claims.custom.contains_key("state"));
+        assert!(claims.custom.contains_key("code_challenge"));
+        assert_eq!(claims.custom.get("redirect_uri").unwrap(), "https://redirect/");
+
+        // Numeric parameters are JSON numbers
+        let rule = PathRule::new("/admin", None).with_step_up(vec![], Some(300));
+        let config = test_config_extra(vec!["openid".to_string()])
+            .with_rules(vec![rule])
+            .with_request_objects(Some(crate::client_auth::ClientKey::RS256(Box::new(keypair.clone()))));
+        let client = crate::oauth_client::OAuthClient::new(config).unwrap();
+        let admin_request = Request::new( vec![
+            ("x-forwarded-proto".to_string(), "http".to_string()),
+            (":authority".to_string(), "localhost".to_string()),
+            (":path".to_string(), "/admin/users".to_string()),
+            (":method".to_string(), "GET".to_string()),
+            ("sec-fetch-mode".to_string(), "navigate".to_string()),
+        ]).unwrap();
+        let (_, session) = test_authorized_request();
+        let (redirect, _) = client.step_up(&admin_request, Some(&session)).unwrap().unwrap();
+        let request_object = redirect.url().query_pairs().find(|(name, _)| name == "request").unwrap().1.to_string();
+        let claims = keypair.public_key()
+            .verify_token::<Map<String, serde_json::Value>>(&request_object, None)
+            .unwrap();
+        assert_eq!(claims.custom.get("max_age").unwrap(), &json!(300));
+        assert_eq!(claims.custom.get("prompt").unwrap(), "login");
+    }
+
     #[test]
     fn configure_scopes() {
         let scopes = vec!["openid".to_string(),
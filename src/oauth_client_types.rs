@@ -1,7 +1,11 @@
 use oauth2::HttpRequest;
 use oauth2::url::Url;
 
+use crate::error_page::ErrorCode;
 use crate::messages::DownStreamResponse;
+use crate::session::SessionUpdate;
+use crate::post_replay::SavedPost;
+use crate::token_exchange::ExchangeTarget;
 use std::{fmt, error};
 
 pub type Headers = Vec<(String, String)>;
@@ -10,24 +14,97 @@ pub type Headers = Vec<(String, String)>;
 pub struct Request {
     headers: Headers,
     url: Url,
+    // Parameters from a form encoded request body, e.g. a form_post authorization response
+    form: Vec<(String, String)>,
 }
 
 impl Request {
     pub(crate) fn new(headers: Headers) -> Result<Self, ClientError> {
         let url = Self::request_url(headers.clone())?;
-        Ok(Request { headers, url })
+        Ok(Request { headers, url, form: vec![] })
+    }
+
+    pub fn with_form(mut self, body: &[u8]) -> Self {
+        self.form = url::form_urlencoded::parse(body).into_owned().collect();
+        self
+    }
+
+    // Returns true for POST requests with a form encoded body
+    pub fn is_form_post(&self) -> bool {
+        let content_type = self.header("content-type").unwrap_or_default();
+        self.header(":method").is_some_and(|method| method.eq_ignore_ascii_case("POST"))
+            && content_type.to_ascii_lowercase().starts_with("application/x-www-form-urlencoded")
+    }
+
+    // Returns true if the request declares an empty body with Content-Length: 0. Envoy does not call the body
+    // callback for such requests, so they are handled without waiting for the body.
+    pub fn has_empty_body(&self) -> bool {
+        self.header("content-length").is_some_and(|length| length.trim() == "0")
+    }
+
+    // Top-level GET navigations of browsers, which can follow redirects to the authorization server.
+    // Fetch metadata is used if sent, otherwise requests must accept HTML.
+    pub fn is_navigation(&self) -> bool {
+        let is_get = self.header(":method").is_some_and(|method| method.eq_ignore_ascii_case("GET"));
+        let navigates = match self.header("sec-fetch-mode") {
+            Some(mode) => mode.eq_ignore_ascii_case("navigate"),
+            None => self.header("accept").is_some_and(|accept| accept.contains("text/html")),
+        };
+        is_get && navigates
     }
 
     pub fn url(&self) -> &Url {
         &self.url
     }
 
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers.iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    // Value of the named cookie from the cookie headers
+    pub fn cookie(&self, name: &str) -> Option<String> {
+        self.headers.iter()
+            .filter(|(key, _)| key.eq_ignore_ascii_case("cookie"))
+            .flat_map(|(_, value)| value.split(';'))
+            .filter_map(|cookie| cookie.trim().split_once('='))
+            .find(|(key, _)| key.trim() == name)
+            .map(|(_, value)| value.trim().to_string())
+    }
+
+    // Token from the Authorization header using the Bearer scheme, RFC 6750 section 2.1
+    pub fn bearer_token(&self) -> Option<&str> {
+        let value = self.header("authorization")?;
+        match value.get(..7) {
+            Some(scheme) if scheme.eq_ignore_ascii_case("bearer ") => Some(value[7..].trim()),
+            _ => None,
+        }
+    }
+
     pub fn authorization_code(&self) -> Option<String> {
-        self.find_query("code")
+        self.param("code")
     }
 
     pub fn state(&self) -> Option<String>  {
-        self.find_query("state")
+        self.param("state")
+    }
+
+    // Error response from the authorization endpoint, RFC 6749 section 4.1.2.1
+    pub fn authorization_error(&self) -> Option<AuthorizationError> {
+        Some(AuthorizationError {
+            error: self.param("error")?,
+            error_description: self.param("error_description"),
+            error_uri: self.param("error_uri"),
+        })
+    }
+
+    // Parameter from the form encoded body, or the query string
+    pub fn param(&self, name: &str) -> Option<String> {
+        if let Some((_, value)) = self.form.iter().find(|(key, _)| key == name) {
+            return Some(value.clone())
+        }
+        self.find_query(name)
     }
 
     fn find_query(&self, name: &str) -> Option<String>  {
@@ -45,7 +122,7 @@ impl Request {
         let host_url = Self::host_url(headers)?;
         host_url.join(path.as_ref().unwrap_or(&"".to_string()).as_str())
             .map_err(|err| {
-                ClientError::new(500, format!("Could not create URL from base={}, and path={}", host_url, path.unwrap_or_default()),None )
+                ClientError::new(ErrorCode::InvalidRequest, format!("Could not create URL from base={}, and path={}", host_url, path.unwrap_or_default()))
             })
     }
 
@@ -55,8 +132,8 @@ impl Request {
         let authority =
             headers.iter().find(|(name, _)| { *name == ":authority" }).map(|entry| { entry.1.clone() });
         match (scheme, authority) {
-            (None, _) => Err(ClientError::new(400,"No scheme in request header".to_string(), None)),
-            (_, None) => Err(ClientError::new(400, "No authority in request header".to_string(), None)),
+            (None, _) => Err(ClientError::new(ErrorCode::InvalidRequest, "No scheme in request header".to_string())),
+            (_, None) => Err(ClientError::new(ErrorCode::InvalidRequest, "No authority in request header".to_string())),
             (Some(scheme), Some(authority)) => {
                 Ok(format!("{}://{}", scheme, authority).parse().unwrap())
             }
@@ -64,14 +141,26 @@ impl Request {
     }
 }
 
+#[derive(Debug)]
 pub struct Redirect {
     url: Url,
-    headers: Headers
+    headers: Headers,
+    // POST request replayed to the URL instead of redirecting to it
+    replay: Option<SavedPost>,
 }
 
 impl Redirect {
     pub fn new(url: Url, headers: Headers) -> Self {
-        Redirect { url, headers }
+        Redirect { url, headers, replay: None }
+    }
+
+    pub fn with_replay(mut self, replay: Option<SavedPost>) -> Self {
+        self.replay = replay;
+        self
+    }
+
+    pub fn replay(&self) -> Option<&SavedPost> {
+        self.replay.as_ref()
     }
 
     pub fn url(&self) -> &Url {
@@ -83,6 +172,28 @@ impl Redirect {
     }
 }
 
+#[derive(Debug, Clone)]
+pub struct AuthorizationError {
+    pub error: String,
+    pub error_description: Option<String>,
+    pub error_uri: Option<String>,
+}
+
+// Outcome of an authorization error response on the callback endpoint
+pub enum CallbackError {
+    // Retry the authorization request, e.g. with prompt=login after a prompt=none request
+    Retry(Redirect),
+    Redirect(Redirect),
+    Response(DownStreamResponse),
+}
+
+// Outcome of a request to the token endpoint for front-ends
+pub enum FrontendToken {
+    Token(DownStreamResponse),
+    // The access token is refreshed before it is returned, the update marks the session as refreshing
+    Refresh(TokenRequest, Box<SessionUpdate>),
+}
+
 #[derive(Debug, Clone)]
 pub struct TokenRequest {
     raw_request: HttpRequest,
@@ -97,7 +208,7 @@ impl TokenRequest {
     pub fn headers(&self) -> Vec<(&str, &str)> {
         let mut headers = self.serialize_headers();
         headers.append(&mut vec![
-            (":method", "POST"),
+            (":method", self.raw_request.method.as_str()),
             (":path", self.raw_request.url.path()),
             (":authority", self.raw_request.url.host_str().unwrap())]);
         headers
@@ -125,40 +236,42 @@ pub enum Access {
     Denied(DownStreamResponse),
     Allowed(Headers),
     UnAuthenticated,
+    // The result of introspecting the token is required before access can be decided
+    Introspect(String),
+    // The subject token must be exchanged for a token for the target before the request is forwarded
+    Exchange(String, ExchangeTarget),
+    // The expired session is renewed with a silent authorization request
+    Renew(Redirect, Box<SessionUpdate>),
+    // The expired tokens are refreshed before the request is handled again, the update marks the session as refreshing
+    Refresh(TokenRequest, Box<SessionUpdate>),
 }
 
 
 // Respresents Errors that occur in the ClientError
 #[derive(Debug)]
 pub struct ClientError {
-    status: u64,
+    code: ErrorCode,
     message: String,
-    description: Option<String>,
 }
 
 impl fmt::Display for ClientError {
 
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        if self.description.is_some() {
-            write!(f, "OAuth Client error status = {} message = {} description = {}", self.status, self.message, self.description.as_ref().unwrap())
-        } else {
-            write!(f, "OAuth Client error status = {} message = {}", self.status, self.message)
-        }
+        write!(f, "OAuth Client error code = {} message = {}", self.code.as_str(), self.message)
     }
 }
 
 impl error::Error for ClientError {}
 
 impl ClientError {
-    pub fn new(status: u64, message: String, description: Option<String>) -> ClientError {
+    pub fn new(code: ErrorCode, message: String) -> ClientError {
         ClientError {
-            status,
+            code,
             message,
-            description
         }
     }
 
     pub fn response(&self) -> DownStreamResponse {
-        DownStreamResponse::new(vec![], self.status, self.message.clone())
+        DownStreamResponse::error(vec![], self.code, self.message.clone())
     }
 }
@@ -0,0 +1,176 @@
+use serde::{Deserialize, Serialize};
+use url::Url;
+
+use crate::csrf;
+use crate::messages::DownStreamResponse;
+use crate::oauth_client_types::Request;
+use crate::util;
+
+const FORM_URLENCODED: &str = "application/x-www-form-urlencoded";
+const TEXT_PLAIN: &str = "text/plain";
+
+// Saves the body of POST requests redirected to the authorization server, replayed after the callback
+#[derive(Deserialize, Clone, Debug)]
+pub struct PostReplayConfig {
+    // Larger bodies, or bodies without a Content-Length, are not saved
+    #[serde(default = "default_max_body_size")]
+    max_body_size: usize,
+    // Only bodies an HTML form can send again are replayed, i.e. form encoded or text/plain bodies
+    #[serde(default = "default_content_types")]
+    content_types: Vec<String>,
+}
+
+impl PostReplayConfig {
+    pub fn new(max_body_size: usize, content_types: Vec<String>) -> PostReplayConfig {
+        PostReplayConfig { max_body_size, content_types }
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        match self.content_types.iter().find(|content_type| form_enctype(content_type).is_none()) {
+            Some(content_type) => Err(format!("Content type {} can't be replayed by a form", content_type)),
+            None => Ok(()),
+        }
+    }
+
+    // Same-origin POST requests with an allowed content type and a body within the size limit. Cross-origin
+    // requests are not saved, replaying them after the login would bypass the CSRF protection of the application.
+    pub fn accepts(&self, request: &Request) -> bool {
+        let is_post = request.header(":method").is_some_and(|method| method.eq_ignore_ascii_case("POST"));
+        let allowed = self.content_type(request).is_some();
+        let size = request.header("content-length").and_then(|length| length.parse::<usize>().ok());
+        let same_origin = csrf::request_origin(request)
+            .is_some_and(|origin| origin == request.url().origin().ascii_serialization());
+        is_post && allowed && size.is_some_and(|size| size <= self.max_body_size) && same_origin
+    }
+
+    // Saved request for the buffered body, None if the request is not accepted
+    pub fn save(&self, request: &Request, body: &[u8]) -> Option<SavedPost> {
+        if !self.accepts(request) || body.len() > self.max_body_size {
+            return None;
+        }
+        Some(SavedPost {
+            content_type: self.content_type(request)?.to_string(),
+            body: String::from_utf8(body.to_vec()).ok()?,
+        })
+    }
+
+    fn content_type(&self, request: &Request) -> Option<&str> {
+        let content_type = request.header("content-type")?.split(';').next()?.trim();
+        self.content_types.iter()
+            .find(|allowed| allowed.eq_ignore_ascii_case(content_type))
+            .and_then(|allowed| form_enctype(allowed))
+    }
+}
+
+// A POST request body saved with the authorization request
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct SavedPost {
+    content_type: String,
+    body: String,
+}
+
+impl SavedPost {
+    // Page submitting the saved body to the URL of the original request as soon as it is loaded
+    pub fn replay_page(&self, url: &Url, headers: Vec<(String, String)>) -> DownStreamResponse {
+        let fields: Vec<(String, String)> = if self.content_type == TEXT_PLAIN {
+            self.body.lines()
+                .filter_map(|line| line.split_once('='))
+                .map(|(name, value)| (name.to_string(), value.to_string()))
+                .collect()
+        } else {
+            url::form_urlencoded::parse(self.body.as_bytes()).into_owned().collect()
+        };
+        let inputs: Vec<String> = fields.iter()
+            .map(|(name, value)| format!(
+                "<input type=\"hidden\" name=\"{}\" value=\"{}\">",
+                util::escape_html(name),
+                util::escape_html(value)
+            ))
+            .collect();
+        let body = format!(
+            "<!DOCTYPE html><html><head><title>Continue</title></head><body onload=\"document.forms[0].submit()\">\
+             <form method=\"post\" action=\"{}\" enctype=\"{}\">{}<noscript><button type=\"submit\">Continue</button></noscript></form></body></html>",
+            util::escape_html(url.as_str()),
+            self.content_type,
+            inputs.join("")
+        );
+        DownStreamResponse::html(headers, 200, body)
+    }
+}
+
+// Encoding of an HTML form sending the content type
+fn form_enctype(content_type: &str) -> Option<&'static str> {
+    if content_type.eq_ignore_ascii_case(FORM_URLENCODED) {
+        Some(FORM_URLENCODED)
+    } else if content_type.eq_ignore_ascii_case(TEXT_PLAIN) {
+        Some(TEXT_PLAIN)
+    } else {
+        None
+    }
+}
+
+fn default_max_body_size() -> usize {
+    8192
+}
+
+fn default_content_types() -> Vec<String> {
+    vec![FORM_URLENCODED.to_string()]
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::oauth_client_types::Request;
+    use crate::post_replay::PostReplayConfig;
+
+    fn test_request(headers: Vec<(&str, &str)>) -> Request {
+        let mut headers: Vec<(String, String)> = headers.iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect();
+        headers.push(("x-forwarded-proto".to_string(), "https".to_string()));
+        headers.push((":authority".to_string(), "app.example.com".to_string()));
+        headers.push((":path".to_string(), "/orders".to_string()));
+        Request::new(headers).unwrap()
+    }
+
+    #[test]
+    fn accepts() {
+        let config = PostReplayConfig::new(16, vec!["application/x-www-form-urlencoded".to_string()]);
+        let form = vec![
+            (":method", "POST"),
+            ("content-type", "application/x-www-form-urlencoded; charset=utf-8"),
+            ("content-length", "9"),
+            ("origin", "https://app.example.com"),
+        ];
+        assert!(config.accepts(&test_request(form.clone())));
+
+        let with = |name: &str, value: &str| {
+            let mut headers: Vec<(&str, &str)> = form.iter().filter(|(key, _)| *key != name).cloned().collect();
+            headers.push((name, value));
+            test_request(headers.iter().map(|(name, value)| (*name, *value)).collect())
+        };
+        assert!(!config.accepts(&with(":method", "GET")));
+        assert!(!config.accepts(&with("content-type", "application/json")));
+        assert!(!config.accepts(&with("content-length", "17")));
+        assert!(!config.accepts(&with("origin", "https://evil.example.com")));
+
+        // Empty bodies are saved without buffering
+        let empty = with("content-length", "0");
+        assert!(config.accepts(&empty) && empty.has_empty_body());
+        assert!(config.save(&empty, &[]).is_some());
+
+        let saved = config.save(&test_request(form.clone()), b"item=1&n=2").unwrap();
+        assert!(config.save(&test_request(form), &[b'a'; 17]).is_none());
+
+        let page = saved.replay_page(&"https://app.example.com/orders".parse().unwrap(), vec![]);
+        assert_eq!(page.content_type(), Some("text/html; charset=utf-8"));
+        assert!(page.body().contains("<form method=\"post\" action=\"https://app.example.com/orders\" enctype=\"application/x-www-form-urlencoded\">"));
+        assert!(page.body().contains("<input type=\"hidden\" name=\"item\" value=\"1\">"));
+        assert!(page.body().contains("<input type=\"hidden\" name=\"n\" value=\"2\">"));
+    }
+
+    #[test]
+    fn validate() {
+        assert!(PostReplayConfig::new(16, vec!["text/plain".to_string()]).validate().is_ok());
+        assert!(PostReplayConfig::new(16, vec!["multipart/form-data".to_string()]).validate().is_err());
+    }
+}
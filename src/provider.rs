@@ -0,0 +1,218 @@
+use crate::config::{FilterConfig, RawProviderConfig, DEFAULT_PROVIDER};
+use crate::messages::DownStreamResponse;
+use crate::oauth_client_types::Request;
+use crate::util;
+use url::Url;
+
+// Query parameter selecting the provider, used by the links of the provider picker page
+pub const PROVIDER_PARAM: &str = "provider";
+
+// Query parameter with the login hint of the end-user, e.g. an email address
+pub const LOGIN_HINT_PARAM: &str = "login_hint";
+
+// An authorization server the filter can authenticate end-users with
+#[derive(Clone, Debug)]
+pub struct Provider {
+    name: String,
+    hosts: Vec<String>,
+    path_prefix: Option<String>,
+    login_hint_domains: Vec<String>,
+    config: FilterConfig,
+}
+
+impl Provider {
+    // The provider configured at the top level, used when no other provider is selected
+    pub fn default(config: FilterConfig) -> Provider {
+        Provider {
+            name: DEFAULT_PROVIDER.to_string(),
+            hosts: vec![],
+            path_prefix: None,
+            login_hint_domains: vec![],
+            config,
+        }
+    }
+
+    pub fn new(raw_provider: &RawProviderConfig, config: FilterConfig) -> Provider {
+        Provider {
+            name: raw_provider.name.clone(),
+            hosts: raw_provider.hosts.clone(),
+            path_prefix: raw_provider.path_prefix.clone(),
+            login_hint_domains: raw_provider.login_hint_domains.clone(),
+            config,
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn config(&self) -> &FilterConfig {
+        &self.config
+    }
+
+    fn matches_host(&self, url: &Url) -> bool {
+        let host = url.host_str().unwrap_or_default();
+        self.hosts.iter().any(|expected| expected.eq_ignore_ascii_case(host))
+    }
+
+    fn matches_path(&self, url: &Url) -> bool {
+        self.path_prefix
+            .as_ref()
+            .is_some_and(|prefix| url.path().starts_with(prefix.as_str()))
+    }
+
+    // Login hints with an email address select the provider of the domain
+    fn matches_login_hint(&self, login_hint: &str) -> bool {
+        match login_hint.rsplit_once('@') {
+            Some((_, domain)) => self
+                .login_hint_domains
+                .iter()
+                .any(|expected| expected.eq_ignore_ascii_case(domain)),
+            None => false,
+        }
+    }
+}
+
+// Providers of the filter, the default provider first
+#[derive(Clone, Debug)]
+pub struct Providers {
+    providers: Vec<Provider>,
+    picker: bool,
+}
+
+impl Providers {
+    pub fn new(default: Provider, providers: Vec<Provider>, picker: bool) -> Providers {
+        let mut all = vec![default];
+        all.extend(providers);
+        Providers { providers: all, picker }
+    }
+
+    pub fn default(&self) -> &Provider {
+        &self.providers[0]
+    }
+
+    pub fn find(&self, name: &str) -> Option<&Provider> {
+        self.providers.iter().find(|provider| provider.name == name)
+    }
+
+    // Provider for the request, None if the request does not select one.
+    // Hosts and path prefixes take precedence over the provider of the session, which takes
+    // precedence over the provider query parameter and the login hint.
+    pub fn select(&self, request: &Request, session_provider: Option<&str>) -> Option<&Provider> {
+        let url = request.url();
+        self.providers.iter().find(|provider| provider.matches_host(url))
+            .or_else(|| self.providers.iter().find(|provider| provider.matches_path(url)))
+            .or_else(|| session_provider.and_then(|name| self.find(name)))
+            .or_else(|| request.param(PROVIDER_PARAM).and_then(|name| self.find(&name)))
+            .or_else(|| {
+                let login_hint = request.param(LOGIN_HINT_PARAM)?;
+                self.providers.iter().find(|provider| provider.matches_login_hint(&login_hint))
+            })
+    }
+
+    // Page letting the end-user choose a provider, if enabled. Each link reloads the request URL with the provider.
+    pub fn picker_page(&self, request: &Request) -> Option<DownStreamResponse> {
+        if !self.picker {
+            return None;
+        }
+        let links: Vec<String> = self.providers.iter()
+            .map(|provider| {
+                let mut url = request.url().clone();
+                url.query_pairs_mut().append_pair(PROVIDER_PARAM, provider.name());
+                format!(
+                    "<li><a href=\"{}\">{}</a></li>",
+                    util::escape_html(url.as_str()),
+                    util::escape_html(provider.name())
+                )
+            })
+            .collect();
+        let body = format!(
+            "<!DOCTYPE html><html><head><title>Sign in</title></head><body><h1>Sign in with</h1><ul>{}</ul></body></html>",
+            links.join("")
+        );
+        Some(DownStreamResponse::html(vec![], 200, body))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config::FilterConfig;
+    use crate::oauth_client_types::Request;
+    use crate::provider::{Provider, Providers};
+    use time::Duration;
+
+    fn test_config() -> FilterConfig {
+        FilterConfig::oauth(
+            "sessioncookie",
+            "cluster",
+            "issuer",
+            &"https://redirect".parse().unwrap(),
+            &"https://authorization".parse().unwrap(),
+            &"https://token".parse().unwrap(),
+            "myclient",
+            "mysecret",
+            vec![],
+            Duration::hours(1),
+            vec![],
+        )
+    }
+
+    fn test_provider(name: &str, hosts: Vec<&str>, path_prefix: Option<&str>, domains: Vec<&str>) -> Provider {
+        Provider {
+            name: name.to_string(),
+            hosts: hosts.iter().map(|host| host.to_string()).collect(),
+            path_prefix: path_prefix.map(|prefix| prefix.to_string()),
+            login_hint_domains: domains.iter().map(|domain| domain.to_string()).collect(),
+            config: test_config(),
+        }
+    }
+
+    fn test_providers(picker: bool) -> Providers {
+        Providers::new(
+            Provider::default(test_config()),
+            vec![
+                test_provider("acme", vec!["acme.example.com"], None, vec!["acme.com"]),
+                test_provider("partners", vec![], Some("/partners"), vec![]),
+            ],
+            picker,
+        )
+    }
+
+    fn test_request(authority: &str, path: &str) -> Request {
+        Request::new(vec![
+            (":authority".to_string(), authority.to_string()),
+            (":path".to_string(), path.to_string()),
+            ("x-forwarded-proto".to_string(), "https".to_string()),
+        ]).unwrap()
+    }
+
+    #[test]
+    fn select() {
+        let providers = test_providers(false);
+        let selected = |request: &Request, session: Option<&str>| {
+            providers.select(request, session).map(|provider| provider.name().to_string())
+        };
+
+        assert_eq!(selected(&test_request("acme.example.com", "/"), None).unwrap(), "acme");
+        assert_eq!(selected(&test_request("example.com", "/partners/home"), None).unwrap(), "partners");
+        assert_eq!(selected(&test_request("example.com", "/?provider=acme"), None).unwrap(), "acme");
+        assert_eq!(selected(&test_request("example.com", "/?login_hint=jane%40ACME.com"), None).unwrap(), "acme");
+        assert!(selected(&test_request("example.com", "/?login_hint=jane%40other.com"), None).is_none());
+        assert!(selected(&test_request("example.com", "/?provider=unknown"), None).is_none());
+
+        // The session provider is used unless the host or path selects another provider
+        assert_eq!(selected(&test_request("example.com", "/?provider=acme"), Some("default")).unwrap(), "default");
+        assert_eq!(selected(&test_request("acme.example.com", "/"), Some("default")).unwrap(), "acme");
+    }
+
+    #[test]
+    fn picker_page() {
+        assert!(test_providers(false).picker_page(&test_request("example.com", "/")).is_none());
+
+        let page = test_providers(true).picker_page(&test_request("example.com", "/app?a=1")).unwrap();
+        assert_eq!(page.content_type(), Some("text/html; charset=utf-8"));
+        assert!(page.body().contains("https://example.com/app?a=1&amp;provider=acme"));
+        assert!(page.body().contains("provider=default"));
+        assert!(page.body().contains("provider=partners"));
+    }
+}
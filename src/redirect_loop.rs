@@ -0,0 +1,128 @@
+use cookie::{CookieBuilder, SameSite};
+use oauth2::http::header::SET_COOKIE;
+use std::time::{SystemTime, UNIX_EPOCH};
+use time::Duration;
+
+use crate::error_page::ErrorCode;
+use crate::messages::DownStreamResponse;
+use crate::oauth_client_types::Request;
+use crate::util;
+
+// Counts the authorization requests started by the browser, as "<attempts>.<window start>". The cookie is not
+// Secure, so it is also kept when the session cookie is lost over plain HTTP.
+pub const ATTEMPTS_COOKIE_NAME: &str = "oauthAttempts";
+
+// Authorization attempts of the browser within the window, expired windows have no attempts
+pub fn attempts(request: &Request, window: u64) -> (u32, u64) {
+    let now = now();
+    request.cookie(ATTEMPTS_COOKIE_NAME)
+        .and_then(|value| {
+            let (attempts, started) = value.split_once('.')?;
+            Some((attempts.parse::<u32>().ok()?, started.parse::<u64>().ok()?))
+        })
+        .filter(|(_, started)| now < started.saturating_add(window))
+        .unwrap_or((0, now))
+}
+
+// Cookie counting one more authorization attempt, expiring with the window
+pub fn attempt_cookie(request: &Request, window: u64) -> (String, String) {
+    let (attempts, started) = attempts(request, window);
+    let remaining = started.saturating_add(window).saturating_sub(now());
+    let cookie = CookieBuilder::new(ATTEMPTS_COOKIE_NAME, format!("{}.{}", attempts + 1, started))
+        .http_only(true)
+        .same_site(SameSite::Lax)
+        .path("/")
+        .max_age(Duration::seconds(remaining as i64))
+        .finish();
+    (SET_COOKIE.to_string(), cookie.to_string())
+}
+
+// Clears the attempts once an authorization succeeded
+pub fn clear_cookie() -> (String, String) {
+    let cookie = CookieBuilder::new(ATTEMPTS_COOKIE_NAME, "")
+        .http_only(true)
+        .path("/")
+        .max_age(Duration::seconds(0))
+        .finish();
+    (SET_COOKIE.to_string(), cookie.to_string())
+}
+
+// Page explaining why the browser keeps being sent to the authorization server, with the causes the request points to.
+// The status is the one of invalid_session errors, which get the sign in failed page as well.
+pub fn diagnostic_page(request: &Request, cookie_name: &str, attempts: u32) -> DownStreamResponse {
+    let mut causes = vec![];
+    if request.url().scheme() == "http" {
+        causes.push("The site is served over plain HTTP, browsers don't store the Secure session cookie.".to_string());
+    }
+    if request.cookie(cookie_name).is_none() {
+        causes.push(format!(
+            "The browser did not send the session cookie {}, it may be blocked or rejected by the browser.",
+            cookie_name
+        ));
+    }
+    causes.push("The state of the authorization response does not match the session, e.g. with several tabs or a changed redirect_uri.".to_string());
+    causes.push("The clock of the proxy or the authorization server is skewed, so tokens are expired or not yet valid when received.".to_string());
+    let items: Vec<String> = causes.iter()
+        .map(|cause| format!("<li>{}</li>", util::escape_html(cause)))
+        .collect();
+    let body = format!(
+        "<!DOCTYPE html><html><head><title>Sign in failed</title></head><body><h1>Sign in failed</h1>\
+         <p>Signing in was attempted {} times without success, it is stopped to avoid a redirect loop. Likely causes:</p>\
+         <ul>{}</ul></body></html>",
+        attempts,
+        items.join("")
+    );
+    DownStreamResponse::html(vec![], ErrorCode::InvalidSession.status(), body)
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|now| now.as_secs())
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::oauth_client_types::Request;
+    use crate::redirect_loop::{attempt_cookie, attempts, diagnostic_page, now};
+
+    fn test_request(scheme: &str, cookie: Option<String>) -> Request {
+        let mut headers = vec![
+            ("x-forwarded-proto".to_string(), scheme.to_string()),
+            (":authority".to_string(), "app.example.com".to_string()),
+            (":path".to_string(), "/".to_string()),
+        ];
+        if let Some(cookie) = cookie {
+            headers.push(("cookie".to_string(), cookie));
+        }
+        Request::new(headers).unwrap()
+    }
+
+    #[test]
+    fn count_attempts() {
+        let now = now();
+        assert_eq!(attempts(&test_request("https", None), 60), (0, now));
+        let request = test_request("https", Some(format!("oauthAttempts=3.{}", now - 10)));
+        assert_eq!(attempts(&request, 60), (3, now - 10));
+        assert!(attempt_cookie(&request, 60).1.starts_with(&format!("oauthAttempts=4.{}", now - 10)));
+
+        // Attempts of an expired window are not counted
+        let request = test_request("https", Some(format!("oauthAttempts=3.{}", now - 60)));
+        assert_eq!(attempts(&request, 60).0, 0);
+        assert_eq!(attempts(&test_request("https", Some("oauthAttempts=invalid".to_string())), 60).0, 0);
+    }
+
+    #[test]
+    fn diagnostic() {
+        let page = diagnostic_page(&test_request("http", None), "oauthSession", 5);
+        assert_eq!(page.code(), 400);
+        assert_eq!(page.content_type(), Some("text/html; charset=utf-8"));
+        assert!(page.body().contains("plain HTTP"));
+        assert!(page.body().contains("session cookie oauthSession"));
+
+        let page = diagnostic_page(&test_request("https", Some("oauthSession=abc".to_string())), "oauthSession", 5);
+        assert!(!page.body().contains("plain HTTP"));
+        assert!(!page.body().contains("session cookie oauthSession"));
+    }
+}
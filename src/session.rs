@@ -1,11 +1,16 @@
 use std::time::{SystemTime, SystemTimeError};
 use serde::{Serialize, Deserialize};
-use oauth2::http::{HeaderMap, HeaderValue};
 use oauth2::http::header::{AUTHORIZATION, SET_COOKIE};
-use cookie::CookieBuilder;
+use cookie::{CookieBuilder, SameSite};
 use crate::util;
+use crate::post_replay::SavedPost;
+use serde_json::{Map, Value};
 use time::{Duration, NumericalDuration};
 
+pub const ID_TOKEN_HEADER: &str = "X-Forwarded-ID-Token";
+// Minutes the authorization cookie of a form_post authorization request is kept
+const AUTHORIZATION_COOKIE_EXPIRE: i64 = 10;
+
 pub trait SessionCache {
     fn get(&self, id: &String) -> Option<Session>;
     fn set(&mut self, session: SessionUpdate);
@@ -15,6 +20,9 @@ pub trait SessionCache {
 pub struct Session {
     id: String,
     pub data: SessionType,
+    // Name of the provider that issued the session, for filters with several providers
+    #[serde(default)]
+    provider: Option<String>,
 }
 
 #[derive(Debug, Clone, Deserialize, Serialize)]
@@ -26,19 +34,20 @@ pub enum SessionType {
 
 impl Session {
     pub fn empty(id: String) -> Session {
-        Session { id, data: SessionType::Empty }
+        Session { id, data: SessionType::Empty, provider: None }
     }
 
     pub fn tokens(id: String, access_token: String, expires_in: Option<std::time::Duration>, id_token: Option<String>, refresh_token: Option<String>) -> Session{
         Session {
             id,
-            data: SessionType::Tokens(AuthorizationTokens {
-                created_at: SystemTime::now(),
+            data: SessionType::Tokens(AuthorizationTokens::new(
+                SystemTime::now(),
                 access_token,
                 expires_in,
                 id_token,
                 refresh_token
-            }),
+            )),
+            provider: None,
         }
     }
 
@@ -46,7 +55,9 @@ impl Session {
         Session::from_verifier(id, AuthorizationResponseVerifiers {
             created_at,
             state: State { path: request_url, csrf_token: state },
-            pcke_verifier
+            pcke_verifier,
+            silent: false,
+            saved_post: None,
         })
     }
 
@@ -76,11 +87,15 @@ impl Session {
         }
     }
 
+    pub fn provider(&self) -> Option<&str> {
+        self.provider.as_deref()
+    }
+
     pub(crate) fn from_verifier(id: String, verifiers: AuthorizationResponseVerifiers) -> Session {
-        Session {id, data: SessionType::AuthorizationRequest(verifiers) }
+        Session { id, data: SessionType::AuthorizationRequest(verifiers), provider: None }
     }
     pub(crate) fn from_tokens(id: String, tokens: AuthorizationTokens) -> Session{
-        Session { id, data: SessionType::Tokens(tokens)}
+        Session { id, data: SessionType::Tokens(tokens), provider: None }
     }
 
     fn parse_cookie(id: &String, headers: &Vec<(&str, &str)>) -> Option<String> {
@@ -123,25 +138,98 @@ impl Session {
 
 
     pub fn token_response(&self, access_token: String, expires_in: Option<std::time::Duration>, id_token: Option<String>, refresh_token: Option<String>) -> SessionUpdate {
-        SessionUpdate { id: self.id.clone(), data: UpdateType::Tokens(AuthorizationTokens {
-            created_at: SystemTime::now(),
+        let mut tokens = AuthorizationTokens::new(
+            SystemTime::now(),
             access_token,
             expires_in,
             id_token,
             refresh_token
-        }) }
+        );
+        // Tokens issued by a step-up keep counting, so requirements the provider doesn't meet can't loop
+        if let SessionType::Tokens(AuthorizationTokens { step_up: Some(verifiers), step_ups, .. }) = &self.data {
+            if !verifiers.silent {
+                tokens.step_ups = *step_ups;
+            }
+        }
+        SessionUpdate { id: self.id.clone(), provider: self.provider.clone(), data: UpdateType::Tokens(tokens) }
     }
 
-    pub fn clear_cookie_header(&self, name: &String) -> HeaderMap {
-        let mut headers = HeaderMap::new();
-        let cookie = CookieBuilder::new(
-            name, "")
-            .secure(true)
-            .http_only(true)
-            .max_age(0.hours())
-            .finish().to_string();
-        headers.insert(SET_COOKIE, cookie.parse().unwrap());
-        headers
+    // Replaces the tokens of the session with refreshed tokens, RFC 6749 section 6.
+    // The ID token and refresh token are kept unless new ones are issued.
+    pub fn refreshed(&self, access_token: String, expires_in: Option<std::time::Duration>, id_token: Option<String>, refresh_token: Option<String>) -> Option<SessionUpdate> {
+        let tokens = match &self.data {
+            SessionType::Tokens(tokens) => tokens,
+            _ => return None,
+        };
+        let mut refreshed = AuthorizationTokens::new(
+            SystemTime::now(),
+            access_token,
+            expires_in,
+            id_token.or_else(|| tokens.id_token.clone()),
+            refresh_token.or_else(|| tokens.refresh_token.clone()),
+        );
+        refreshed.acr = refreshed.acr.or_else(|| tokens.acr.clone());
+        refreshed.auth_time = refreshed.auth_time.or(tokens.auth_time);
+        refreshed.userinfo = tokens.userinfo.clone();
+        Some(SessionUpdate { id: self.id.clone(), provider: self.provider.clone(), data: UpdateType::Tokens(refreshed) })
+    }
+
+    // Keeps the tokens of the session while a step-up authorization request is pending, counting the attempt
+    pub fn step_up(&self, tokens: &AuthorizationTokens, attempt: u32, request_url: String, state: String, verifier: String) -> SessionUpdate {
+        let mut tokens = tokens.clone();
+        tokens.step_ups = attempt;
+        tokens.step_up = Some(AuthorizationResponseVerifiers::new(request_url, state, verifier));
+        SessionUpdate { id: self.id.clone(), provider: self.provider.clone(), data: UpdateType::Tokens(tokens) }
+    }
+
+    // Marks the tokens of the session as being refreshed
+    pub fn refreshing(&self, tokens: &AuthorizationTokens) -> SessionUpdate {
+        let mut tokens = tokens.clone();
+        tokens.refreshing_since = Some(SystemTime::now());
+        SessionUpdate { id: self.id.clone(), provider: self.provider.clone(), data: UpdateType::Tokens(tokens) }
+    }
+
+    // Keeps the expired tokens of the session while a silent renewal (prompt=none) is pending, counting the attempt
+    pub fn silent_renewal(&self, tokens: &AuthorizationTokens, request_url: String, state: String, verifier: String) -> SessionUpdate {
+        let mut tokens = tokens.clone();
+        tokens.silent_renewals += 1;
+        let mut verifiers = AuthorizationResponseVerifiers::new(request_url, state, verifier);
+        verifiers.silent = true;
+        tokens.step_up = Some(verifiers);
+        SessionUpdate { id: self.id.clone(), provider: self.provider.clone(), data: UpdateType::Tokens(tokens) }
+    }
+
+    // Verifiers of the pending authorization request, for new sessions and sessions doing step-up
+    pub fn authorization_verifiers(&self) -> Option<&AuthorizationResponseVerifiers> {
+        match &self.data {
+            SessionType::AuthorizationRequest(verifiers) => Some(verifiers),
+            SessionType::Tokens(tokens) => tokens.step_up.as_ref(),
+            SessionType::Empty => None,
+        }
+    }
+
+    // New authorization request replacing the pending one, keeping the session and any tokens
+    pub fn retry_authorization(&self, request_url: String, state: String, verifier: String) -> SessionUpdate {
+        match &self.data {
+            SessionType::Tokens(tokens) => self.step_up(tokens, tokens.step_ups, request_url, state, verifier),
+            _ => SessionUpdate {
+                id: self.id.clone(),
+                provider: self.provider.clone(),
+                data: UpdateType::AuthorizationRequest(AuthorizationResponseVerifiers::new(request_url, state, verifier))
+            },
+        }
+    }
+
+    // Ends the pending authorization request, sessions doing step-up keep their tokens
+    pub fn cancel_authorization(&self) -> SessionUpdate {
+        match &self.data {
+            SessionType::Tokens(tokens) => {
+                let mut tokens = tokens.clone();
+                tokens.step_up = None;
+                SessionUpdate { id: self.id.clone(), provider: self.provider.clone(), data: UpdateType::Tokens(tokens) }
+            }
+            _ => self.end_session(),
+        }
     }
 
     pub fn clear_cookie_header_tuple(&self, name: &str) -> (String, String) {
@@ -155,7 +243,7 @@ impl Session {
     }
 
     pub fn end_session(&self) -> SessionUpdate {
-        SessionUpdate { id: self.id.clone(), data: UpdateType::Ended }
+        SessionUpdate { id: self.id.clone(), provider: self.provider.clone(), data: UpdateType::Ended }
     }
 
  }
@@ -172,17 +260,55 @@ pub enum UpdateType {
 pub struct SessionUpdate {
     pub id: String,
     data: UpdateType,
+    #[serde(default)]
+    provider: Option<String>,
 }
 
 impl SessionUpdate {
     pub fn auth_request(request_url: String, state: String , verifier: String) -> SessionUpdate {
         SessionUpdate {
             id: util::new_random_verifier(32).secret().to_owned(),
-            data: UpdateType::AuthorizationRequest(AuthorizationResponseVerifiers {
-                created_at: SystemTime::now(),
-                state: State { path: request_url, csrf_token: state },
-                pcke_verifier: Some(verifier)
-            })
+            provider: None,
+            data: UpdateType::AuthorizationRequest(AuthorizationResponseVerifiers::new(request_url, state, verifier))
+        }
+    }
+
+    // Sets the provider of the session
+    pub fn provider(mut self, provider: Option<&str>) -> SessionUpdate {
+        self.provider = provider.map(|provider| provider.to_string());
+        self
+    }
+
+    // Marks the authorization request as silent, i.e. sent with prompt=none
+    pub fn silent(mut self, silent: bool) -> SessionUpdate {
+        match &mut self.data {
+            UpdateType::AuthorizationRequest(verifiers) => verifiers.silent = silent,
+            UpdateType::Tokens(AuthorizationTokens { step_up: Some(verifiers), .. }) => verifiers.silent = silent,
+            _ => {}
+        }
+        self
+    }
+
+    // Saves the body of the POST request that started the authorization request
+    pub fn saved_post(mut self, saved_post: Option<SavedPost>) -> SessionUpdate {
+        if let UpdateType::AuthorizationRequest(verifiers) = &mut self.data {
+            verifiers.saved_post = saved_post;
+        }
+        self
+    }
+
+    // Stores claims from the userinfo endpoint with the tokens
+    pub fn userinfo(mut self, claims: Map<String, Value>) -> SessionUpdate {
+        if let UpdateType::Tokens(tokens) = &mut self.data {
+            tokens.userinfo = claims;
+        }
+        self
+    }
+
+    pub fn tokens(&self) -> Option<&AuthorizationTokens> {
+        match &self.data {
+            UpdateType::Tokens(tokens) => Some(tokens),
+            _ => None,
         }
     }
 
@@ -196,18 +322,38 @@ impl SessionUpdate {
             &self.id)
             .secure(true)
             .http_only(true)
+            .same_site(SameSite::Lax)
             .max_age(expires.clone())
-            .finish().to_string()
+            .finish()
+            .to_string()
+    }
+
+    // Cross site cookie identifying the session on the callback path, for form_post authorization responses
+    // which are cross site POST requests without the Lax session cookie. It is short-lived and only accepted
+    // while the authorization request is pending.
+    pub fn authorization_cookie_header_tuple(&self, cookie_name: &str, path: &str) -> (String, String) {
+        let cookie = CookieBuilder::new(
+            authorization_cookie_name(cookie_name),
+            self.id.clone())
+            .secure(true)
+            .http_only(true)
+            .same_site(SameSite::None)
+            .path(path.to_string())
+            .max_age(AUTHORIZATION_COOKIE_EXPIRE.minutes())
+            .finish();
+        (SET_COOKIE.to_string(), cookie.to_string())
     }
 
     pub fn create_session(&self) -> Session {
-        match &self.data {
+        let mut session = match &self.data {
             UpdateType::AuthorizationRequest(verifiers) =>
                 Session::from_verifier(self.id.clone(), verifiers.clone()),
             UpdateType::Tokens(tokens) =>
                 Session::from_tokens(self.id.clone(), tokens.clone()),
             _ => Session::empty(self.id.clone())
-        }
+        };
+        session.provider = self.provider.clone();
+        session
     }
 }
 
@@ -215,11 +361,26 @@ impl SessionUpdate {
 pub struct AuthorizationResponseVerifiers {
     created_at: SystemTime,
     state: State,
-    pcke_verifier: Option<String>
+    pcke_verifier: Option<String>,
+    #[serde(default)]
+    silent: bool,
+    // POST request replayed to the request URL after the callback
+    #[serde(default)]
+    saved_post: Option<SavedPost>,
 }
 
 impl AuthorizationResponseVerifiers {
 
+    fn new(request_url: String, state: String, verifier: String) -> AuthorizationResponseVerifiers {
+        AuthorizationResponseVerifiers {
+            created_at: SystemTime::now(),
+            state: State { path: request_url, csrf_token: state },
+            pcke_verifier: Some(verifier),
+            silent: false,
+            saved_post: None,
+        }
+    }
+
     pub fn request_url(&self) -> String {
         self.state.path.clone()
     }
@@ -231,6 +392,14 @@ impl AuthorizationResponseVerifiers {
     pub fn validate_state(&self, state: String) -> bool {
         self.state.csrf_token == state
     }
+
+    pub fn is_silent(&self) -> bool {
+        self.silent
+    }
+
+    pub fn saved_post(&self) -> Option<&SavedPost> {
+        self.saved_post.as_ref()
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -239,7 +408,27 @@ pub struct AuthorizationTokens {
     access_token: String,
     expires_in: Option<std::time::Duration>,
     id_token: Option<String>,
-    refresh_token: Option<String>
+    refresh_token: Option<String>,
+    // Authentication context and time of the end-user authentication, from the ID token
+    #[serde(default)]
+    acr: Option<String>,
+    #[serde(default)]
+    auth_time: Option<u64>,
+    // Pending step-up authorization request
+    #[serde(default)]
+    step_up: Option<AuthorizationResponseVerifiers>,
+    // Selected claims from the userinfo endpoint
+    #[serde(default)]
+    userinfo: Map<String, Value>,
+    // Silent renewals attempted since the tokens were issued
+    #[serde(default)]
+    silent_renewals: u32,
+    // Step-up authorization requests started in a row
+    #[serde(default)]
+    step_ups: u32,
+    // Start of a pending refresh of the tokens, concurrent requests don't refresh them again
+    #[serde(default)]
+    refreshing_since: Option<SystemTime>,
 }
 
 impl AuthorizationTokens {
@@ -249,27 +438,95 @@ impl AuthorizationTokens {
         expires_in: Option<std::time::Duration>,
         id_token: Option<String>,
         refresh_token: Option<String>) -> AuthorizationTokens {
+        let claims = id_token.as_ref().and_then(|token| util::jwt_claims(token)).unwrap_or_default();
         AuthorizationTokens {
             created_at,
             access_token,
             expires_in,
             id_token,
             refresh_token,
+            acr: claims.get("acr").and_then(|acr| acr.as_str()).map(|acr| acr.to_string()),
+            auth_time: claims.get("auth_time").and_then(|auth_time| auth_time.as_u64()),
+            step_up: None,
+            userinfo: Map::new(),
+            silent_renewals: 0,
+            step_ups: 0,
+            refreshing_since: None,
         }
     }
 
+    pub fn acr(&self) -> Option<&str> {
+        self.acr.as_deref()
+    }
+
+    pub fn auth_time(&self) -> Option<u64> {
+        self.auth_time
+    }
+
+    pub fn access_token(&self) -> &str {
+        &self.access_token
+    }
+
+    pub fn id_token(&self) -> Option<&str> {
+        self.id_token.as_deref()
+    }
+
+    pub fn userinfo(&self) -> &Map<String, Value> {
+        &self.userinfo
+    }
+
+    pub fn silent_renewals(&self) -> u32 {
+        self.silent_renewals
+    }
+
+    pub fn step_ups(&self) -> u32 {
+        self.step_ups
+    }
+
+    // Returns true if the tokens were issued within the duration
+    pub fn issued_within(&self, duration: std::time::Duration) -> bool {
+        SystemTime::now().duration_since(self.created_at).map_or(true, |elapsed| elapsed < duration)
+    }
+
+    pub fn refresh_token(&self) -> Option<&str> {
+        self.refresh_token.as_deref()
+    }
+
+    // Returns true if a refresh of the tokens was started within the timeout
+    pub fn is_refreshing(&self, timeout: std::time::Duration) -> bool {
+        self.refreshing_since
+            .and_then(|since| SystemTime::now().duration_since(since).ok())
+            .is_some_and(|elapsed| elapsed < timeout)
+    }
+
+    // Returns true if the access token expires within the duration
+    pub fn expires_within(&self, duration: std::time::Duration) -> bool {
+        let expires_in = match self.expires_in {
+            None => return true,
+            Some(expires_in) => expires_in,
+        };
+        let elapsed = SystemTime::now().duration_since(self.created_at).unwrap_or_default();
+        expires_in.saturating_sub(elapsed) < duration
+    }
+
     pub fn upstream_headers_tuple(&self) -> Vec<(String, String)> {
         let mut headers = Vec::new();
         headers.push((
             AUTHORIZATION.to_string(),
             format!("bearer {}",self.access_token)));
         self.id_token.as_ref().and_then( |id_token| {
-            headers.push(("X-Forwarded-ID-Token".to_string(), id_token.clone()));
+            headers.push((ID_TOKEN_HEADER.to_string(), id_token.clone()));
             Some(id_token)
         });
         headers
     }
 
+    // Expiry of the access token in seconds since the epoch
+    pub fn expires_at(&self) -> Option<u64> {
+        let expires_at = self.created_at + self.expires_in?;
+        expires_at.duration_since(std::time::UNIX_EPOCH).ok().map(|duration| duration.as_secs())
+    }
+
     // Returns true or false depending on if the access_token is still valid
     pub fn is_access_token_valid(&self) -> Result<bool, SystemTimeError>{
         if self.expires_in.is_none() {return Ok(false)}
@@ -277,6 +534,38 @@ impl AuthorizationTokens {
     }
 }
 
+// Name of the cookie identifying the session of a pending form_post authorization request
+pub fn authorization_cookie_name(cookie_name: &str) -> String {
+    format!("{}-authorization", cookie_name)
+}
+
+pub fn clear_authorization_cookie_header_tuple(cookie_name: &str, path: &str) -> (String, String) {
+    let cookie = CookieBuilder::new(
+        authorization_cookie_name(cookie_name), "")
+        .secure(true)
+        .http_only(true)
+        .same_site(SameSite::None)
+        .path(path.to_string())
+        .max_age(0.hours())
+        .finish();
+    (SET_COOKIE.to_string(), cookie.to_string())
+}
+
+// Removes the named cookie from a cookie header value, returns None if no other cookies remain
+pub fn remove_cookie(cookie_name: &str, cookies: &str) -> Option<String> {
+    let remaining: Vec<&str> = cookies.split(';')
+        .map(|cookie| cookie.trim())
+        .filter(|cookie| {
+            let name = cookie.split('=').next().unwrap_or("").trim();
+            !cookie.is_empty() && name != cookie_name
+        }).collect();
+    if remaining.is_empty() {
+        None
+    } else {
+        Some(remaining.join("; "))
+    }
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 struct State {
     path: String,
@@ -285,7 +574,7 @@ struct State {
 
 #[cfg(test)]
 mod tests {
-    use crate::session::{Session, SessionType, UpdateType, SessionCache, SessionUpdate, AuthorizationResponseVerifiers, State, AuthorizationTokens};
+    use crate::session::{Session, SessionType, UpdateType, SessionCache, SessionUpdate, AuthorizationResponseVerifiers, State, AuthorizationTokens, remove_cookie};
     use std::collections::HashMap;
     use std::time::SystemTime;
 
@@ -337,25 +626,34 @@ mod tests {
         let session: Session = Session::from_headers(cookie_name.clone(), headers.clone(), &cache).unwrap();
         assert!(matches!(session.data, SessionType::Empty));
 
-        cache.set(SessionUpdate { id: cookie_value.clone(), data: UpdateType::AuthorizationRequest(AuthorizationResponseVerifiers {
+        cache.set(SessionUpdate { id: cookie_value.clone(), provider: None, data: UpdateType::AuthorizationRequest(AuthorizationResponseVerifiers {
             created_at: SystemTime::now(),
             state: State { path: "/secure".to_string(), csrf_token: "1234".to_string() },
-            pcke_verifier: Some("1234".to_string())
+            pcke_verifier: Some("1234".to_string()),
+            silent: false,
+            saved_post: None,
         } ) });
 
         let session: Session = Session::from_headers(cookie_name.clone(), headers.clone(), &cache).unwrap();
         assert!(matches!(session.data, SessionType::AuthorizationRequest { .. }));
 
-        cache.set(SessionUpdate { id: cookie_value.clone(), data: UpdateType::Tokens(AuthorizationTokens {
-            created_at: SystemTime::now(),
-            access_token: "SomeJWT".to_string(),
-            expires_in: None,
-            id_token: None,
-            refresh_token: None
-        }) });
+        cache.set(SessionUpdate { id: cookie_value.clone(), provider: None, data: UpdateType::Tokens(AuthorizationTokens::new(
+            SystemTime::now(),
+            "SomeJWT".to_string(),
+            None,
+            None,
+            None
+        )) });
 
         let session: Session = Session::from_headers(cookie_name.clone(), headers, &cache).unwrap();
         assert!(matches!(session.data, SessionType::Tokens { .. }));
     }
 
+    #[test]
+    fn remove_session_cookie() {
+        let cookies = "theme=dark; auth_session=testsession; lang=en";
+        assert_eq!(remove_cookie("auth_session", cookies), Some("theme=dark; lang=en".to_string()));
+        assert_eq!(remove_cookie("auth_session", "auth_session=testsession"), None);
+        assert_eq!(remove_cookie("auth_session", "theme=dark"), Some("theme=dark".to_string()));
+    }
 }
@@ -0,0 +1,113 @@
+use serde::{Deserialize, Serialize};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::util;
+
+pub const GRANT_TYPE_TOKEN_EXCHANGE: &str = "urn:ietf:params:oauth:grant-type:token-exchange";
+pub const TOKEN_TYPE_ACCESS_TOKEN: &str = "urn:ietf:params:oauth:token-type:access_token";
+
+// Exchanged tokens are refreshed this many seconds before they expire, at most half of their lifetime
+const EXPIRY_MARGIN: u64 = 30;
+// Lifetime assumed for exchanged tokens without expires_in
+const DEFAULT_EXPIRES_IN: u64 = 60;
+
+pub trait TokenExchangeCache {
+    fn get_exchanged_token(&self, subject_token: &str, audience: &str) -> Option<ExchangedToken>;
+    fn set_exchanged_token(&mut self, subject_token: &str, audience: &str, token: ExchangedToken);
+}
+
+// Upstream host that requests are sent to with a token exchanged for the audience
+#[derive(Deserialize, Clone, Debug)]
+pub struct ExchangeTarget {
+    host: String,
+    audience: String,
+    #[serde(default)]
+    scopes: Vec<String>,
+}
+
+impl ExchangeTarget {
+    pub fn new(host: &str, audience: &str, scopes: Vec<String>) -> ExchangeTarget {
+        ExchangeTarget {
+            host: host.to_string(),
+            audience: audience.to_string(),
+            scopes,
+        }
+    }
+
+    pub fn matches(&self, host: &str) -> bool {
+        self.host.eq_ignore_ascii_case(host)
+    }
+
+    pub fn audience(&self) -> &str {
+        &self.audience
+    }
+
+    pub fn scopes(&self) -> &Vec<String> {
+        &self.scopes
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ExchangedToken {
+    access_token: String,
+    // Time the token is exchanged again, before it expires
+    refresh_at: u64,
+}
+
+impl ExchangedToken {
+    pub fn new(access_token: String, expires_in: Option<std::time::Duration>) -> ExchangedToken {
+        let expires_in = expires_in.map_or(DEFAULT_EXPIRES_IN, |expires_in| expires_in.as_secs());
+        ExchangedToken {
+            access_token,
+            refresh_at: now() + expires_in - EXPIRY_MARGIN.min(expires_in / 2),
+        }
+    }
+
+    pub fn access_token(&self) -> &str {
+        &self.access_token
+    }
+
+    // Returns true if the token is expired or about to expire
+    pub fn is_expired(&self) -> bool {
+        now() >= self.refresh_at
+    }
+}
+
+// Cache key for exchanged tokens, per subject token and audience. The key is hashed so subject tokens
+// are not kept in shared data.
+pub fn cache_key(subject_token: &str, audience: &str) -> String {
+    util::token_hash(&format!("{}:{}", audience, subject_token))
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|now| now.as_secs())
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::token_exchange::{ExchangeTarget, ExchangedToken};
+    use std::time::Duration;
+
+    #[test]
+    fn exchanged_token() {
+        let token = ExchangedToken::new("token".to_string(), Some(Duration::from_secs(300)));
+        assert!(!token.is_expired());
+
+        // Short-lived tokens are exchanged again after half of their lifetime
+        let token = ExchangedToken::new("token".to_string(), Some(Duration::from_secs(10)));
+        assert!(!token.is_expired());
+        assert_eq!(token.refresh_at, super::now() + 5);
+    }
+
+    #[test]
+    fn exchange_target() {
+        let target: ExchangeTarget =
+            serde_json::from_str("{\"host\": \"api2.internal\", \"audience\": \"api2\"}").unwrap();
+        assert!(target.matches("API2.internal"));
+        assert!(!target.matches("api1.internal"));
+        assert_eq!(target.audience(), "api2");
+    }
+}
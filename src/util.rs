@@ -2,6 +2,7 @@ use std::borrow::Cow;
 use oauth2::http::{HeaderMap, HeaderValue};
 use oauth2::http::header::{ACCEPT, CONTENT_TYPE, AUTHORIZATION};
 use url::form_urlencoded;
+use sha2::{Digest, Sha256};
 use oauth2::{AuthType, ClientId, ClientSecret, RedirectUrl, Scope, TokenUrl, HttpRequest, http, PkceCodeVerifier};
 
 
@@ -45,7 +46,6 @@ pub fn token_request<'a>(
         params.push(("scope", scopes));
     }
 
-    // FIXME: add support for auth extensions? e.g., client_secret_jwt and private_key_jwt
     match auth_type {
         AuthType::RequestBody => {
             params.push(("client_id", client_id));
@@ -103,6 +103,15 @@ pub fn token_request<'a>(
     }
 }
 
+// Escapes text for use in HTML documents
+pub fn escape_html(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&#39;")
+}
+
 pub fn new_random_verifier(num_bytes: u32) -> PkceCodeVerifier {
     let random_bytes: Vec<u8> = (0..num_bytes).map(|_| {
         let mut buf = [0u8; 1];
@@ -113,4 +122,16 @@ pub fn new_random_verifier(num_bytes: u32) -> PkceCodeVerifier {
         &random_bytes,
         base64::URL_SAFE_NO_PAD,
     ))
-}
\ No newline at end of file
+}
+
+// Decodes the claims of a JWT without verifying the signature, returns None for opaque tokens
+pub fn jwt_claims(token: &str) -> Option<serde_json::Map<String, serde_json::Value>> {
+    let payload = token.split('.').nth(1)?;
+    let bytes = base64::decode_config(payload, base64::URL_SAFE_NO_PAD).ok()?;
+    serde_json::from_slice(bytes.as_slice()).ok()
+}
+
+// SHA-256 hash of a token, used as cache key so tokens are not kept in shared data
+pub fn token_hash(token: &str) -> String {
+    base64::encode_config(Sha256::digest(token.as_bytes()), base64::URL_SAFE_NO_PAD)
+}